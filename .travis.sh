@@ -1,6 +1,7 @@
 #!/usr/bin/env bash
 
 function wait_for_service() {
+    compose_file=$1
     n=0
     until [ $n -ge 30 ]
     do
@@ -15,7 +16,7 @@ function wait_for_service() {
     done
 
     if [ $n -ge 30 ]; then
-      docker-compose down
+      docker-compose -f ${compose_file} down
       exit 1
     fi
 
@@ -29,14 +30,22 @@ function set_correct_version {
     fi
 }
 
+function run_tests {
+    compose_file=$1
+
+    docker-compose -f ${compose_file} up -d --build --force-recreate && \
+    wait_for_service ${compose_file} && \
+    ./tests.sh ${compose_file} && \
+    docker-compose -f ${compose_file} down
+}
+
 function rbackup_test {
     set_correct_version && \
     docker build -t rbackup . && \
     cd tests && \
-    docker-compose up -d --build --force-recreate && \
-    wait_for_service && \
-    ./tests.sh && \
-    docker-compose down
+    run_tests docker-compose.yml && \
+    run_tests docker-compose-postgres.yml && \
+    run_tests docker-compose-sqlite.yml
 }
 
 function rbackup_publish {
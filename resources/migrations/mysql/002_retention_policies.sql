@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`account_retention_policies` (
+  `account_id` varchar(64) NOT NULL PRIMARY KEY,
+  `keep_all_hours` int(10) unsigned NOT NULL,
+  `keep_daily_days` int(10) unsigned NOT NULL,
+  `keep_weekly_weeks` int(10) unsigned NOT NULL,
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
+
+CREATE TABLE IF NOT EXISTS `DBNAME`.`device_retention_policies` (
+  `account_id` varchar(64) NOT NULL,
+  `device_id` varchar(100) NOT NULL,
+  `keep_all_hours` int(10) unsigned NOT NULL,
+  `keep_daily_days` int(10) unsigned NOT NULL,
+  `keep_weekly_weeks` int(10) unsigned NOT NULL,
+  PRIMARY KEY (account_id, device_id),
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`upload_sessions` (
+  `id` varchar(64) NOT NULL PRIMARY KEY,
+  `account_id` varchar(64) NOT NULL,
+  `device_id` varchar(100) NOT NULL,
+  `original_name` varchar(10000) NOT NULL,
+  `size` bigint(20) unsigned NOT NULL,
+  `mtime` datetime(3) NOT NULL,
+  `hash` char(64) NOT NULL,
+  `created` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  `last_activity` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
+
+CREATE TABLE IF NOT EXISTS `DBNAME`.`upload_sessions_chunks` (
+  `session_id` varchar(64) NOT NULL,
+  `idx` int(10) unsigned NOT NULL,
+  `size` bigint(20) unsigned NOT NULL,
+  `hash` char(64) NOT NULL,
+  `storage_name` char(64) NOT NULL,
+  PRIMARY KEY (session_id, idx),
+  FOREIGN KEY (session_id)
+        REFERENCES `DBNAME`.`upload_sessions` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
@@ -0,0 +1,2 @@
+ALTER TABLE `DBNAME`.`files_versions`
+  MODIFY `size` bigint(20) unsigned NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE `DBNAME`.`accounts`
+  MODIFY `password` varchar(255) NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE `DBNAME`.`accounts`
+  ADD COLUMN `repo_key_salt` varchar(64) NULL,
+  ADD COLUMN `repo_secret` varchar(255) NULL;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`throttling` (
+  `id` char(64) NOT NULL PRIMARY KEY,
+  `attempts` int(10) unsigned NOT NULL,
+  `last_attempt` datetime NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
@@ -0,0 +1,13 @@
+ALTER TABLE `DBNAME`.`accounts`
+  ADD COLUMN `totp_secret` varchar(64) NULL,
+  ADD COLUMN `totp_enabled` tinyint(1) NOT NULL DEFAULT 0,
+  ADD COLUMN `totp_last_step` bigint(20) unsigned NULL;
+
+CREATE TABLE IF NOT EXISTS `DBNAME`.`backup_codes` (
+  `account_id` varchar(64) NOT NULL,
+  `code_hash` char(64) NOT NULL,
+  PRIMARY KEY (account_id, code_hash),
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`api_tokens` (
+  `id` varchar(64) NOT NULL PRIMARY KEY,
+  `account_id` varchar(64) NOT NULL,
+  `device_id` varchar(200) NOT NULL,
+  `name` varchar(100) NOT NULL,
+  `scope` varchar(20) NOT NULL,
+  `pass` varchar(200) NOT NULL,
+  `created` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`device_roles` (
+  `account_id` varchar(64) NOT NULL,
+  `device_id` varchar(200) NOT NULL,
+  `role` varchar(20) NOT NULL,
+  PRIMARY KEY (`account_id`, `device_id`),
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
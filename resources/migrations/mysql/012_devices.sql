@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS `DBNAME`.`devices` (
+  `account_id` varchar(64) NOT NULL,
+  `id` varchar(200) NOT NULL,
+  `name` varchar(100) NULL,
+  `os` varchar(100) NULL,
+  `created` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  `last_backup` datetime NULL,
+  PRIMARY KEY (`account_id`, `id`),
+  FOREIGN KEY (account_id)
+        REFERENCES `DBNAME`.`accounts` (id)
+        ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
+
+INSERT IGNORE INTO `DBNAME`.`devices` (account_id, id, created, last_backup)
+  SELECT `DBNAME`.`files`.account_id, `DBNAME`.`files`.device_id, min(`DBNAME`.`files_versions`.created), max(`DBNAME`.`files_versions`.created)
+  FROM `DBNAME`.`files` JOIN `DBNAME`.`files_versions` ON `DBNAME`.`files_versions`.file_id = `DBNAME`.`files`.id
+  GROUP BY `DBNAME`.`files`.account_id, `DBNAME`.`files`.device_id;
+
+INSERT IGNORE INTO `DBNAME`.`devices` (account_id, id, created)
+  SELECT account_id, device_id, min(created) FROM `DBNAME`.`sessions` GROUP BY account_id, device_id;
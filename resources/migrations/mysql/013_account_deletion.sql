@@ -0,0 +1,12 @@
+ALTER TABLE `DBNAME`.`accounts`
+  ADD COLUMN `deletion_scheduled` datetime NULL;
+
+CREATE TABLE IF NOT EXISTS `DBNAME`.`audit_log` (
+  `id` bigint(20) NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `account_id` varchar(64) NOT NULL,
+  `username` varchar(250) NOT NULL,
+  `event` varchar(50) NOT NULL,
+  `actor` varchar(250) NOT NULL,
+  `created` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  KEY `account_id` (`account_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf32;
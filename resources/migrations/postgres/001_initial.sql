@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS accounts (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  username varchar(250) NOT NULL,
+  password varchar(64) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS files (
+  id bigserial PRIMARY KEY,
+  account_id varchar(100) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(100) NOT NULL,
+  original_name varchar(10000) NOT NULL,
+  identity_hash varchar(64) NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS files_versions (
+  id bigserial PRIMARY KEY,
+  file_id bigint NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  created timestamp(3) NOT NULL,
+  mtime timestamp(3) NOT NULL,
+  size bigint NOT NULL,
+  hash char(64) NOT NULL,
+  storage_name char(64) NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  created timestamp NOT NULL,
+  last_used timestamp NOT NULL,
+  device_id varchar(200) NOT NULL,
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  pass varchar(200) NOT NULL
+);
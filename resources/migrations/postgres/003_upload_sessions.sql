@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS upload_sessions (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(100) NOT NULL,
+  original_name varchar(10000) NOT NULL,
+  size bigint NOT NULL,
+  mtime timestamp(3) NOT NULL,
+  hash char(64) NOT NULL,
+  created timestamp NOT NULL,
+  last_activity timestamp NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS upload_sessions_chunks (
+  session_id varchar(64) NOT NULL REFERENCES upload_sessions (id) ON DELETE CASCADE,
+  idx bigint NOT NULL,
+  size bigint NOT NULL,
+  hash char(64) NOT NULL,
+  storage_name char(64) NOT NULL,
+  PRIMARY KEY (session_id, idx)
+);
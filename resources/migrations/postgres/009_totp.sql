@@ -0,0 +1,10 @@
+ALTER TABLE accounts
+  ADD COLUMN totp_secret varchar(64) NULL,
+  ADD COLUMN totp_enabled boolean NOT NULL DEFAULT false,
+  ADD COLUMN totp_last_step bigint NULL;
+
+CREATE TABLE IF NOT EXISTS backup_codes (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  code_hash char(64) NOT NULL,
+  PRIMARY KEY (account_id, code_hash)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS api_tokens (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(200) NOT NULL,
+  name varchar(100) NOT NULL,
+  scope varchar(20) NOT NULL,
+  pass varchar(200) NOT NULL,
+  created timestamp NOT NULL
+);
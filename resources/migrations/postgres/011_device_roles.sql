@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS device_roles (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(200) NOT NULL,
+  role varchar(20) NOT NULL,
+  PRIMARY KEY (account_id, device_id)
+);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS devices (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  id varchar(200) NOT NULL,
+  name varchar(100) NULL,
+  os varchar(100) NULL,
+  created timestamp NOT NULL,
+  last_backup timestamp NULL,
+  PRIMARY KEY (account_id, id)
+);
+
+INSERT INTO devices (account_id, id, created, last_backup)
+  SELECT files.account_id, files.device_id, min(files_versions.created), max(files_versions.created)
+  FROM files JOIN files_versions ON files_versions.file_id = files.id
+  GROUP BY files.account_id, files.device_id
+  ON CONFLICT DO NOTHING;
+
+INSERT INTO devices (account_id, id, created)
+  SELECT account_id, device_id, min(created) FROM sessions GROUP BY account_id, device_id
+  ON CONFLICT DO NOTHING;
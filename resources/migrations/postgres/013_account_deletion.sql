@@ -0,0 +1,13 @@
+ALTER TABLE accounts
+  ADD COLUMN deletion_scheduled timestamp NULL;
+
+CREATE TABLE IF NOT EXISTS audit_log (
+  id bigserial PRIMARY KEY,
+  account_id varchar(64) NOT NULL,
+  username varchar(250) NOT NULL,
+  event varchar(50) NOT NULL,
+  actor varchar(250) NOT NULL,
+  created timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_account_id ON audit_log (account_id);
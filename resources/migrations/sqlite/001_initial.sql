@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS accounts (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  username varchar(250) NOT NULL,
+  password varchar(64) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS files (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  account_id varchar(100) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(100) NOT NULL,
+  original_name varchar(10000) NOT NULL,
+  identity_hash varchar(64) NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS files_versions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  file_id INTEGER NOT NULL REFERENCES files (id) ON DELETE CASCADE,
+  created datetime NOT NULL,
+  mtime datetime NOT NULL,
+  size INTEGER NOT NULL,
+  hash char(64) NOT NULL,
+  storage_name char(64) NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  created datetime NOT NULL,
+  last_used datetime NOT NULL,
+  device_id varchar(200) NOT NULL,
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  pass varchar(200) NOT NULL
+);
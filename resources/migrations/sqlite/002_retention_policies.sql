@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS account_retention_policies (
+  account_id varchar(64) NOT NULL PRIMARY KEY REFERENCES accounts (id) ON DELETE CASCADE,
+  keep_all_hours INTEGER NOT NULL,
+  keep_daily_days INTEGER NOT NULL,
+  keep_weekly_weeks INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS device_retention_policies (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(100) NOT NULL,
+  keep_all_hours INTEGER NOT NULL,
+  keep_daily_days INTEGER NOT NULL,
+  keep_weekly_weeks INTEGER NOT NULL,
+  PRIMARY KEY (account_id, device_id)
+);
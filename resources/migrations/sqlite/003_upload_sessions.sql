@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS upload_sessions (
+  id varchar(64) NOT NULL PRIMARY KEY,
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  device_id varchar(100) NOT NULL,
+  original_name varchar(10000) NOT NULL,
+  size INTEGER NOT NULL,
+  mtime datetime NOT NULL,
+  hash char(64) NOT NULL,
+  created datetime NOT NULL,
+  last_activity datetime NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS upload_sessions_chunks (
+  session_id varchar(64) NOT NULL REFERENCES upload_sessions (id) ON DELETE CASCADE,
+  idx INTEGER NOT NULL,
+  size INTEGER NOT NULL,
+  hash char(64) NOT NULL,
+  storage_name char(64) NOT NULL,
+  PRIMARY KEY (session_id, idx)
+);
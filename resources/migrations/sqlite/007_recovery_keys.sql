@@ -0,0 +1,3 @@
+ALTER TABLE accounts ADD COLUMN recovery_key_salt varchar(64) NULL;
+
+ALTER TABLE accounts ADD COLUMN recovery_secret varchar(255) NULL;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS throttling (
+  id char(64) NOT NULL PRIMARY KEY,
+  attempts INTEGER NOT NULL,
+  last_attempt datetime NOT NULL
+);
@@ -0,0 +1,11 @@
+ALTER TABLE accounts ADD COLUMN totp_secret varchar(64) NULL;
+
+ALTER TABLE accounts ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE accounts ADD COLUMN totp_last_step INTEGER NULL;
+
+CREATE TABLE IF NOT EXISTS backup_codes (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  code_hash char(64) NOT NULL,
+  PRIMARY KEY (account_id, code_hash)
+);
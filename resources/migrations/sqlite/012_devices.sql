@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS devices (
+  account_id varchar(64) NOT NULL REFERENCES accounts (id) ON DELETE CASCADE,
+  id varchar(200) NOT NULL,
+  name varchar(100) NULL,
+  os varchar(100) NULL,
+  created datetime NOT NULL,
+  last_backup datetime NULL,
+  PRIMARY KEY (account_id, id)
+);
+
+INSERT OR IGNORE INTO devices (account_id, id, created, last_backup)
+  SELECT files.account_id, files.device_id, min(files_versions.created), max(files_versions.created)
+  FROM files JOIN files_versions ON files_versions.file_id = files.id
+  GROUP BY files.account_id, files.device_id;
+
+INSERT OR IGNORE INTO devices (account_id, id, created)
+  SELECT account_id, device_id, min(created) FROM sessions GROUP BY account_id, device_id;
@@ -0,0 +1,12 @@
+ALTER TABLE accounts ADD COLUMN deletion_scheduled datetime NULL;
+
+CREATE TABLE IF NOT EXISTS audit_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  account_id varchar(64) NOT NULL,
+  username varchar(250) NOT NULL,
+  event varchar(50) NOT NULL,
+  actor varchar(250) NOT NULL,
+  created datetime NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_account_id ON audit_log (account_id);
@@ -1,22 +1,60 @@
 use failure::*;
+use rbackup;
 use rbackup::dao::Dao;
+use rbackup::migrations;
 use slog::Logger;
-use std::fs::File;
-use std::io::prelude::*;
 
-pub fn db_init(logger: &Logger, dao: Dao) -> Result<i32, Error> {
-    debug!(logger, "Executing DB init");
+pub fn db_migrate(logger: &Logger, dao: Dao) -> Result<i32, Error> {
+    debug!(logger, "Executing DB migration");
 
-    let mut file = File::open("/resources/db-scheme.sql")?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-
-    info!(logger, "Creating the database scheme");
-
-    dao.exec(&contents)
-        .map(|_| {
-            info!(logger, "Database scheme created");
+    migrations::migrate(logger, &dao)
+        .map(|applied| {
+            info!(logger, "Database schema migrated"; "applied_migrations" => applied, "version" => migrations::latest_version(&dao));
             0
         })
-        .map_err(Error::from)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AccountDeletion {
+    /// Schedules the deletion after the grace period.
+    Schedule(chrono::Duration),
+    Now,
+    Cancel
+}
+
+pub fn delete_account(logger: &Logger, dao: Dao, repo_root: &str, username: &str, action: AccountDeletion) -> Result<i32, Error> {
+    debug!(logger, "Executing deletion of account"; "username" => username, "action" => ?action);
+
+    let account_ids = dao.find_account_ids(username)?;
+
+    if account_ids.is_empty() {
+        error!(logger, "Account '{}' not found", username);
+        return Ok(1);
+    }
+
+    // older versions didn't check uniqueness of the username, so there may be more accounts with the same one
+    for account_id in account_ids {
+        match action {
+            AccountDeletion::Schedule(grace_period) => match dao.request_account_deletion(&account_id, "admin-cli", grace_period)? {
+                Some(purge_after) => info!(logger, "Deletion of account scheduled"; "account_id" => &account_id, "purge_after" => %purge_after),
+                None => warn!(logger, "Deletion of account is already scheduled"; "account_id" => &account_id)
+            },
+            AccountDeletion::Now => {
+                dao.schedule_account_deletion_now(&account_id)?;
+
+                if rbackup::purge_account(logger, &dao, repo_root, &account_id, "admin-cli")? {
+                    info!(logger, "Account deleted"; "account_id" => &account_id)
+                } else {
+                    warn!(logger, "Account is being deleted by another process"; "account_id" => &account_id)
+                }
+            },
+            AccountDeletion::Cancel => if dao.cancel_account_deletion(&account_id, "admin-cli")? {
+                info!(logger, "Deletion of account cancelled"; "account_id" => &account_id)
+            } else {
+                warn!(logger, "Deletion of account is not scheduled"; "account_id" => &account_id)
+            }
+        }
+    }
+
+    Ok(0)
 }
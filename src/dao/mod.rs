@@ -0,0 +1,1687 @@
+extern crate chrono;
+extern crate multimap;
+extern crate serde;
+extern crate serde_json;
+extern crate stopwatch;
+extern crate time;
+
+use cache_2q::Cache;
+use cadence::prelude::*;
+use cadence::StatsdClient;
+use chrono::{Duration, NaiveDateTime, Utc};
+use crate::dao::stopwatch::Stopwatch;
+use crate::encryptor::Encryptor;
+use failure::Error;
+use crate::failures::CustomError;
+use hex;
+use crate::migrations::Migration;
+use crate::passwords::{self, PasswordHasher, PasswordVerification, WrappedSecret};
+use rand::Rng;
+use crate::responses::*;
+use crate::retention::RetentionPolicy;
+use sha2::*;
+use crate::sessions::SessionConfig;
+use crate::throttling::ThrottlingConfig;
+use crate::totp;
+use slog::Logger;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::structs::*;
+use uuid::Uuid;
+
+pub use self::mysql::MySqlDatabase;
+pub use self::postgres::PostgresDatabase;
+pub use self::sqlite::SqliteDatabase;
+
+mod mysql;
+mod postgres;
+mod sqlite;
+
+/// Storage of the metadata. Implementations only execute the queries, the logic (hashing, encryption, caching, metrics) is in the `Dao`.
+pub trait Database: Send + Sync {
+    fn migrations(&self) -> &'static [Migration];
+
+    /// Returns version of the last applied migration, `0` if there is none.
+    fn get_schema_version(&self) -> Result<u32, Error>;
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Error>;
+
+    /// Inserts the file if it doesn't exist yet, returns whether it was inserted.
+    fn insert_file(&self, uploaded_file: &UploadedFile) -> Result<bool, Error>;
+
+    /// Inserts the file version, returns its ID.
+    fn insert_file_version(&self, file_id: u64, file_version: &FileVersion) -> Result<u64, Error>;
+
+    fn find_file(&self, identity_hash: &str) -> Result<Option<File>, Error>;
+
+    fn get_hash_size_and_storage_name(&self, account_id: &str, version_id: u64) -> Result<Option<(String, u64, String)>, Error>;
+
+    fn find_storage_name_by_hash(&self, account_id: &str, hash: &str, size: u64) -> Result<Option<String>, Error>;
+
+    fn touch_file_version(&self, account_id: &str, version_id: u64, mtime: &NaiveDateTime) -> Result<bool, Error>;
+
+    /// Returns triples `(account_id, device_id, version_id)`; the newest version of each file is never returned.
+    fn find_versions_older_than(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String, u64)>, Error>;
+
+    fn get_storage_names(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Vec<String>, Error>;
+
+    fn list_files(&self, account_id: &str, device_id: &str) -> Result<Vec<File>, Error>;
+
+    fn list_account_files(&self, account_id: &str) -> Result<Vec<File>, Error>;
+
+    fn remove_file_version(&self, account_id: &str, version_id: u64) -> Result<bool, Error>;
+
+    /// Removes all versions of the file, returns number of removed versions.
+    fn remove_file_versions(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<u64, Error>;
+
+    fn remove_file(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<bool, Error>;
+
+    fn get_devices(&self, account_id: &str) -> Result<Vec<Device>, Error>;
+
+    fn is_known_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error>;
+
+    fn create_upload_session(&self, session: &UploadSession, now: &NaiveDateTime) -> Result<(), Error>;
+
+    fn find_upload_session(&self, account_id: &str, device_id: &str, session_id: &str) -> Result<Option<UploadSession>, Error>;
+
+    fn get_upload_chunks(&self, session_id: &str) -> Result<Vec<UploadChunk>, Error>;
+
+    /// Inserts or replaces the chunk and updates last activity of the upload session in one transaction, unless all chunks of the
+    /// session would be bigger than `max_size`.
+    fn save_upload_chunk(&self, session_id: &str, chunk: &UploadChunk, max_size: u64, now: &NaiveDateTime) -> Result<SavedUploadChunk, Error>;
+
+    fn remove_upload_session(&self, session_id: &str) -> Result<bool, Error>;
+
+    /// Returns pairs `(session_id, account_id)`.
+    fn find_upload_sessions_inactive_since(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String)>, Error>;
+
+    fn get_account_ids(&self) -> Result<Vec<String>, Error>;
+
+    fn get_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error>;
+
+    fn set_retention_policy(&self, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<(), Error>;
+
+    fn remove_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<bool, Error>;
+
+    /// Returns `(device_id, account_id, encrypted_pass, created, last_used)`.
+    fn find_session(&self, hashed_session_id: &str) -> Result<Option<(String, String, String, NaiveDateTime, NaiveDateTime)>, Error>;
+
+    fn update_session_pass(&self, hashed_session_id: &str, encrypted_pass: &str) -> Result<(), Error>;
+
+    /// Takes pairs `(hashed_session_id, last_used)`.
+    fn update_sessions_last_used(&self, activity: &[(String, NaiveDateTime)]) -> Result<(), Error>;
+
+    /// Removes sessions created or last used before given times, returns number of removed sessions.
+    fn remove_expired_sessions(&self, created_before: &NaiveDateTime, last_used_before: &NaiveDateTime) -> Result<u64, Error>;
+
+    /// Removes all sessions of the device and inserts the new one, atomically. Returns hashed IDs of the removed sessions.
+    fn replace_device_sessions(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<Vec<String>, Error>;
+
+    fn insert_session(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error>;
+
+    /// Returns `(attempts, last_attempt)` of the throttling counter.
+    fn find_throttling(&self, id: &str) -> Result<Option<(u32, NaiveDateTime)>, Error>;
+
+    /// Increments the throttling counter, atomically; the counter is reset first if its last attempt is older than `reset_before`.
+    fn record_attempt(&self, id: &str, now: &NaiveDateTime, reset_before: &NaiveDateTime) -> Result<(), Error>;
+
+    fn remove_throttling(&self, id: &str) -> Result<(), Error>;
+
+    /// Removes throttling counters with the last attempt before given time, returns number of removed counters.
+    fn remove_stale_throttling(&self, last_attempt_before: &NaiveDateTime) -> Result<u64, Error>;
+
+    /// Returns pairs `(account_id, password_hash)`.
+    fn find_accounts(&self, username: &str) -> Result<Vec<(String, String)>, Error>;
+
+    fn insert_account(&self, account_id: &str, username: &str, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: Option<&WrappedSecret>) -> Result<bool, Error>;
+
+    fn update_account_password(&self, account_id: &str, password_hash: &str) -> Result<bool, Error>;
+
+    /// Returns `None` for accounts whose repo is still encrypted directly by the login password.
+    fn find_repo_secret(&self, account_id: &str) -> Result<Option<WrappedSecret>, Error>;
+
+    fn update_repo_secret(&self, account_id: &str, repo_secret: &WrappedSecret) -> Result<bool, Error>;
+
+    fn find_password_hash(&self, account_id: &str) -> Result<Option<String>, Error>;
+
+    /// Updates the password hash and the wrapped repo secret at once.
+    fn update_account_credentials(&self, account_id: &str, password_hash: &str, repo_secret: &WrappedSecret) -> Result<bool, Error>;
+
+    /// Updates the password hash, the wrapped repo secret and the recovery secret at once, unless the recovery secret is not
+    /// `used_recovery_secret` anymore (the recovery key was used concurrently).
+    fn update_recovered_credentials(&self, account_id: &str, used_recovery_secret: &WrappedSecret, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: &WrappedSecret) -> Result<bool, Error>;
+
+    /// Returns pairs `(account_id, recovery_secret)` of accounts with given username which have a recovery key.
+    fn find_recovery_secrets(&self, username: &str) -> Result<Vec<(String, WrappedSecret)>, Error>;
+
+    /// Removes all sessions of the account, returns number of removed sessions.
+    fn remove_account_sessions(&self, account_id: &str) -> Result<u64, Error>;
+
+    /// Returns `(hashed_session_id, device_id, created, last_used)` of all sessions of the account.
+    fn list_sessions(&self, account_id: &str) -> Result<Vec<(String, String, NaiveDateTime, NaiveDateTime)>, Error>;
+
+    fn remove_session(&self, hashed_session_id: &str) -> Result<bool, Error>;
+
+    fn remove_account_session(&self, account_id: &str, hashed_session_id: &str) -> Result<bool, Error>;
+
+    /// Removes all sessions of the device, returns number of removed sessions.
+    fn remove_device_sessions(&self, account_id: &str, device_id: &str) -> Result<u64, Error>;
+
+    /// Returns `(totp_secret, totp_enabled, totp_last_step)` of the account, if it has a TOTP secret.
+    fn find_totp(&self, account_id: &str) -> Result<Option<(String, bool, Option<u64>)>, Error>;
+
+    /// Sets new TOTP secret of the account, not enabled yet. Returns `false` if the account has the TOTP already enabled.
+    fn set_totp_secret(&self, account_id: &str, secret: &str) -> Result<bool, Error>;
+
+    /// Enables the TOTP of the account and replaces its backup codes, atomically. Returns `false` if the TOTP is not pending.
+    fn enable_totp(&self, account_id: &str, step: u64, backup_code_hashes: &[String]) -> Result<bool, Error>;
+
+    /// Returns `false` if the step is not newer than the last used one, i.e. the code was already used.
+    fn update_totp_last_step(&self, account_id: &str, step: u64) -> Result<bool, Error>;
+
+    /// Returns `false` if there is no such backup code (e.g. it was already used).
+    fn remove_backup_code(&self, account_id: &str, code_hash: &str) -> Result<bool, Error>;
+
+    /// Removes the TOTP secret and the backup codes of the account, atomically.
+    fn remove_totp(&self, account_id: &str) -> Result<bool, Error>;
+
+    fn insert_api_token(&self, hashed_token: &str, account_id: &str, device_id: &str, name: &str, scope: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error>;
+
+    /// Returns `(device_id, account_id, scope, encrypted_pass)`.
+    fn find_api_token(&self, hashed_token: &str) -> Result<Option<(String, String, String, String)>, Error>;
+
+    /// Returns `(hashed_token, device_id, name, scope, created)` of all API tokens of the account.
+    fn list_api_tokens(&self, account_id: &str) -> Result<Vec<(String, String, String, String, NaiveDateTime)>, Error>;
+
+    fn remove_api_token(&self, account_id: &str, hashed_token: &str) -> Result<bool, Error>;
+
+    /// Removes all API tokens of the account, returns number of removed tokens.
+    fn remove_account_api_tokens(&self, account_id: &str) -> Result<u64, Error>;
+
+    /// Returns the role of the device; `None` if it wasn't set explicitly.
+    fn find_device_role(&self, account_id: &str, device_id: &str) -> Result<Option<String>, Error>;
+
+    /// Inserts or replaces the role of the device.
+    fn set_device_role(&self, account_id: &str, device_id: &str, role: &str) -> Result<(), Error>;
+
+    /// Returns `(device_id, role)` of all devices of the account with explicitly set role.
+    fn list_device_roles(&self, account_id: &str) -> Result<Vec<(String, String)>, Error>;
+
+    /// Inserts the device if it doesn't exist yet; updates its OS if provided.
+    fn insert_device(&self, account_id: &str, device_id: &str, os: Option<&str>, now: &NaiveDateTime) -> Result<(), Error>;
+
+    fn update_device_name(&self, account_id: &str, device_id: &str, name: &str) -> Result<bool, Error>;
+
+    fn update_device_last_backup(&self, account_id: &str, device_id: &str, now: &NaiveDateTime) -> Result<(), Error>;
+
+    /// Removes the device together with its sessions, API tokens, role and retention policy. Returns whether the device existed.
+    fn remove_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error>;
+
+    /// Schedules the removal of the account unless it's scheduled already. Returns whether it was scheduled.
+    fn schedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<bool, Error>;
+
+    /// Returns `false` if the removal of the account was not scheduled.
+    fn cancel_account_deletion(&self, account_id: &str) -> Result<bool, Error>;
+
+    /// Returns IDs of accounts whose scheduled removal is due.
+    fn find_accounts_to_purge(&self, now: &NaiveDateTime) -> Result<Vec<String>, Error>;
+
+    /// Unschedules the removal of the account if it's due, so it can't be cancelled any more and no other purge of it runs.
+    /// Returns whether the account was claimed.
+    fn claim_account_purge(&self, account_id: &str, now: &NaiveDateTime) -> Result<bool, Error>;
+
+    /// Sets the time of the removal of the account, no matter whether it was scheduled already.
+    fn reschedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<(), Error>;
+
+    /// Inserts the record to the audit log together with the current username of the account.
+    fn insert_audit_record(&self, account_id: &str, event: &str, actor: &str, now: &NaiveDateTime) -> Result<(), Error>;
+
+    /// Removes the account and records it to the audit log in one transaction; its files, sessions etc. are removed by the cascade.
+    /// Returns whether the account existed.
+    fn remove_account(&self, account_id: &str, actor: &str, now: &NaiveDateTime) -> Result<bool, Error>;
+}
+
+/// API tokens are distinguished from session IDs (UUIDs) by this prefix.
+const API_TOKEN_PREFIX: &str = "rbt_";
+
+/// Session held in the cache (under its hashed ID); its `last_used` is newer than the one in the DB until the pending activity is flushed.
+#[derive(Debug, Clone)]
+struct CachedSession {
+    identity: DeviceIdentity,
+    created: NaiveDateTime,
+    last_used: NaiveDateTime
+}
+
+pub struct Dao {
+    db: Box<dyn Database>,
+    password_hasher: PasswordHasher,
+    session_config: SessionConfig,
+    throttling_config: ThrottlingConfig,
+    session_cache: Arc<Mutex<Cache<String, Option<CachedSession>>>>,
+    /// Incremented (under the lock of the cache) whenever sessions are evicted, so a session loaded from the DB meanwhile isn't cached.
+    session_cache_generation: AtomicUsize,
+    session_activity: Mutex<HashMap<String, NaiveDateTime>>,
+    repo_secret_migration: Mutex<()>,
+    logger: Logger,
+    statsd_client: Option<StatsdClient>
+}
+
+impl Dao {
+    pub fn new(db: Box<dyn Database>, password_hasher: PasswordHasher, session_config: SessionConfig, throttling_config: ThrottlingConfig, logger: Logger, statsd_client: Option<StatsdClient>) -> Dao {
+        Dao {
+            db,
+            password_hasher,
+            session_config,
+            throttling_config,
+            session_cache: Arc::new(Mutex::new(Cache::new(100))),
+            session_cache_generation: AtomicUsize::new(0),
+            session_activity: Mutex::new(HashMap::new()),
+            repo_secret_migration: Mutex::new(()),
+            logger: logger.new(o!("component" => "dao")),
+            statsd_client
+        }
+    }
+
+    fn report_timer(&self, name: &str, stopwatch: Stopwatch) -> () {
+        #[allow(unused_must_use)] {
+            match self.statsd_client {
+                Some(ref cl) => {
+                    let millis = stopwatch.elapsed_ms() as u64;
+                    debug!(self.logger, "Dao: '{}' took {} ms", name, millis);
+                    cl.time(format!("dao.{}", name).as_ref(), millis);
+                },
+                None => () // ok
+            }
+        }
+    }
+
+    pub fn migrations(&self) -> &'static [Migration] {
+        self.db.migrations()
+    }
+
+    pub fn get_schema_version(&self) -> Result<u32, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_schema_version()
+            .map(|version| {
+                self.report_timer("get_schema_version", stopwatch);
+                version
+            })
+    }
+
+    pub fn apply_migration(&self, migration: &Migration) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.apply_migration(migration)
+            .map(|_| {
+                self.report_timer("apply_migration", stopwatch);
+            })
+    }
+
+    fn get_or_insert_file(&self, uploaded_file: &UploadedFile) -> Result<File, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        debug!(self.logger, "Trying to create record for file"; "file" => ?uploaded_file);
+
+        let inserted = self.db.insert_file(uploaded_file)?;
+
+        self.report_timer("insert_file", stopwatch);
+
+        if inserted {
+            debug!(self.logger, "File was inserted into DB"; "file" => ?uploaded_file)
+        }
+
+        let file = self.find_file(&uploaded_file.identity_hash)?.expect("Just inserted file was not found in DB");
+
+        debug!(self.logger, "File has ID {} in DB", file.id; "file" => ?uploaded_file);
+
+        // TODO what if now the flow fails - orphaned record in DB! is it a problem?
+
+        Ok(file)
+    }
+
+    pub fn save_file_version(&self, uploaded_file: &UploadedFile, new_file_version: FileVersion) -> Result<File, Error> {
+        let file = self.get_or_insert_file(uploaded_file)?;
+
+        let stopwatch = Stopwatch::start_new();
+
+        let new_id = self.db.insert_file_version(file.id, &new_file_version)?;
+
+        self.report_timer("insert_file_version", stopwatch);
+
+        self.record_backup(&uploaded_file.account_id, &uploaded_file.device_id)?;
+
+        let mut new_file_version = new_file_version.clone();
+        new_file_version.version = new_id;
+
+        let mut versions = file.versions;
+        versions.push(new_file_version);
+
+        Ok(File {
+            id: file.id,
+            device_id: file.device_id,
+            original_name: file.original_name,
+            versions
+        })
+    }
+
+    pub fn find_file(&self, identity_hash: &str) -> Result<Option<File>, Error> {
+        debug!(self.logger, "Trying to locate file in DB"; "identity_hash" => identity_hash);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_file(identity_hash)
+            .map(|file| {
+                self.report_timer("find_file", stopwatch);
+                file
+            })
+    }
+
+    pub fn get_hash_size_and_storage_name(&self, account_id: &str, version_id: u64) -> Result<Option<(String, u64, String)>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_hash_size_and_storage_name(account_id, version_id)
+            .map(|result| {
+                self.report_timer("get_storage_name", stopwatch);
+                result
+            })
+    }
+
+    pub fn find_storage_name_by_hash(&self, account_id: &str, hash: &str, size: u64) -> Result<Option<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_storage_name_by_hash(account_id, hash, size)
+            .map(|result| {
+                self.report_timer("find_storage_name_by_hash", stopwatch);
+                result
+            })
+    }
+
+    pub fn touch_file_version(&self, account_id: &str, version_id: u64, mtime: &NaiveDateTime) -> Result<bool, Error> {
+        debug!(self.logger, "Updating mtime of file version"; "id" => version_id, "mtime" => %mtime);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.touch_file_version(account_id, version_id, mtime)
+            .map(|updated| {
+                self.report_timer("touch_file_version", stopwatch);
+                updated
+            })
+    }
+
+    pub fn find_versions_older_than(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String, u64)>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_versions_older_than(threshold)
+            .map(|result| {
+                self.report_timer("find_versions_older_than", stopwatch);
+                result
+            })
+    }
+
+    pub fn get_storage_names(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Vec<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_storage_names(account_id, device_id, file_id)
+            .map(|result| {
+                self.report_timer("get_storage_names", stopwatch);
+                result
+            })
+    }
+
+    pub fn list_files(&self, account_id: &str, device_id: &str) -> Result<Option<Vec<File>>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.list_files(account_id, device_id)
+            .and_then(|files| {
+                self.report_timer("list_files", stopwatch);
+
+                if files.len() >= 1 {
+                    Ok(Some(files))
+                } else {
+                    // if no files were found, check if the device itself "is known"
+                    self.is_known_device(account_id, device_id).map(|exists| {
+                        if exists { Some(Vec::new()) } else { None }
+                    })
+                }
+            })
+    }
+
+    pub fn list_account_files(&self, account_id: &str) -> Result<Vec<File>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.list_account_files(account_id)
+            .map(|files| {
+                self.report_timer("list_account_files", stopwatch);
+                files
+            })
+    }
+
+    /// Removes the version if it belongs to the account, returns its storage name.
+    pub fn remove_file_version(&self, account_id: &str, version_id: u64) -> Result<Option<String>, Error> {
+        debug!(self.logger, "Deleting file version with"; "id" => version_id, "account_id" => account_id);
+
+        self.get_hash_size_and_storage_name(account_id, version_id)
+            .and_then(|st| {
+                let stopwatch = Stopwatch::start_new();
+
+                self.db.remove_file_version(account_id, version_id)
+                    .map(|removed| {
+                        self.report_timer("remove_file_version", stopwatch);
+
+                        if removed {
+                            st.map(|o| o.2)
+                        } else { None }
+                    })
+            })
+    }
+
+    pub fn remove_file(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Option<Vec<String>>, Error> {
+        debug!(self.logger, "Deleting file versions"; "file_id" => file_id, "device_id" => device_id, "account_id" => account_id);
+
+        self.get_storage_names(account_id, device_id, file_id)
+            .and_then(|st| {
+                let stopwatch = Stopwatch::start_new();
+
+                if st.len() >= 1 {
+                    self.db.remove_file_versions(account_id, device_id, file_id)
+                        .and_then(|deleted| {
+                            self.report_timer("remove_file", stopwatch);
+
+                            debug!(self.logger, "Deleted file versions: `{}`", deleted);
+
+                            if deleted == st.len() as u64 {
+                                Ok(Some(st))
+                            } else { Err(Error::from(CustomError::new("Could not delete all"))) }
+                        })
+                } else {
+                    Ok(None)
+                }
+            }).and_then(|list| match list {
+            Some(versions) => {
+                // versions were deleted, now delete the file itself
+                self.db.remove_file(account_id, device_id, file_id)
+                    .map(|_| Some(versions))
+            },
+            None => Ok(None)
+        })
+    }
+
+    pub fn get_devices(&self, account_id: &str) -> Result<Vec<Device>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_devices(account_id)
+            .map(|devices| {
+                self.report_timer("get_devices", stopwatch);
+                devices
+            })
+    }
+
+    pub fn create_upload_session(&self, session: &UploadSession) -> Result<(), Error> {
+        debug!(self.logger, "Creating upload session"; "session" => ?session);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.create_upload_session(session, &Utc::now().naive_utc())
+            .map(|_| {
+                self.report_timer("create_upload_session", stopwatch);
+            })
+    }
+
+    pub fn find_upload_session(&self, account_id: &str, device_id: &str, session_id: &str) -> Result<Option<UploadSession>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_upload_session(account_id, device_id, session_id)
+            .map(|session| {
+                self.report_timer("find_upload_session", stopwatch);
+                session
+            })
+    }
+
+    pub fn get_upload_chunks(&self, session_id: &str) -> Result<Vec<UploadChunk>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_upload_chunks(session_id)
+            .map(|chunks| {
+                self.report_timer("get_upload_chunks", stopwatch);
+                chunks
+            })
+    }
+
+    /// Saves the chunk unless the chunks of the session would be bigger than `max_size`.
+    pub fn save_upload_chunk(&self, session_id: &str, chunk: &UploadChunk, max_size: u64) -> Result<SavedUploadChunk, Error> {
+        debug!(self.logger, "Saving upload chunk"; "session_id" => session_id, "chunk" => ?chunk);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.save_upload_chunk(session_id, chunk, max_size, &Utc::now().naive_utc())
+            .map(|saved| {
+                self.report_timer("save_upload_chunk", stopwatch);
+                saved
+            })
+    }
+
+    pub fn remove_upload_session(&self, session_id: &str) -> Result<bool, Error> {
+        debug!(self.logger, "Removing upload session"; "session_id" => session_id);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.remove_upload_session(session_id)
+            .map(|removed| {
+                self.report_timer("remove_upload_session", stopwatch);
+                removed
+            })
+    }
+
+    pub fn find_upload_sessions_inactive_since(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String)>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_upload_sessions_inactive_since(threshold)
+            .map(|sessions| {
+                self.report_timer("find_upload_sessions_inactive_since", stopwatch);
+                sessions
+            })
+    }
+
+    pub fn get_account_ids(&self) -> Result<Vec<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_account_ids()
+            .map(|ids| {
+                self.report_timer("get_account_ids", stopwatch);
+                ids
+            })
+    }
+
+    pub fn get_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.get_retention_policy(account_id, device_id)
+            .map(|policy| {
+                self.report_timer("get_retention_policy", stopwatch);
+                policy
+            })
+    }
+
+    pub fn set_retention_policy(&self, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<(), Error> {
+        debug!(self.logger, "Setting retention policy"; "account_id" => account_id, "device_id" => device_id, "policy" => ?policy);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.set_retention_policy(account_id, device_id, policy)
+            .map(|_| {
+                self.report_timer("set_retention_policy", stopwatch);
+            })
+    }
+
+    pub fn remove_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<bool, Error> {
+        debug!(self.logger, "Removing retention policy"; "account_id" => account_id, "device_id" => device_id);
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.remove_retention_policy(account_id, device_id)
+            .map(|removed| {
+                self.report_timer("remove_retention_policy", stopwatch);
+                removed
+            })
+    }
+
+    /// Updates the time of the last backup of the device.
+    pub fn record_backup(&self, account_id: &str, device_id: &str) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.update_device_last_backup(account_id, device_id, &Utc::now().naive_utc())
+            .map(|_| {
+                self.report_timer("record_backup", stopwatch);
+            })
+    }
+
+    /// Sets the friendly name of the device, returns whether the device exists.
+    pub fn rename_device(&self, account_id: &str, device_id: &str, name: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.update_device_name(account_id, device_id, name)
+            .map(|renamed| {
+                self.report_timer("rename_device", stopwatch);
+                renamed
+            })
+    }
+
+    /// Removes the device and everything which allows it to access the account (sessions, API tokens). Files of the device are kept.
+    pub fn remove_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let removed = self.db.remove_device(account_id, device_id)?;
+        self.invalidate_session_cache();
+
+        self.report_timer("remove_device", stopwatch);
+        Ok(removed)
+    }
+
+    pub fn is_known_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.is_known_device(account_id, device_id)
+            .map(|known| {
+                self.report_timer("device_exists", stopwatch);
+                known
+            })
+    }
+
+    fn find_session(&self, enc: &Encryptor, session_pass: &str, hashed_session_pass: &str) -> Result<Option<CachedSession>, Error> {
+        if session_pass.starts_with(API_TOKEN_PREFIX) {
+            return self.find_api_token(enc, session_pass, hashed_session_pass);
+        }
+
+        let stopwatch = Stopwatch::start_new();
+
+        let session = self.db.find_session(hashed_session_pass)?;
+        self.report_timer("find_session", stopwatch);
+
+        match session {
+            Some((device_id, account_id, pass, created, last_used)) => {
+                debug!(self.logger, "Found session in DB"; "device_id" => &device_id, "pass" => &pass);
+
+                let decrypted = enc.decrypt(&pass, session_pass.as_bytes())?;
+                let repo_pass = String::from_utf8(decrypted.data)?;
+                let role = self.find_device_role(&account_id, &device_id)?;
+
+                if decrypted.legacy {
+                    debug!(self.logger, "Re-encrypting legacy session pass"; "device_id" => &device_id);
+                    self.db.update_session_pass(hashed_session_pass, &enc.encrypt(repo_pass.as_bytes(), session_pass.as_bytes()))?;
+                }
+
+                Ok(Some(CachedSession {
+                    identity: DeviceIdentity {
+                        id: device_id,
+                        account_id,
+                        repo_pass,
+                        role,
+                        token_scope: None
+                    },
+                    created,
+                    last_used
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn find_api_token(&self, enc: &Encryptor, token: &str, hashed_token: &str) -> Result<Option<CachedSession>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let api_token = self.db.find_api_token(hashed_token)?;
+        self.report_timer("find_api_token", stopwatch);
+
+        match api_token {
+            Some((device_id, account_id, scope, pass)) => {
+                debug!(self.logger, "Found API token in DB"; "device_id" => &device_id, "scope" => &scope);
+
+                let token_scope = TokenScope::from_name(&scope)
+                    .ok_or_else(|| CustomError::new(&format!("Unknown scope of API token: {}", scope)))?;
+
+                let repo_pass = String::from_utf8(enc.decrypt(&pass, token.as_bytes())?.data)?;
+                let role = self.find_device_role(&account_id, &device_id)?;
+                let now = Utc::now().naive_utc();
+
+                Ok(Some(CachedSession {
+                    identity: DeviceIdentity {
+                        id: device_id,
+                        account_id,
+                        repo_pass,
+                        role,
+                        token_scope: Some(token_scope)
+                    },
+                    created: now,
+                    last_used: now
+                }))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn find_device_role(&self, account_id: &str, device_id: &str) -> Result<DeviceRole, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let role = self.db.find_device_role(account_id, device_id)?;
+        self.report_timer("find_device_role", stopwatch);
+
+        match role {
+            Some(role) => DeviceRole::from_name(&role)
+                .ok_or_else(|| Error::from(CustomError::new(&format!("Unknown role of device: {}", role)))),
+            None => Ok(DeviceRole::default())
+        }
+    }
+
+    /// Lists roles of all known devices of the account, including the ones whose role wasn't set explicitly.
+    pub fn list_device_roles(&self, account_id: &str) -> Result<Vec<DeviceRoleInfo>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let mut roles: HashMap<String, String> = self.db.get_devices(account_id)?
+            .into_iter()
+            .map(|device| (device.id, DeviceRole::default().name().to_string()))
+            .collect();
+
+        roles.extend(self.db.list_device_roles(account_id)?);
+
+        let mut roles: Vec<DeviceRoleInfo> = roles.into_iter()
+            .map(|(device_id, role)| DeviceRoleInfo { device_id, role })
+            .collect();
+
+        roles.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+        self.report_timer("list_device_roles", stopwatch);
+        Ok(roles)
+    }
+
+    pub fn set_device_role(&self, account_id: &str, device_id: &str, role: DeviceRole) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.set_device_role(account_id, device_id, role.name())?;
+        // cached sessions and tokens of the device carry the old role
+        self.invalidate_session_cache();
+
+        self.report_timer("set_device_role", stopwatch);
+        Ok(())
+    }
+
+    pub fn authenticate(&self, enc: &Encryptor, session_pass: &str) -> Result<Authentication, Error> {
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+        let hashed_session_pass = Dao::hash_session_id(session_pass);
+
+        let cached = self.session_cache.lock().unwrap().get_mut(&hashed_session_pass).map(|session| session.clone());
+
+        let session = match cached {
+            Some(session) => {
+                debug!(self.logger, "Loaded session from cache"; "session_pass" => session_pass);
+                session
+            },
+            None => {
+                // the cache is not locked during the DB round-trip, so other requests don't wait for it
+                let generation = self.session_cache_generation.load(Ordering::SeqCst);
+
+                debug!(self.logger, "Loading session from DB"; "session_pass" => session_pass);
+                let session = self.find_session(enc, session_pass, &hashed_session_pass)?;
+
+                let mut cache = self.session_cache.lock().unwrap();
+
+                if generation == self.session_cache_generation.load(Ordering::SeqCst) {
+                    debug!(self.logger, "Loaded session from DB, saving into cache"; "session_pass" => session_pass, "session" => ?session);
+                    cache.insert(hashed_session_pass.clone(), session.clone());
+                } else {
+                    debug!(self.logger, "Sessions were evicted while loading the session from DB, not caching it"; "session_pass" => session_pass);
+                }
+
+                session
+            }
+        };
+
+        let authentication = match session {
+            Some(session) => {
+                if session.identity.token_scope.is_some() {
+                    // API tokens don't expire, they are valid until revoked
+                    Authentication::Valid(session.identity)
+                } else if self.session_config.is_expired(&session.created, &session.last_used, &now) {
+                    debug!(self.logger, "Session has expired"; "session_pass" => session_pass);
+                    Authentication::Expired
+                } else {
+                    // written to the DB in batches, see `flush_session_activity`
+                    if let Some(Some(cached)) = self.session_cache.lock().unwrap().get_mut(&hashed_session_pass) {
+                        cached.last_used = now;
+                    }
+                    self.session_activity.lock().unwrap().insert(hashed_session_pass.clone(), now);
+
+                    Authentication::Valid(session.identity)
+                }
+            },
+            None => Authentication::NotFound
+        };
+
+        self.report_timer("authenticate", stopwatch);
+
+        Ok(authentication)
+    }
+
+    /// Removes the session, returns whether it existed.
+    pub fn logout(&self, session_pass: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+        let hashed_session_pass = Dao::hash_session_id(session_pass);
+
+        let removed = self.db.remove_session(&hashed_session_pass)?;
+        self.evict_sessions(&[hashed_session_pass]);
+
+        self.report_timer("logout", stopwatch);
+        Ok(removed)
+    }
+
+    /// Lists sessions of the account; the one with given session pass is marked as the current one.
+    pub fn list_sessions(&self, account_id: &str, session_pass: &str) -> Result<Vec<SessionInfo>, Error> {
+        // the `last_used` in the DB has to be up-to-date
+        self.flush_session_activity()?;
+
+        let stopwatch = Stopwatch::start_new();
+        let current_id = Dao::hash_session_id(session_pass);
+
+        self.db.list_sessions(account_id)
+            .map(|sessions| {
+                self.report_timer("list_sessions", stopwatch);
+
+                sessions.into_iter().map(|(id, device_id, created, last_used)| {
+                    SessionInfo {
+                        current: id == current_id,
+                        id,
+                        device_id,
+                        created,
+                        last_used
+                    }
+                }).collect()
+            })
+    }
+
+    /// Revokes the session of the account, returns whether it existed.
+    pub fn revoke_session(&self, account_id: &str, session_id: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let removed = self.db.remove_account_session(account_id, session_id)?;
+
+        if removed {
+            self.evict_sessions(&[session_id.to_string()]);
+        }
+
+        self.report_timer("revoke_session", stopwatch);
+        Ok(removed)
+    }
+
+    /// Revokes all sessions of the device, returns number of revoked sessions.
+    pub fn revoke_device_sessions(&self, account_id: &str, device_id: &str) -> Result<u64, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let session_ids: Vec<String> = self.db.list_sessions(account_id)?
+            .into_iter()
+            .filter(|(_, session_device_id, _, _)| session_device_id == device_id)
+            .map(|(id, _, _, _)| id)
+            .collect();
+
+        let removed = self.db.remove_device_sessions(account_id, device_id)?;
+        self.evict_sessions(&session_ids);
+
+        self.report_timer("revoke_device_sessions", stopwatch);
+        Ok(removed)
+    }
+
+    fn evict_sessions(&self, hashed_session_ids: &[String]) -> () {
+        let mut cache = self.session_cache.lock().unwrap();
+        self.session_cache_generation.fetch_add(1, Ordering::SeqCst);
+
+        for id in hashed_session_ids {
+            cache.remove(id);
+        }
+    }
+
+    fn hash_session_id(session_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(session_id.as_bytes());
+        hex::encode(&hasher.result())
+    }
+
+    /// Writes the `last_used` time of recently used sessions to the DB, returns number of updated sessions.
+    pub fn flush_session_activity(&self) -> Result<usize, Error> {
+        let activity: Vec<(String, NaiveDateTime)> = self.session_activity.lock().unwrap().drain().collect();
+
+        if activity.is_empty() {
+            return Ok(0);
+        }
+
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.update_sessions_last_used(&activity)
+            .map(|_| {
+                self.report_timer("flush_session_activity", stopwatch);
+                activity.len()
+            })
+    }
+
+    /// Removes sessions which exceeded their maximal lifetime or idle timeout, returns number of removed sessions.
+    pub fn remove_expired_sessions(&self) -> Result<u64, Error> {
+        // the idle timeout has to be checked against up-to-date `last_used`
+        self.flush_session_activity()?;
+
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+
+        self.db.remove_expired_sessions(&self.session_config.created_threshold(&now), &self.session_config.last_used_threshold(&now))
+            .map(|removed| {
+                self.report_timer("remove_expired_sessions", stopwatch);
+                removed
+            })
+    }
+
+    /// Creates a new session of the device, registering the device if it logs in for the first time. Unless multiple sessions per
+    /// device are allowed, the current session of the device is replaced by the new one.
+    pub fn create_session(&self, enc: &Encryptor, device_id: &str, device_os: Option<&str>, account_id: &str, repo_secret: &str) -> Result<LoginResult, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.insert_device(account_id, device_id, device_os, &Utc::now().naive_utc())?;
+
+        if self.session_config.multiple_per_device {
+            return self.insert_session(enc, device_id, account_id, repo_secret)
+                .map(|new_session_id| {
+                    self.report_timer("login", stopwatch);
+                    debug!(self.logger, "New session: {}", &new_session_id);
+                    LoginResult::NewSession(new_session_id)
+                });
+        }
+
+        let (new_session_id, hashed_session_id, encrypted_pass) = Dao::prepare_session(enc, repo_secret);
+
+        self.db.replace_device_sessions(&hashed_session_id, account_id, device_id, &encrypted_pass, &Utc::now().naive_utc())
+            .map(|replaced| {
+                self.evict_sessions(&replaced);
+                self.report_timer("login", stopwatch);
+
+                if replaced.is_empty() {
+                    debug!(self.logger, "New session: {}", &new_session_id);
+                    LoginResult::NewSession(new_session_id)
+                } else {
+                    debug!(self.logger, "Renewed session: {}", &new_session_id; "replaced_sessions" => replaced.len());
+                    LoginResult::RenewedSession(new_session_id)
+                }
+            })
+    }
+
+    /// Saves new session besides the existing ones. Returns the session ID.
+    fn insert_session(&self, enc: &Encryptor, device_id: &str, account_id: &str, repo_secret: &str) -> Result<String, Error> {
+        let (new_session_id, hashed_session_id, encrypted_pass) = Dao::prepare_session(enc, repo_secret);
+
+        self.db.insert_session(&hashed_session_id, account_id, device_id, &encrypted_pass, &Utc::now().naive_utc())
+            .map(|_| new_session_id)
+    }
+
+    /// Generates new session ID; returns it together with its hash and the repo secret encrypted by it. Only the client knows the ID
+    /// itself, so only the client can decrypt the repo secret.
+    fn prepare_session(enc: &Encryptor, repo_secret: &str) -> (String, String, String) {
+        let new_session_id = Uuid::new_v4().hyphenated().to_string();
+        let hashed_session_id = Dao::hash_session_id(&new_session_id);
+
+        let encrypted_pass = enc.encrypt(repo_secret.as_bytes(), new_session_id.as_bytes());
+
+        (new_session_id, hashed_session_id, encrypted_pass)
+    }
+
+    /// Replaces the password of the account and re-wraps its repo secret. All sessions of the account are invalidated, the new session
+    /// of the device is returned.
+    pub fn change_password(&self, enc: &Encryptor, account_id: &str, device_id: &str, new_pass: &str, repo_secret: &str) -> Result<String, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let password_hash = self.password_hasher.hash(new_pass)?;
+        let wrapped_secret = passwords::wrap_secret(new_pass, repo_secret)?;
+
+        if !self.db.update_account_credentials(account_id, &password_hash, &wrapped_secret)? {
+            return Err(Error::from(CustomError::new("Could not update password of the account")));
+        }
+
+        let removed_sessions = self.db.remove_account_sessions(account_id)?;
+        let removed_tokens = self.db.remove_account_api_tokens(account_id)?;
+        self.invalidate_session_cache();
+
+        debug!(self.logger, "Changed password of account"; "account_id" => account_id, "removed_sessions" => removed_sessions, "removed_api_tokens" => removed_tokens);
+
+        self.insert_session(enc, device_id, account_id, repo_secret)
+            .map(|session_id| {
+                self.report_timer("change_password", stopwatch);
+                session_id
+            })
+    }
+
+    /// Verifies the password of the account with given ID.
+    pub fn verify_account_password(&self, account_id: &str, pass: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        match self.db.find_password_hash(account_id)? {
+            Some(password_hash) => {
+                let verification = self.password_hasher.verify(&password_hash, pass)?;
+                self.report_timer("verify_account_password", stopwatch);
+
+                Ok(verification != PasswordVerification::Invalid)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Decrypts the repo secret of the account. Returns `None` if the account's repo is still encrypted directly by the login password.
+    pub fn unlock_repo_secret(&self, account_id: &str, pass: &str) -> Result<Option<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        match self.db.find_repo_secret(account_id)? {
+            Some(wrapped) => {
+                let repo_secret = passwords::unwrap_secret(pass, &wrapped)?
+                    .ok_or_else(|| CustomError::new("Could not unwrap repo secret with a verified password"))?;
+
+                self.report_timer("unlock_repo_secret", stopwatch);
+                Ok(Some(repo_secret))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Replaces the login password of a legacy account's repo by a random secret. The `change_passphrase(old, new)` callback re-encrypts
+    /// the repo itself; it's called before the secret is saved, so the account never has a secret which doesn't open its repo. When saving
+    /// the secret fails, the repo is re-encrypted back by the password.
+    pub fn migrate_repo_secret<F>(&self, account_id: &str, pass: &str, change_passphrase: F) -> Result<String, Error>
+        where F: Fn(&str, &str) -> Result<(), Error> {
+        let _lock = self.repo_secret_migration.lock().unwrap();
+
+        // concurrent login could have migrated the account meanwhile
+        if let Some(repo_secret) = self.unlock_repo_secret(account_id, pass)? {
+            return Ok(repo_secret);
+        }
+
+        let repo_secret = passwords::generate_repo_secret();
+        let wrapped_secret = passwords::wrap_secret(pass, &repo_secret)?;
+
+        change_passphrase(pass, &repo_secret)?;
+
+        if let Err(e) = self.db.update_repo_secret(account_id, &wrapped_secret) {
+            error!(self.logger, "Could not save repo secret, re-encrypting the repo back by the password: {}", e; "account_id" => account_id);
+            change_passphrase(&repo_secret, pass)?;
+            return Err(e);
+        }
+
+        // existing sessions (and API tokens) contain the login password, which doesn't open the repo anymore
+        self.db.remove_account_sessions(account_id)?;
+        self.db.remove_account_api_tokens(account_id)?;
+        self.invalidate_session_cache();
+
+        debug!(self.logger, "Migrated repo secret of account"; "account_id" => account_id);
+
+        Ok(repo_secret)
+    }
+
+    /// Creates a new API token of the device; returns the token and its ID (hash). Only the client knows the token itself, so only the
+    /// client can decrypt the repo secret.
+    pub fn create_api_token(&self, enc: &Encryptor, account_id: &str, device_id: &str, name: &str, scope: TokenScope, repo_secret: &str) -> Result<(String, String), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let random_bytes: [u8; 32] = rand::thread_rng().gen();
+        let token = format!("{}{}", API_TOKEN_PREFIX, hex::encode(&random_bytes));
+        let hashed_token = Dao::hash_session_id(&token);
+        let encrypted_pass = enc.encrypt(repo_secret.as_bytes(), token.as_bytes());
+
+        self.db.insert_api_token(&hashed_token, account_id, device_id, name, scope.name(), &encrypted_pass, &Utc::now().naive_utc())
+            .map(|_| {
+                self.report_timer("create_api_token", stopwatch);
+                (token, hashed_token)
+            })
+    }
+
+    pub fn list_api_tokens(&self, account_id: &str) -> Result<Vec<ApiTokenInfo>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.list_api_tokens(account_id)
+            .map(|tokens| {
+                self.report_timer("list_api_tokens", stopwatch);
+
+                tokens.into_iter().map(|(id, device_id, name, scope, created)| {
+                    ApiTokenInfo { id, device_id, name, scope, created }
+                }).collect()
+            })
+    }
+
+    /// Revokes the API token of the account, returns whether it existed.
+    pub fn revoke_api_token(&self, account_id: &str, token_id: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let removed = self.db.remove_api_token(account_id, token_id)?;
+
+        if removed {
+            self.evict_sessions(&[token_id.to_string()]);
+        }
+
+        self.report_timer("revoke_api_token", stopwatch);
+        Ok(removed)
+    }
+
+    fn invalidate_session_cache(&self) -> () {
+        let mut cache = self.session_cache.lock().unwrap();
+        self.session_cache_generation.fetch_add(1, Ordering::SeqCst);
+
+        *cache = Cache::new(100);
+    }
+
+    pub fn register(&self, username: &str, pass: &str, repo_secret: &str, recovery_key: Option<String>) -> Result<RegisterResult, Error> {
+        // TODO check format of username
+
+        let stopwatch = Stopwatch::start_new();
+
+        if self.db.find_accounts(username)?.is_empty() {
+            let account_id = Dao::create_account_id();
+            let password_hash = self.password_hasher.hash(pass)?;
+            let wrapped_secret = passwords::wrap_secret(pass, repo_secret)?;
+            let recovery_secret = match recovery_key {
+                Some(ref key) => Some(passwords::wrap_secret(key, repo_secret)?),
+                None => None
+            };
+
+            let inserted = self.db.insert_account(&account_id, username, &password_hash, &wrapped_secret, recovery_secret.as_ref())?;
+
+            self.report_timer("register", stopwatch);
+
+            if inserted {
+                Ok(RegisterResult::Created(account_id, recovery_key))
+            } else {
+                Err(Error::from(CustomError::new("")))
+            }
+        } else {
+            self.report_timer("registerExists", stopwatch);
+            Ok(RegisterResult::Exists)
+        }
+    }
+
+    /// Sets new password of the account using its recovery key. The recovery key can be used only once, it's replaced by a new one.
+    /// All sessions and API tokens of the account are invalidated.
+    pub fn recover_account(&self, username: &str, recovery_key: &str, new_pass: &str) -> Result<RecoverAccountResult, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        for (account_id, recovery_secret) in self.db.find_recovery_secrets(username)? {
+            if let Some(repo_secret) = passwords::unwrap_secret(recovery_key, &recovery_secret)? {
+                let password_hash = self.password_hasher.hash(new_pass)?;
+                let wrapped_secret = passwords::wrap_secret(new_pass, &repo_secret)?;
+                let new_recovery_key = passwords::generate_recovery_key();
+                let new_recovery_secret = passwords::wrap_secret(&new_recovery_key, &repo_secret)?;
+
+                if !self.db.update_recovered_credentials(&account_id, &recovery_secret, &password_hash, &wrapped_secret, &new_recovery_secret)? {
+                    break; // the key was used by a concurrent request
+                }
+
+                let removed_sessions = self.db.remove_account_sessions(&account_id)?;
+                let removed_tokens = self.db.remove_account_api_tokens(&account_id)?;
+                self.invalidate_session_cache();
+
+                debug!(self.logger, "Recovered account"; "account_id" => &account_id, "removed_sessions" => removed_sessions, "removed_api_tokens" => removed_tokens);
+                self.report_timer("recover_account", stopwatch);
+
+                return Ok(RecoverAccountResult::Recovered(new_recovery_key));
+            }
+        }
+
+        self.report_timer("recoverAccountInvalid", stopwatch);
+        Ok(RecoverAccountResult::InvalidRecoveryKey)
+    }
+
+    /// Returns IDs of all accounts with given username.
+    pub fn find_account_ids(&self, username: &str) -> Result<Vec<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_accounts(username)
+            .map(|accounts| {
+                self.report_timer("find_account_ids", stopwatch);
+                accounts.into_iter().map(|(account_id, _)| account_id).collect()
+            })
+    }
+
+    /// Schedules the removal of the account after the grace period, returns its time. Returns `None` if the removal is scheduled already.
+    pub fn request_account_deletion(&self, account_id: &str, actor: &str, grace_period: Duration) -> Result<Option<NaiveDateTime>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let now = Utc::now().naive_utc();
+        let purge_after = now + grace_period;
+
+        if !self.db.schedule_account_deletion(account_id, &purge_after)? {
+            return Ok(None);
+        }
+
+        self.db.insert_audit_record(account_id, "deletion_requested", actor, &now)?;
+
+        self.report_timer("request_account_deletion", stopwatch);
+        Ok(Some(purge_after))
+    }
+
+    /// Returns `false` if the removal of the account was not scheduled.
+    pub fn cancel_account_deletion(&self, account_id: &str, actor: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        if !self.db.cancel_account_deletion(account_id)? {
+            return Ok(false);
+        }
+
+        self.db.insert_audit_record(account_id, "deletion_cancelled", actor, &Utc::now().naive_utc())?;
+
+        self.report_timer("cancel_account_deletion", stopwatch);
+        Ok(true)
+    }
+
+    /// Returns IDs of accounts whose grace period after the deletion request is over.
+    pub fn find_accounts_to_purge(&self) -> Result<Vec<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.find_accounts_to_purge(&Utc::now().naive_utc())
+            .map(|account_ids| {
+                self.report_timer("find_accounts_to_purge", stopwatch);
+                account_ids
+            })
+    }
+
+    /// Claims the account whose removal is due for the purge; see `Database::claim_account_purge`.
+    pub fn claim_account_purge(&self, account_id: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.claim_account_purge(account_id, &Utc::now().naive_utc())
+            .map(|claimed| {
+                self.report_timer("claim_account_purge", stopwatch);
+                claimed
+            })
+    }
+
+    /// Makes the removal of the account due right now, whether it was scheduled or not.
+    pub fn schedule_account_deletion_now(&self, account_id: &str) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.reschedule_account_deletion(account_id, &Utc::now().naive_utc())
+            .map(|_| self.report_timer("schedule_account_deletion_now", stopwatch))
+    }
+
+    /// Removes the account with all its metadata and drops its cached sessions. Returns whether the account existed.
+    pub fn remove_account(&self, account_id: &str, actor: &str) -> Result<bool, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let removed = self.db.remove_account(account_id, actor, &Utc::now().naive_utc())?;
+        self.invalidate_session_cache();
+
+        self.report_timer("remove_account", stopwatch);
+        Ok(removed)
+    }
+
+    /// Finds the account with given username and password, returns its ID. Password hashes in outdated format are replaced.
+    pub fn verify_password(&self, username: &str, pass: &str) -> Result<Option<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let accounts = self.db.find_accounts(username)?;
+
+        if accounts.is_empty() {
+            // the response must not be faster than for an existing username
+            self.password_hasher.verify_dummy(pass)?;
+        }
+
+        // older versions didn't check uniqueness of the username, so there may be more accounts with the same one
+        for (account_id, password_hash) in accounts {
+            match self.password_hasher.verify(&password_hash, pass)? {
+                PasswordVerification::Valid { needs_rehash } => {
+                    if needs_rehash {
+                        debug!(self.logger, "Rehashing password of account"; "account_id" => &account_id);
+
+                        let new_hash = self.password_hasher.hash(pass)?;
+                        self.db.update_account_password(&account_id, &new_hash)?;
+                    }
+
+                    self.report_timer("verify_password", stopwatch);
+                    return Ok(Some(account_id));
+                },
+                PasswordVerification::Invalid => ()
+            }
+        }
+
+        self.report_timer("loginNotFound", stopwatch);
+        Ok(None)
+    }
+
+    /// Generates new TOTP secret of the account; it has to be confirmed by a valid code to become enabled. Returns `None` if the
+    /// account has the TOTP already enabled.
+    pub fn enroll_totp(&self, account_id: &str) -> Result<Option<String>, Error> {
+        let stopwatch = Stopwatch::start_new();
+        let secret = totp::generate_secret();
+
+        self.db.set_totp_secret(account_id, &secret)
+            .map(|updated| {
+                self.report_timer("enroll_totp", stopwatch);
+                if updated { Some(secret) } else { None }
+            })
+    }
+
+    /// Enables the pending TOTP of the account, if the code is valid. New backup codes are generated.
+    pub fn confirm_totp(&self, account_id: &str, code: &str) -> Result<ConfirmTotpResult, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let secret = match self.db.find_totp(account_id)? {
+            Some((secret, false, _)) => secret,
+            _ => return Ok(ConfirmTotpResult::NotEnrolled)
+        };
+
+        match totp::verify(&secret, code, Utc::now().timestamp() as u64)? {
+            Some(step) => {
+                let (codes, code_hashes): (Vec<String>, Vec<String>) = totp::generate_backup_codes().into_iter().unzip();
+
+                let enabled = self.db.enable_totp(account_id, step, &code_hashes)?;
+                self.report_timer("confirm_totp", stopwatch);
+
+                Ok(if enabled { ConfirmTotpResult::Enabled(codes) } else { ConfirmTotpResult::NotEnrolled })
+            },
+            None => Ok(ConfirmTotpResult::InvalidCode)
+        }
+    }
+
+    /// Verifies the TOTP code or a backup code (which is consumed by that) of the account.
+    pub fn verify_second_factor(&self, account_id: &str, code: Option<&str>) -> Result<SecondFactorVerification, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        let (secret, last_step) = match self.db.find_totp(account_id)? {
+            Some((secret, true, last_step)) => (secret, last_step),
+            _ => return Ok(SecondFactorVerification::NotEnabled)
+        };
+
+        let code = match code {
+            Some(code) if !code.trim().is_empty() => code,
+            _ => return Ok(SecondFactorVerification::Missing)
+        };
+
+        let valid = if totp::is_backup_code(code) {
+            self.db.remove_backup_code(account_id, &totp::hash_backup_code(code))?
+        } else {
+            match totp::verify(&secret, code, Utc::now().timestamp() as u64)? {
+                // the same code must not be accepted twice
+                Some(step) if last_step.map(|last| step > last).unwrap_or(true) => self.db.update_totp_last_step(account_id, step)?,
+                _ => false
+            }
+        };
+
+        self.report_timer("verify_second_factor", stopwatch);
+
+        Ok(if valid { SecondFactorVerification::Valid } else { SecondFactorVerification::Invalid })
+    }
+
+    /// Disables the TOTP of the account; both the password and a valid code (or a backup code) are required.
+    pub fn disable_totp(&self, account_id: &str, pass: &str, code: &str) -> Result<DisableTotpResult, Error> {
+        if !self.verify_account_password(account_id, pass)? {
+            return Ok(DisableTotpResult::InvalidCredentials);
+        }
+
+        match self.verify_second_factor(account_id, Some(code))? {
+            SecondFactorVerification::NotEnabled => Ok(DisableTotpResult::NotEnabled),
+            SecondFactorVerification::Valid => {
+                let stopwatch = Stopwatch::start_new();
+
+                self.db.remove_totp(account_id)
+                    .map(|_| {
+                        self.report_timer("disable_totp", stopwatch);
+                        DisableTotpResult::Disabled
+                    })
+            },
+            _ => Ok(DisableTotpResult::InvalidCredentials)
+        }
+    }
+
+    /// Returns how long are next logins of the username (or from the IP address) blocked, if they are.
+    pub fn login_blocked_for(&self, username: &str, client_ip: Option<&str>) -> Result<Option<Duration>, Error> {
+        self.failures_blocked_for("login", username, client_ip)
+    }
+
+    pub fn record_login_failure(&self, username: &str, client_ip: Option<&str>) -> Result<(), Error> {
+        self.record_failure("login", username, client_ip)
+    }
+
+    /// Resets the failure counter of the username. The counter of the IP address is left as it is, otherwise an attacker could reset
+    /// it by logging into their own account.
+    pub fn clear_login_failures(&self, username: &str) -> Result<(), Error> {
+        self.clear_failures("login", username)
+    }
+
+    /// Account recoveries are throttled the same way as logins, but counted separately.
+    pub fn recovery_blocked_for(&self, username: &str, client_ip: Option<&str>) -> Result<Option<Duration>, Error> {
+        self.failures_blocked_for("recovery", username, client_ip)
+    }
+
+    pub fn record_recovery_failure(&self, username: &str, client_ip: Option<&str>) -> Result<(), Error> {
+        self.record_failure("recovery", username, client_ip)
+    }
+
+    pub fn clear_recovery_failures(&self, username: &str) -> Result<(), Error> {
+        self.clear_failures("recovery", username)
+    }
+
+    fn failures_blocked_for(&self, action: &str, username: &str, client_ip: Option<&str>) -> Result<Option<Duration>, Error> {
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+        let config = &self.throttling_config;
+
+        let by_username = self.db.find_throttling(&Dao::throttling_id(&format!("{}_username", action), username))?
+            .and_then(|(failures, last_failure)| config.login_blocked_until(failures, config.username_free_failures, &last_failure, &now));
+
+        let by_ip = match client_ip {
+            Some(ip) => self.db.find_throttling(&Dao::throttling_id(&format!("{}_ip", action), ip))?
+                .and_then(|(failures, last_failure)| config.login_blocked_until(failures, config.ip_free_failures, &last_failure, &now)),
+            None => None
+        };
+
+        self.report_timer(&format!("{}_blocked_for", action), stopwatch);
+
+        Ok(by_username.into_iter().chain(by_ip).max().map(|until| until - now))
+    }
+
+    fn record_failure(&self, action: &str, username: &str, client_ip: Option<&str>) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+        let reset_before = now - self.throttling_config.reset_after;
+
+        self.db.record_attempt(&Dao::throttling_id(&format!("{}_username", action), username), &now, &reset_before)?;
+
+        if let Some(ip) = client_ip {
+            self.db.record_attempt(&Dao::throttling_id(&format!("{}_ip", action), ip), &now, &reset_before)?;
+        }
+
+        self.report_timer(&format!("record_{}_failure", action), stopwatch);
+        Ok(())
+    }
+
+    fn clear_failures(&self, action: &str, username: &str) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.remove_throttling(&Dao::throttling_id(&format!("{}_username", action), username))
+            .map(|_| self.report_timer(&format!("clear_{}_failures", action), stopwatch))
+    }
+
+    /// Returns how long are next registrations from the IP address blocked, if they are.
+    pub fn registration_blocked_for(&self, client_ip: &str) -> Result<Option<Duration>, Error> {
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+
+        self.db.find_throttling(&Dao::throttling_id("register_ip", client_ip))
+            .map(|counter| {
+                self.report_timer("registration_blocked_for", stopwatch);
+
+                counter
+                    .and_then(|(registrations, last_registration)| self.throttling_config.registration_blocked_until(registrations, &last_registration, &now))
+                    .map(|until| until - now)
+            })
+    }
+
+    pub fn record_registration(&self, client_ip: &str) -> Result<(), Error> {
+        let stopwatch = Stopwatch::start_new();
+        let now = Utc::now().naive_utc();
+
+        self.db.record_attempt(&Dao::throttling_id("register_ip", client_ip), &now, &(now - self.throttling_config.registration_window))
+            .map(|_| self.report_timer("record_registration", stopwatch))
+    }
+
+    /// Removes throttling counters which would be reset anyway, returns number of removed counters.
+    pub fn remove_stale_throttling(&self) -> Result<u64, Error> {
+        let stopwatch = Stopwatch::start_new();
+
+        self.db.remove_stale_throttling(&self.throttling_config.stale_threshold(&Utc::now().naive_utc()))
+            .map(|removed| {
+                self.report_timer("remove_stale_throttling", stopwatch);
+                removed
+            })
+    }
+
+    /// The usernames and IP addresses are not stored in plain text.
+    fn throttling_id(scope: &str, subject: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(format!("{}:{}", scope, subject).as_bytes());
+        hex::encode(&hasher.result())
+    }
+
+    fn create_account_id() -> String {
+        let random_bytes: [u8; 32] = rand::thread_rng().gen();
+        hex::encode(&random_bytes)
+    }
+}
+
+/// Checks the new chunk against the chunks already saved in the upload session.
+fn check_upload_chunk(chunks: Vec<UploadChunk>, chunk: &UploadChunk, max_size: u64) -> SavedUploadChunk {
+    let others_size: u64 = chunks.iter()
+        .filter(|c| c.index != chunk.index)
+        .map(|c| c.size)
+        .sum();
+
+    if others_size + chunk.size > max_size {
+        return SavedUploadChunk::SizeExceeded;
+    }
+
+    SavedUploadChunk::Saved(chunks.into_iter().find(|c| c.index == chunk.index).map(|c| c.storage_name))
+}
+
+/// Groups rows of files joined with their versions into files.
+fn to_files<I: IntoIterator<Item = ((u64, String, String), FileVersion)>>(rows: I) -> Vec<File> {
+    rows.into_iter()
+        .collect::<multimap::MultiMap<(u64, String, String), FileVersion>>()
+        .into_iter().map(|((id, device_id, original_name), versions)| {
+        File {
+            id,
+            device_id,
+            original_name,
+            versions
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use slog::{Discard, Logger};
+
+    use crate::encryptor::Encryptor;
+    use crate::passwords::{PasswordHasher, PasswordHashingConfig, WrappedSecret};
+    use crate::responses::LoginResult;
+    use crate::sessions::SessionConfig;
+    use crate::structs::Authentication;
+    use crate::throttling::ThrottlingConfig;
+
+    use super::{Dao, Database, SqliteDatabase};
+
+    fn dao(session_config: SessionConfig) -> Dao {
+        let db = SqliteDatabase::new(":memory:").unwrap();
+
+        for migration in db.migrations() {
+            db.apply_migration(migration).unwrap();
+        }
+
+        db.insert_account("account", "user", "", &WrappedSecret { salt: String::new(), secret: String::new() }, None).unwrap();
+
+        let logger = Logger::root(Discard, o!());
+
+        Dao::new(Box::new(db), PasswordHasher::new(PasswordHashingConfig::default()).unwrap(), session_config, ThrottlingConfig::default(), logger, None)
+    }
+
+    fn encryptor() -> Encryptor {
+        Encryptor::new("0123456789abcdef0123456789abcdef".to_string())
+    }
+
+    fn login(dao: &Dao, enc: &Encryptor) -> String {
+        match dao.create_session(enc, "device", None, "account", "repo secret").unwrap() {
+            LoginResult::NewSession(session_id) | LoginResult::RenewedSession(session_id) => session_id,
+            _ => panic!("Session was not created")
+        }
+    }
+
+    #[test]
+    fn authenticates_valid_session() {
+        let dao = dao(SessionConfig::default());
+        let enc = encryptor();
+        let session_id = login(&dao, &enc);
+
+        // the first authentication loads the session from the DB, the second one from the cache
+        for _ in 0..2 {
+            match dao.authenticate(&enc, &session_id).unwrap() {
+                Authentication::Valid(identity) => {
+                    assert_eq!("account", identity.account_id);
+                    assert_eq!("repo secret", identity.repo_pass);
+                },
+                other => panic!("Unexpected authentication: {:?}", other)
+            }
+        }
+
+        match dao.authenticate(&enc, "unknown").unwrap() {
+            Authentication::NotFound => (),
+            other => panic!("Unexpected authentication: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn re_encrypts_legacy_session_pass() {
+        let dao = dao(SessionConfig::default());
+        let enc = encryptor();
+        let now = chrono::Utc::now().naive_utc();
+
+        // sessions of older versions were 32 characters long, used directly as the AES key
+        let session_id = "0123456789abcdef0123456789abcdef";
+        let hashed_session_id = Dao::hash_session_id(session_id);
+
+        dao.db.insert_device("account", "device", None, &now).unwrap();
+        dao.db.insert_session(&hashed_session_id, "account", "device", &enc.encrypt_legacy(b"repo secret", session_id.as_bytes()), &now).unwrap();
+
+        match dao.authenticate(&enc, session_id).unwrap() {
+            Authentication::Valid(identity) => assert_eq!("repo secret", identity.repo_pass),
+            other => panic!("Unexpected authentication: {:?}", other)
+        }
+
+        let (_, _, pass, _, _) = dao.db.find_session(&hashed_session_id).unwrap().unwrap();
+        let decrypted = enc.decrypt(&pass, session_id.as_bytes()).unwrap();
+
+        assert!(!decrypted.legacy);
+        assert_eq!(b"repo secret".to_vec(), decrypted.data);
+    }
+
+    #[test]
+    fn session_expires_after_max_lifetime() {
+        let dao = dao(SessionConfig { max_lifetime: Duration::milliseconds(500), ..SessionConfig::default() });
+        let enc = encryptor();
+        let session_id = login(&dao, &enc);
+
+        match dao.authenticate(&enc, &session_id).unwrap() {
+            Authentication::Valid(_) => (),
+            other => panic!("Unexpected authentication: {:?}", other)
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(600));
+
+        match dao.authenticate(&enc, &session_id).unwrap() {
+            Authentication::Expired => (),
+            other => panic!("Unexpected authentication: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn session_expires_when_idle() {
+        let dao = dao(SessionConfig { idle_timeout: Duration::milliseconds(300), ..SessionConfig::default() });
+        let enc = encryptor();
+        let session_id = login(&dao, &enc);
+
+        // each use prolongs the session
+        for _ in 0..3 {
+            std::thread::sleep(std::time::Duration::from_millis(150));
+
+            match dao.authenticate(&enc, &session_id).unwrap() {
+                Authentication::Valid(_) => (),
+                other => panic!("Unexpected authentication: {:?}", other)
+            }
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(400));
+
+        match dao.authenticate(&enc, &session_id).unwrap() {
+            Authentication::Expired => (),
+            other => panic!("Unexpected authentication: {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,926 @@
+use chrono::{NaiveDateTime, Utc};
+use failure::Error;
+
+use crate::migrations::{Migration, MYSQL_MIGRATIONS};
+use crate::passwords::WrappedSecret;
+use crate::retention::RetentionPolicy;
+use crate::structs::*;
+
+use super::{Database, check_upload_chunk, to_files};
+
+pub struct MySqlDatabase {
+    pool: ::mysql::Pool,
+    db_name: String
+}
+
+impl MySqlDatabase {
+    pub fn new(connection_query: &str, db_name: &str) -> Result<MySqlDatabase, Error> {
+        ::mysql::Pool::new(connection_query)
+            .map(|pool| {
+                MySqlDatabase {
+                    pool,
+                    db_name: String::from(db_name)
+                }
+            }).map_err(Error::from)
+    }
+
+    fn exec(&self, query: &str) -> ::mysql::error::Result<()> {
+        let query = query.replace("DBNAME", &self.db_name);
+        let string = query.clone();
+
+        self.pool.get_conn()
+            .and_then(|mut conn| {
+                string.split(";").map(String::from).fold(Ok(()), |acc, q| {
+                    acc.and_then(|_| {
+                        let trimmed = q.trim();
+
+                        if !trimmed.is_empty() {
+                            conn.prep_exec(trimmed, ()).map(|_| ())
+                        } else {
+                            Ok(())
+                        }
+                    })
+                })
+            })
+    }
+
+    fn to_file_row(row: ::mysql::Row) -> ((u64, String, String), FileVersion) {
+        let (id, device_id, original_name, versionid, size, hash, created, mtime, storage_name) = ::mysql::from_row(row);
+
+        (
+            (id, device_id, original_name),
+            FileVersion {
+                version: versionid,
+                size,
+                hash,
+                created,
+                mtime,
+                storage_name
+            }
+        )
+    }
+}
+
+impl Database for MySqlDatabase {
+    fn migrations(&self) -> &'static [Migration] {
+        MYSQL_MIGRATIONS
+    }
+
+    fn get_schema_version(&self) -> Result<u32, Error> {
+        let exists = self.pool.prep_exec("SELECT count(*) from information_schema.tables where table_schema=:db_name and table_name='schema_version'",
+                                         params! {"db_name" => &self.db_name})
+            .map(|mut result| {
+                result.next().map(|row| {
+                    let count: u32 = ::mysql::from_row(row.unwrap());
+                    count > 0
+                }).unwrap_or(false)
+            })?;
+
+        if exists {
+            self.pool.prep_exec(format!("SELECT coalesce(max(version), 0) from `{}`.schema_version", self.db_name), ())
+                .map(|mut result| {
+                    result.next().map(|row| ::mysql::from_row(row.unwrap())).unwrap_or(0)
+                })
+                .map_err(Error::from)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Error> {
+        self.exec("CREATE TABLE IF NOT EXISTS `DBNAME`.`schema_version` (
+                     `version` int(10) unsigned NOT NULL PRIMARY KEY,
+                     `description` varchar(250) NOT NULL,
+                     `applied` datetime NOT NULL
+                   ) ENGINE=InnoDB DEFAULT CHARSET=utf32")?;
+
+        // MySQL commits DDL statements implicitly, so the migration can't be wrapped in a transaction and a failed migration may
+        // leave some of its statements applied; the scripts creating tables are idempotent, but `ALTER TABLE ... ADD COLUMN` can't be
+        // run twice (MySQL has no `IF NOT EXISTS` for columns), such a migration has to be fixed up manually (see docs/server.md)
+        self.exec(migration.script)?;
+
+        self.pool.prep_exec(format!("insert into `{}`.schema_version (version, description, applied) values (:version, :description, :applied)", self.db_name),
+                            params! {"version" => migration.version, "description" => migration.description, "applied" => Utc::now().naive_utc()})
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn insert_file(&self, uploaded_file: &UploadedFile) -> Result<bool, Error> {
+        self.pool.prep_exec(
+            format!("insert ignore into `{}`.files (account_id, device_id, original_name, identity_hash) values (:account_id, :device_id, :original_name, :identity_hash)", self.db_name),
+            params! {"device_id" => &uploaded_file.device_id,
+                            "account_id" => &uploaded_file.account_id,
+                            "original_name" => &uploaded_file.original_name,
+                            "identity_hash" => &uploaded_file.identity_hash
+        })
+            .map(|qr| qr.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn insert_file_version(&self, file_id: u64, file_version: &FileVersion) -> Result<u64, Error> {
+        self.pool.prep_exec(
+            format!("insert into `{}`.files_versions (file_id, created, mtime, size, hash, storage_name) values (:file_id, :created, :mtime, :size, :hash, :storage_name)", self.db_name),
+            params! {"file_id" => file_id,
+                                   "created" => &file_version.created,
+                                   "mtime" => &file_version.mtime,
+                                   "size" => &file_version.size,
+                                   "hash" => &file_version.hash,
+                                   "storage_name" => &file_version.storage_name
+                                   })
+            .map(|r| r.last_insert_id())
+            .map_err(Error::from)
+    }
+
+    fn find_file(&self, identity_hash: &str) -> Result<Option<File>, Error> {
+        let result = self.pool.prep_exec(
+            format!("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from `{}`.files join `{}`.files_versions on `{}`.files_versions.file_id = `{}`.files.id where identity_hash=:identity_hash",
+                    self.db_name, self.db_name, self.db_name, self.db_name),
+            params! { "identity_hash" => identity_hash}
+        )?;
+
+        let file_with_versions = to_files(result.map(|x| x.unwrap()).map(MySqlDatabase::to_file_row)).into_iter().next();
+
+        match file_with_versions {
+            Some(f) => Ok(Some(f)),
+            None => {
+                // try fallback and find file even without any versions yet
+                self.pool.prep_exec(
+                    format!("select files.id, device_id, original_name from `{}`.files where identity_hash=:identity_hash",
+                            self.db_name),
+                    params! { "identity_hash" => identity_hash}
+                ).map(|result| {
+                    result.map(|x| x.unwrap()).map(|row| {
+                        let (id, device_id, original_name) = ::mysql::from_row(row);
+
+                        File {
+                            id,
+                            device_id,
+                            original_name,
+                            versions: Vec::new()
+                        }
+                    }).into_iter().next()
+                }).map_err(Error::from)
+            }
+        }
+    }
+
+    fn get_hash_size_and_storage_name(&self, account_id: &str, version_id: u64) -> Result<Option<(String, u64, String)>, Error> {
+        self.pool.prep_exec(format!("select hash, size, storage_name from `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id \
+                                     where `{db}`.files_versions.id=:version_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"version_id" => version_id, "account_id" => account_id})
+            .map(|result| {
+                result.map(|r| r.unwrap())
+                    .map(|row| {
+                        ::mysql::from_row(row)
+                    })
+                    .into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn find_storage_name_by_hash(&self, account_id: &str, hash: &str, size: u64) -> Result<Option<String>, Error> {
+        self.pool.prep_exec(format!("select storage_name from `{}`.files_versions join `{}`.files on `{}`.files_versions.file_id=`{}`.files.id where `{}`.files.account_id=:account_id and hash=:hash and size=:size limit 1",
+                                    self.db_name, self.db_name, self.db_name, self.db_name, self.db_name),
+                            params! {"account_id" => account_id, "hash" => hash, "size" => size})
+            .map(|result| {
+                result.map(|r| r.unwrap())
+                    .map(|row| {
+                        ::mysql::from_row(row)
+                    })
+                    .into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn touch_file_version(&self, account_id: &str, version_id: u64, mtime: &NaiveDateTime) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id set `{db}`.files_versions.mtime=:mtime \
+                                     where `{db}`.files_versions.id=:version_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"mtime" => mtime, "version_id" => version_id, "account_id" => account_id})
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_versions_older_than(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String, u64)>, Error> {
+        self.pool.prep_exec(format!("select files.account_id, files.device_id, files_versions.id from `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id \
+                                     where `{db}`.files_versions.created < :threshold \
+                                     and `{db}`.files_versions.id < (select max(newest.id) from `{db}`.files_versions newest where newest.file_id=`{db}`.files_versions.file_id)", db = self.db_name),
+                            params! {"threshold" => threshold})
+            .map(|result| {
+                result.map(|r| r.unwrap())
+                    .map(|row| {
+                        ::mysql::from_row(row)
+                    })
+                    .collect::<Vec<(String, String, u64)>>()
+            })
+            .map_err(Error::from)
+    }
+
+    fn get_storage_names(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Vec<String>, Error> {
+        self.pool.prep_exec(format!("select storage_name from `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id \
+                                     where `{db}`.files.id=:file_id and `{db}`.files.device_id=:device_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"file_id" => file_id, "device_id" => device_id, "account_id" => account_id})
+            .map(|result| {
+                result.map(|r| r.unwrap())
+                    .map(|row| {
+                        ::mysql::from_row(row)
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .map_err(Error::from)
+    }
+
+    fn list_files(&self, account_id: &str, device_id: &str) -> Result<Vec<File>, Error> {
+        self.pool.prep_exec(
+            format!("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from `{}`.files join `{}`.files_versions on `{}`.files_versions.file_id = `{}`.files.id where account_id=:account_id and device_id=:device_id",
+                    self.db_name, self.db_name, self.db_name, self.db_name), params! { "device_id" => device_id, "account_id" => account_id}
+        ).map(|result| {
+            to_files(result.map(|x| x.unwrap()).map(MySqlDatabase::to_file_row))
+        }).map_err(Error::from)
+    }
+
+    fn list_account_files(&self, account_id: &str) -> Result<Vec<File>, Error> {
+        self.pool.prep_exec(
+            format!("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from `{}`.files join `{}`.files_versions on `{}`.files_versions.file_id = `{}`.files.id where account_id=:account_id",
+                    self.db_name, self.db_name, self.db_name, self.db_name), params! { "account_id" => account_id}
+        ).map(|result| {
+            to_files(result.map(|x| x.unwrap()).map(MySqlDatabase::to_file_row))
+        }).map_err(Error::from)
+    }
+
+    fn remove_file_version(&self, account_id: &str, version_id: u64) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete `{db}`.files_versions from `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id \
+                                     where `{db}`.files_versions.id=:version_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"version_id" => version_id, "account_id" => account_id})
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_file_versions(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete `{db}`.files_versions from `{db}`.files_versions join `{db}`.files on `{db}`.files_versions.file_id=`{db}`.files.id \
+                                     where `{db}`.files.id=:file_id and `{db}`.files.device_id=:device_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"file_id" => file_id, "device_id" => device_id, "account_id" => account_id})
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn remove_file(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{db}`.files where `{db}`.files.id=:file_id and `{db}`.files.device_id=:device_id and `{db}`.files.account_id=:account_id", db = self.db_name),
+                            params! {"file_id" => file_id, "device_id" => device_id, "account_id" => account_id})
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn get_devices(&self, account_id: &str) -> Result<Vec<Device>, Error> {
+        self.pool.prep_exec(format!("SELECT id, name, os, created, last_backup from `{}`.devices where account_id=:account_id order by id", self.db_name), params! {"account_id" => account_id})
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let (id, name, os, created, last_backup) = ::mysql::from_row(row);
+                    Device { id, name, os, created, last_backup }
+                }).collect()
+            })
+            .map_err(Error::from)
+    }
+
+    fn is_known_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("SELECT id from `{}`.devices where account_id=:account_id and id=:device_id", self.db_name), params! {"account_id" => account_id, "device_id" => device_id})
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|_| {
+                    true
+                }).into_iter().next().unwrap_or_else(|| false)
+            })
+            .map_err(Error::from)
+    }
+
+    fn create_upload_session(&self, session: &UploadSession, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{}`.upload_sessions (id, account_id, device_id, original_name, size, mtime, hash, created, last_activity) values (:id, :account_id, :device_id, :original_name, :size, :mtime, :hash, :now, :now)", self.db_name),
+                            params! {"id" => &session.id,
+                                     "now" => now,
+                                     "account_id" => &session.account_id,
+                                     "device_id" => &session.device_id,
+                                     "original_name" => &session.original_name,
+                                     "size" => session.size,
+                                     "mtime" => &session.mtime,
+                                     "hash" => &session.hash})
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn find_upload_session(&self, account_id: &str, device_id: &str, session_id: &str) -> Result<Option<UploadSession>, Error> {
+        self.pool.prep_exec(format!("select id, account_id, device_id, original_name, size, mtime, hash from `{}`.upload_sessions where id=:id and account_id=:account_id and device_id=:device_id", self.db_name),
+                            params! {"id" => session_id, "account_id" => account_id, "device_id" => device_id})
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let (id, account_id, device_id, original_name, size, mtime, hash) = ::mysql::from_row(row);
+
+                    UploadSession {
+                        id,
+                        account_id,
+                        device_id,
+                        original_name,
+                        size,
+                        mtime,
+                        hash
+                    }
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn get_upload_chunks(&self, session_id: &str) -> Result<Vec<UploadChunk>, Error> {
+        self.pool.prep_exec(format!("select idx, size, hash, storage_name from `{}`.upload_sessions_chunks where session_id=:session_id order by idx", self.db_name),
+                            params! {"session_id" => session_id})
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let (index, size, hash, storage_name) = ::mysql::from_row(row);
+
+                    UploadChunk {
+                        index,
+                        size,
+                        hash,
+                        storage_name
+                    }
+                }).collect()
+            })
+            .map_err(Error::from)
+    }
+
+    fn save_upload_chunk(&self, session_id: &str, chunk: &UploadChunk, max_size: u64, now: &NaiveDateTime) -> Result<SavedUploadChunk, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        // locks the session, so concurrent uploads of chunks of the same session are serialized
+        tx.prep_exec(format!("select id from `{}`.upload_sessions where id=:session_id for update", self.db_name),
+                     params! {"session_id" => session_id})?;
+
+        let chunks: Vec<UploadChunk> = tx.prep_exec(format!("select idx, size, hash, storage_name from `{}`.upload_sessions_chunks where session_id=:session_id", self.db_name),
+                                                    params! {"session_id" => session_id})?
+            .map(|x| x.unwrap())
+            .map(|row| {
+                let (index, size, hash, storage_name) = ::mysql::from_row(row);
+
+                UploadChunk {
+                    index,
+                    size,
+                    hash,
+                    storage_name
+                }
+            }).collect();
+
+        let saved = check_upload_chunk(chunks, chunk, max_size);
+
+        if saved == SavedUploadChunk::SizeExceeded {
+            return Ok(saved); // rolled back on drop
+        }
+
+        tx.prep_exec(format!("insert into `{}`.upload_sessions_chunks (session_id, idx, size, hash, storage_name) values (:session_id, :idx, :size, :hash, :storage_name) \
+                              on duplicate key update size=values(size), hash=values(hash), storage_name=values(storage_name)", self.db_name),
+                     params! {"session_id" => session_id,
+                              "idx" => chunk.index,
+                              "size" => chunk.size,
+                              "hash" => &chunk.hash,
+                              "storage_name" => &chunk.storage_name})?;
+
+        tx.prep_exec(format!("update `{}`.upload_sessions set last_activity=:now where id=:session_id", self.db_name),
+                     params! {"now" => now, "session_id" => session_id})?;
+
+        tx.commit()?;
+
+        Ok(saved)
+    }
+
+    fn remove_upload_session(&self, session_id: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.upload_sessions where id=:session_id", self.db_name),
+                            params! {"session_id" => session_id})
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_upload_sessions_inactive_since(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String)>, Error> {
+        self.pool.prep_exec(format!("select id, account_id from `{}`.upload_sessions where last_activity < :threshold", self.db_name),
+                            params! {"threshold" => threshold})
+            .map(|result| {
+                result.map(|x| x.unwrap())
+                    .map(|row| {
+                        ::mysql::from_row(row)
+                    })
+                    .collect::<Vec<(String, String)>>()
+            })
+            .map_err(Error::from)
+    }
+
+    fn get_account_ids(&self) -> Result<Vec<String>, Error> {
+        self.pool.prep_exec(format!("SELECT id from `{}`.accounts", self.db_name), ())
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let account_id: String = ::mysql::from_row(row);
+                    account_id
+                }).collect()
+            })
+            .map_err(Error::from)
+    }
+
+    fn get_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error> {
+        let result = match device_id {
+            Some(device_id) => self.pool.prep_exec(format!("SELECT keep_all_hours, keep_daily_days, keep_weekly_weeks from `{}`.device_retention_policies where account_id=:account_id and device_id=:device_id", self.db_name),
+                                                   params! {"account_id" => account_id, "device_id" => device_id}),
+            None => self.pool.prep_exec(format!("SELECT keep_all_hours, keep_daily_days, keep_weekly_weeks from `{}`.account_retention_policies where account_id=:account_id", self.db_name),
+                                        params! {"account_id" => account_id})
+        }?;
+
+        Ok(result.map(|x| x.unwrap()).map(|row| {
+            let (keep_all_hours, keep_daily_days, keep_weekly_weeks) = ::mysql::from_row(row);
+
+            RetentionPolicy {
+                keep_all_hours,
+                keep_daily_days,
+                keep_weekly_weeks
+            }
+        }).into_iter().next())
+    }
+
+    fn set_retention_policy(&self, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<(), Error> {
+        match device_id {
+            Some(device_id) => self.pool.prep_exec(format!("insert into `{}`.device_retention_policies (account_id, device_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values (:account_id, :device_id, :keep_all_hours, :keep_daily_days, :keep_weekly_weeks) \
+                                                            on duplicate key update keep_all_hours=values(keep_all_hours), keep_daily_days=values(keep_daily_days), keep_weekly_weeks=values(keep_weekly_weeks)", self.db_name),
+                                                   params! {"account_id" => account_id,
+                                                            "device_id" => device_id,
+                                                            "keep_all_hours" => policy.keep_all_hours,
+                                                            "keep_daily_days" => policy.keep_daily_days,
+                                                            "keep_weekly_weeks" => policy.keep_weekly_weeks}),
+            None => self.pool.prep_exec(format!("insert into `{}`.account_retention_policies (account_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values (:account_id, :keep_all_hours, :keep_daily_days, :keep_weekly_weeks) \
+                                                 on duplicate key update keep_all_hours=values(keep_all_hours), keep_daily_days=values(keep_daily_days), keep_weekly_weeks=values(keep_weekly_weeks)", self.db_name),
+                                        params! {"account_id" => account_id,
+                                                 "keep_all_hours" => policy.keep_all_hours,
+                                                 "keep_daily_days" => policy.keep_daily_days,
+                                                 "keep_weekly_weeks" => policy.keep_weekly_weeks})
+        }.map(|_| ()).map_err(Error::from)
+    }
+
+    fn remove_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<bool, Error> {
+        match device_id {
+            Some(device_id) => self.pool.prep_exec(format!("delete from `{}`.device_retention_policies where account_id=:account_id and device_id=:device_id", self.db_name),
+                                                   params! {"account_id" => account_id, "device_id" => device_id}),
+            None => self.pool.prep_exec(format!("delete from `{}`.account_retention_policies where account_id=:account_id", self.db_name),
+                                        params! {"account_id" => account_id})
+        }.map(|result| result.affected_rows() > 0).map_err(Error::from)
+    }
+
+    fn find_session(&self, hashed_session_id: &str) -> Result<Option<(String, String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        self.pool.prep_exec(format!("SELECT device_id, account_id, pass, created, last_used from `{}`.sessions where id=:id", self.db_name), params!("id" => hashed_session_id))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    ::mysql::from_row(row)
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn update_session_pass(&self, hashed_session_id: &str, encrypted_pass: &str) -> Result<(), Error> {
+        self.pool.prep_exec(format!("update `{}`.sessions set pass=:pass where id=:id", self.db_name), params!("id" => hashed_session_id, "pass" => encrypted_pass))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn update_sessions_last_used(&self, activity: &[(String, NaiveDateTime)]) -> Result<(), Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        for (hashed_session_id, last_used) in activity {
+            tx.prep_exec(format!("update `{}`.sessions set last_used=:last_used where id=:id", self.db_name), params!("id" => hashed_session_id, "last_used" => last_used))?;
+        }
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_expired_sessions(&self, created_before: &NaiveDateTime, last_used_before: &NaiveDateTime) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.sessions where created < :created or last_used < :last_used", self.db_name),
+                            params!("created" => created_before, "last_used" => last_used_before))
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn insert_session(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{}`.sessions (id, account_id, device_id, pass, created, last_used) values(:id, :account_id, :device_id, :pass, :now, :now)", self.db_name),
+                            params!("id" => hashed_session_id, "account_id" => account_id, "device_id" => device_id, "pass" => encrypted_pass, "now" => now))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn replace_device_sessions(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        let replaced: Vec<String> = tx.prep_exec(format!("select id from `{}`.sessions where account_id=:account_id and device_id=:device_id for update", self.db_name),
+                                                 params!("account_id" => account_id, "device_id" => device_id))?
+            .map(|x| x.unwrap())
+            .map(|row| {
+                let s: String = ::mysql::from_row(row);
+                s
+            }).collect();
+
+        tx.prep_exec(format!("delete from `{}`.sessions where account_id=:account_id and device_id=:device_id", self.db_name),
+                     params!("account_id" => account_id, "device_id" => device_id))?;
+
+        tx.prep_exec(format!("insert into `{}`.sessions (id, account_id, device_id, pass, created, last_used) values(:id, :account_id, :device_id, :pass, :now, :now)", self.db_name),
+                     params!("id" => hashed_session_id, "account_id" => account_id, "device_id" => device_id, "pass" => encrypted_pass, "now" => now))?;
+
+        tx.commit()?;
+
+        Ok(replaced)
+    }
+
+    fn find_throttling(&self, id: &str) -> Result<Option<(u32, NaiveDateTime)>, Error> {
+        self.pool.prep_exec(format!("select attempts, last_attempt from `{}`.throttling where id=:id", self.db_name), params!("id" => id))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    ::mysql::from_row(row)
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn record_attempt(&self, id: &str, now: &NaiveDateTime, reset_before: &NaiveDateTime) -> Result<(), Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        tx.prep_exec(format!("delete from `{}`.throttling where id=:id and last_attempt < :reset_before", self.db_name),
+                     params!("id" => id, "reset_before" => reset_before))?;
+
+        tx.prep_exec(format!("insert into `{}`.throttling (id, attempts, last_attempt) values(:id, 1, :now) on duplicate key update attempts=attempts + 1, last_attempt=:now", self.db_name),
+                     params!("id" => id, "now" => now))?;
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_throttling(&self, id: &str) -> Result<(), Error> {
+        self.pool.prep_exec(format!("delete from `{}`.throttling where id=:id", self.db_name), params!("id" => id))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_stale_throttling(&self, last_attempt_before: &NaiveDateTime) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.throttling where last_attempt < :last_attempt", self.db_name), params!("last_attempt" => last_attempt_before))
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn find_accounts(&self, username: &str) -> Result<Vec<(String, String)>, Error> {
+        self.pool.prep_exec(format!("select id, password from `{}`.accounts where username=:username", self.db_name), params!("username" => username))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    ::mysql::from_row(row)
+                }).collect())
+            .map_err(Error::from)
+    }
+
+    fn insert_account(&self, account_id: &str, username: &str, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: Option<&WrappedSecret>) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("insert into `{}`.accounts (id, username, password, repo_key_salt, repo_secret, recovery_key_salt, recovery_secret) values (:id, :username, :pass, :salt, :secret, :recovery_salt, :recovery_secret)", self.db_name),
+                            params!("id" => account_id, "username" => username, "pass" => password_hash, "salt" => repo_secret.salt.as_str(), "secret" => repo_secret.secret.as_str(),
+                                    "recovery_salt" => recovery_secret.map(|s| s.salt.as_str()), "recovery_secret" => recovery_secret.map(|s| s.secret.as_str())))
+            .map(|result| result.affected_rows() == 1)
+            .map_err(Error::from)
+    }
+
+    fn update_account_password(&self, account_id: &str, password_hash: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set password=:pass where id=:id", self.db_name), params!("id" => account_id, "pass" => password_hash))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_repo_secret(&self, account_id: &str) -> Result<Option<WrappedSecret>, Error> {
+        self.pool.prep_exec(format!("select repo_key_salt, repo_secret from `{}`.accounts where id=:id and repo_secret is not null", self.db_name), params!("id" => account_id))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    let (salt, secret) = ::mysql::from_row(row);
+                    WrappedSecret { salt, secret }
+                }).into_iter().next())
+            .map_err(Error::from)
+    }
+
+    fn update_repo_secret(&self, account_id: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set repo_key_salt=:salt, repo_secret=:secret where id=:id", self.db_name),
+                            params!("id" => account_id, "salt" => repo_secret.salt.as_str(), "secret" => repo_secret.secret.as_str()))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_recovery_secrets(&self, username: &str) -> Result<Vec<(String, WrappedSecret)>, Error> {
+        self.pool.prep_exec(format!("select id, recovery_key_salt, recovery_secret from `{}`.accounts where username=:username and recovery_secret is not null", self.db_name),
+                            params!("username" => username))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    let (account_id, salt, secret) = ::mysql::from_row(row);
+                    (account_id, WrappedSecret { salt, secret })
+                }).collect())
+            .map_err(Error::from)
+    }
+
+    fn find_password_hash(&self, account_id: &str) -> Result<Option<String>, Error> {
+        self.pool.prep_exec(format!("select password from `{}`.accounts where id=:id", self.db_name), params!("id" => account_id))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    let s: String = ::mysql::from_row(row);
+                    s
+                }).into_iter().next())
+            .map_err(Error::from)
+    }
+
+    fn update_account_credentials(&self, account_id: &str, password_hash: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set password=:pass, repo_key_salt=:salt, repo_secret=:secret where id=:id", self.db_name),
+                            params!("id" => account_id, "pass" => password_hash, "salt" => repo_secret.salt.as_str(), "secret" => repo_secret.secret.as_str()))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn update_recovered_credentials(&self, account_id: &str, used_recovery_secret: &WrappedSecret, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set password=:pass, repo_key_salt=:salt, repo_secret=:secret, recovery_key_salt=:recovery_salt, recovery_secret=:recovery_secret \
+                                     where id=:id and recovery_secret=:used_recovery_secret", self.db_name),
+                            params!("id" => account_id,
+                                    "pass" => password_hash,
+                                    "salt" => repo_secret.salt.as_str(),
+                                    "secret" => repo_secret.secret.as_str(),
+                                    "recovery_salt" => recovery_secret.salt.as_str(),
+                                    "recovery_secret" => recovery_secret.secret.as_str(),
+                                    "used_recovery_secret" => used_recovery_secret.secret.as_str()))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_sessions(&self, account_id: &str) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.sessions where account_id=:account_id", self.db_name), params!("account_id" => account_id))
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn list_sessions(&self, account_id: &str) -> Result<Vec<(String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        self.pool.prep_exec(format!("select id, device_id, created, last_used from `{}`.sessions where account_id=:account_id order by created", self.db_name),
+                            params!("account_id" => account_id))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    ::mysql::from_row(row)
+                }).collect())
+            .map_err(Error::from)
+    }
+
+    fn remove_session(&self, hashed_session_id: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.sessions where id=:id", self.db_name), params!("id" => hashed_session_id))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_session(&self, account_id: &str, hashed_session_id: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.sessions where id=:id and account_id=:account_id", self.db_name),
+                            params!("id" => hashed_session_id, "account_id" => account_id))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_device_sessions(&self, account_id: &str, device_id: &str) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.sessions where account_id=:account_id and device_id=:device_id", self.db_name),
+                            params!("account_id" => account_id, "device_id" => device_id))
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn find_totp(&self, account_id: &str) -> Result<Option<(String, bool, Option<u64>)>, Error> {
+        self.pool.prep_exec(format!("select totp_secret, totp_enabled, totp_last_step from `{}`.accounts where id=:id and totp_secret is not null", self.db_name),
+                            params!("id" => account_id))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    ::mysql::from_row(row)
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn set_totp_secret(&self, account_id: &str, secret: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set totp_secret=:secret, totp_last_step=null where id=:id and totp_enabled=0", self.db_name),
+                            params!("id" => account_id, "secret" => secret))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn enable_totp(&self, account_id: &str, step: u64, backup_code_hashes: &[String]) -> Result<bool, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        let updated = tx.prep_exec(format!("update `{}`.accounts set totp_enabled=1, totp_last_step=:step where id=:id and totp_enabled=0 and totp_secret is not null", self.db_name),
+                                   params!("id" => account_id, "step" => step))?
+            .affected_rows();
+
+        if updated == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.prep_exec(format!("delete from `{}`.backup_codes where account_id=:account_id", self.db_name), params!("account_id" => account_id))?;
+
+        for code_hash in backup_code_hashes {
+            tx.prep_exec(format!("insert into `{}`.backup_codes (account_id, code_hash) values (:account_id, :code_hash)", self.db_name),
+                         params!("account_id" => account_id, "code_hash" => code_hash.as_str()))?;
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn update_totp_last_step(&self, account_id: &str, step: u64) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set totp_last_step=:step where id=:id and (totp_last_step is null or totp_last_step < :step)", self.db_name),
+                            params!("id" => account_id, "step" => step))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_backup_code(&self, account_id: &str, code_hash: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.backup_codes where account_id=:account_id and code_hash=:code_hash", self.db_name),
+                            params!("account_id" => account_id, "code_hash" => code_hash))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_totp(&self, account_id: &str) -> Result<bool, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        tx.prep_exec(format!("delete from `{}`.backup_codes where account_id=:account_id", self.db_name), params!("account_id" => account_id))?;
+
+        let updated = tx.prep_exec(format!("update `{}`.accounts set totp_secret=null, totp_enabled=0, totp_last_step=null where id=:id", self.db_name),
+                                   params!("id" => account_id))?
+            .affected_rows();
+
+        tx.commit()?;
+
+        Ok(updated > 0)
+    }
+
+    fn insert_api_token(&self, hashed_token: &str, account_id: &str, device_id: &str, name: &str, scope: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{}`.api_tokens (id, account_id, device_id, name, scope, pass, created) values (:id, :account_id, :device_id, :name, :scope, :pass, :now)", self.db_name),
+                            params!("id" => hashed_token, "account_id" => account_id, "device_id" => device_id, "name" => name, "scope" => scope, "pass" => encrypted_pass, "now" => now))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn find_api_token(&self, hashed_token: &str) -> Result<Option<(String, String, String, String)>, Error> {
+        self.pool.prep_exec(format!("select device_id, account_id, scope, pass from `{}`.api_tokens where id=:id", self.db_name), params!("id" => hashed_token))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    ::mysql::from_row(row)
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn list_api_tokens(&self, account_id: &str) -> Result<Vec<(String, String, String, String, NaiveDateTime)>, Error> {
+        self.pool.prep_exec(format!("select id, device_id, name, scope, created from `{}`.api_tokens where account_id=:account_id order by created", self.db_name),
+                            params!("account_id" => account_id))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    ::mysql::from_row(row)
+                }).collect())
+            .map_err(Error::from)
+    }
+
+    fn remove_api_token(&self, account_id: &str, hashed_token: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.api_tokens where id=:id and account_id=:account_id", self.db_name),
+                            params!("id" => hashed_token, "account_id" => account_id))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_api_tokens(&self, account_id: &str) -> Result<u64, Error> {
+        self.pool.prep_exec(format!("delete from `{}`.api_tokens where account_id=:account_id", self.db_name), params!("account_id" => account_id))
+            .map(|result| result.affected_rows())
+            .map_err(Error::from)
+    }
+
+    fn find_device_role(&self, account_id: &str, device_id: &str) -> Result<Option<String>, Error> {
+        self.pool.prep_exec(format!("select role from `{}`.device_roles where account_id=:account_id and device_id=:device_id", self.db_name),
+                            params!("account_id" => account_id, "device_id" => device_id))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let role: String = ::mysql::from_row(row);
+                    role
+                }).into_iter().next()
+            })
+            .map_err(Error::from)
+    }
+
+    fn set_device_role(&self, account_id: &str, device_id: &str, role: &str) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{}`.device_roles (account_id, device_id, role) values (:account_id, :device_id, :role)
+                                     on duplicate key update role=values(role)", self.db_name),
+                            params!("account_id" => account_id, "device_id" => device_id, "role" => role))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn list_device_roles(&self, account_id: &str) -> Result<Vec<(String, String)>, Error> {
+        self.pool.prep_exec(format!("select device_id, role from `{}`.device_roles where account_id=:account_id order by device_id", self.db_name),
+                            params!("account_id" => account_id))
+            .map(|r| r.map(|x| x.unwrap())
+                .map(|row| {
+                    ::mysql::from_row(row)
+                }).collect())
+            .map_err(Error::from)
+    }
+
+    fn insert_device(&self, account_id: &str, device_id: &str, os: Option<&str>, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{}`.devices (account_id, id, os, created) values (:account_id, :device_id, :os, :now)
+                                     on duplicate key update os=coalesce(values(os), os)", self.db_name),
+                            params!("account_id" => account_id, "device_id" => device_id, "os" => os, "now" => now))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn update_device_name(&self, account_id: &str, device_id: &str, name: &str) -> Result<bool, Error> {
+        // MySQL reports only changed rows as affected, so renaming to the same name has to be checked separately
+        self.pool.prep_exec(format!("update `{}`.devices set name=:name where account_id=:account_id and id=:device_id", self.db_name),
+                            params!("name" => name, "account_id" => account_id, "device_id" => device_id))
+            .map_err(Error::from)
+            .and_then(|result| if result.affected_rows() > 0 { Ok(true) } else { self.is_known_device(account_id, device_id) })
+    }
+
+    fn update_device_last_backup(&self, account_id: &str, device_id: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("update `{}`.devices set last_backup=:now where account_id=:account_id and id=:device_id", self.db_name),
+                            params!("now" => now, "account_id" => account_id, "device_id" => device_id))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        for table in &["sessions", "api_tokens", "device_roles", "device_retention_policies"] {
+            tx.prep_exec(format!("delete from `{}`.{} where account_id=:account_id and device_id=:device_id", self.db_name, table),
+                         params!("account_id" => account_id, "device_id" => device_id))?;
+        }
+
+        let removed = tx.prep_exec(format!("delete from `{}`.devices where account_id=:account_id and id=:device_id", self.db_name),
+                                   params!("account_id" => account_id, "device_id" => device_id))?
+            .affected_rows();
+
+        tx.commit()?;
+
+        Ok(removed > 0)
+    }
+
+    fn schedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set deletion_scheduled=:purge_after where id=:account_id and deletion_scheduled is null", self.db_name),
+                            params!("purge_after" => purge_after, "account_id" => account_id))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn cancel_account_deletion(&self, account_id: &str) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set deletion_scheduled=null where id=:account_id and deletion_scheduled is not null", self.db_name),
+                            params!("account_id" => account_id))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_accounts_to_purge(&self, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        self.pool.prep_exec(format!("select id from `{}`.accounts where deletion_scheduled<=:now", self.db_name), params!("now" => now))
+            .map(|result| {
+                result.map(|x| x.unwrap()).map(|row| {
+                    let account_id: String = ::mysql::from_row(row);
+                    account_id
+                }).collect()
+            })
+            .map_err(Error::from)
+    }
+
+    fn claim_account_purge(&self, account_id: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set deletion_scheduled=null where id=:account_id and deletion_scheduled<=:now", self.db_name),
+                            params!("account_id" => account_id, "now" => now))
+            .map(|result| result.affected_rows() > 0)
+            .map_err(Error::from)
+    }
+
+    fn reschedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("update `{}`.accounts set deletion_scheduled=:purge_after where id=:account_id", self.db_name),
+                            params!("purge_after" => purge_after, "account_id" => account_id))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn insert_audit_record(&self, account_id: &str, event: &str, actor: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.pool.prep_exec(format!("insert into `{db}`.audit_log (account_id, username, event, actor, created)
+                                     select id, username, :event, :actor, :now from `{db}`.accounts where id=:account_id", db = self.db_name),
+                            params!("event" => event, "actor" => actor, "now" => now, "account_id" => account_id))
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_account(&self, account_id: &str, actor: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        let mut tx = self.pool.start_transaction(false, None, None)?;
+
+        // the record has to be inserted first, it copies the username from the account
+        tx.prep_exec(format!("insert into `{db}`.audit_log (account_id, username, event, actor, created)
+                              select id, username, 'account_deleted', :actor, :now from `{db}`.accounts where id=:account_id", db = self.db_name),
+                     params!("actor" => actor, "now" => now, "account_id" => account_id))?;
+
+        let removed = tx.prep_exec(format!("delete from `{}`.accounts where id=:account_id", self.db_name), params!("account_id" => account_id))?
+            .affected_rows();
+
+        if removed == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+}
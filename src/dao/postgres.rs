@@ -0,0 +1,668 @@
+use chrono::{NaiveDateTime, Utc};
+use failure::Error;
+use ::postgres::rows::Row;
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+
+use crate::failures::CustomError;
+use crate::migrations::{Migration, POSTGRES_MIGRATIONS};
+use crate::passwords::WrappedSecret;
+use crate::retention::RetentionPolicy;
+use crate::structs::*;
+
+use super::{Database, check_upload_chunk, to_files};
+
+/// PostgreSQL doesn't support unsigned types; all the numbers are stored as `bigint` and converted from/to `i64`.
+pub struct PostgresDatabase {
+    pool: Pool<PostgresConnectionManager>
+}
+
+impl PostgresDatabase {
+    pub fn new(connection_url: &str) -> Result<PostgresDatabase, Error> {
+        let manager = PostgresConnectionManager::new(connection_url, TlsMode::None)?;
+
+        Pool::new(manager)
+            .map(|pool| PostgresDatabase { pool })
+            .map_err(Error::from)
+    }
+
+    fn conn(&self) -> Result<PooledConnection<PostgresConnectionManager>, Error> {
+        self.pool.get().map_err(Error::from)
+    }
+
+    fn to_file_row(row: Row) -> ((u64, String, String), FileVersion) {
+        (
+            (row.get::<_, i64>(0) as u64, row.get(1), row.get(2)),
+            FileVersion {
+                version: row.get::<_, i64>(3) as u64,
+                size: row.get::<_, i64>(4) as u64,
+                hash: row.get(5),
+                created: row.get(6),
+                mtime: row.get(7),
+                storage_name: row.get(8)
+            }
+        )
+    }
+
+    fn to_retention_policy(row: Row) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_all_hours: row.get::<_, i64>(0) as u32,
+            keep_daily_days: row.get::<_, i64>(1) as u32,
+            keep_weekly_weeks: row.get::<_, i64>(2) as u32
+        }
+    }
+
+    fn query_files(&self, query: &str, params: &[&dyn ::postgres::types::ToSql]) -> Result<Vec<File>, Error> {
+        let rows = self.conn()?.query(query, params)?;
+
+        Ok(to_files(rows.iter().map(PostgresDatabase::to_file_row)))
+    }
+
+    fn query_strings(&self, query: &str, params: &[&dyn ::postgres::types::ToSql]) -> Result<Vec<String>, Error> {
+        let rows = self.conn()?.query(query, params)?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn execute(&self, query: &str, params: &[&dyn ::postgres::types::ToSql]) -> Result<u64, Error> {
+        self.conn()?.execute(query, params).map_err(Error::from)
+    }
+}
+
+impl Database for PostgresDatabase {
+    fn migrations(&self) -> &'static [Migration] {
+        POSTGRES_MIGRATIONS
+    }
+
+    fn get_schema_version(&self) -> Result<u32, Error> {
+        let conn = self.conn()?;
+
+        let exists: i64 = conn.query("select count(*) from information_schema.tables where table_schema=current_schema() and table_name='schema_version'", &[])?
+            .iter().next().map(|row| row.get(0)).unwrap_or(0);
+
+        if exists > 0 {
+            let version: i32 = conn.query("select coalesce(max(version), 0) from schema_version", &[])?
+                .iter().next().map(|row| row.get(0)).unwrap_or(0);
+
+            Ok(version as u32)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Error> {
+        let conn = self.conn()?;
+
+        // PostgreSQL supports transactional DDL, so the migration is applied completely or not at all
+        let tx = conn.transaction()?;
+
+        tx.batch_execute("CREATE TABLE IF NOT EXISTS schema_version (
+                            version integer NOT NULL PRIMARY KEY,
+                            description varchar(250) NOT NULL,
+                            applied timestamp NOT NULL
+                          )")?;
+
+        tx.batch_execute(migration.script)?;
+
+        tx.execute("insert into schema_version (version, description, applied) values ($1, $2, $3)",
+                   &[&(migration.version as i32), &migration.description, &Utc::now().naive_utc()])?;
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn insert_file(&self, uploaded_file: &UploadedFile) -> Result<bool, Error> {
+        self.execute("insert into files (account_id, device_id, original_name, identity_hash) values ($1, $2, $3, $4) on conflict do nothing",
+                     &[&uploaded_file.account_id, &uploaded_file.device_id, &uploaded_file.original_name, &uploaded_file.identity_hash])
+            .map(|affected| affected > 0)
+    }
+
+    fn insert_file_version(&self, file_id: u64, file_version: &FileVersion) -> Result<u64, Error> {
+        let rows = self.conn()?.query("insert into files_versions (file_id, created, mtime, size, hash, storage_name) values ($1, $2, $3, $4, $5, $6) returning id",
+                                      &[&(file_id as i64), &file_version.created, &file_version.mtime, &(file_version.size as i64), &file_version.hash, &file_version.storage_name])?;
+
+        rows.iter().next()
+            .map(|row| row.get::<_, i64>(0) as u64)
+            .ok_or_else(|| Error::from(CustomError::new("Inserted file version has no ID")))
+    }
+
+    fn find_file(&self, identity_hash: &str) -> Result<Option<File>, Error> {
+        let file_with_versions = self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where identity_hash=$1",
+                                                  &[&identity_hash])?
+            .into_iter().next();
+
+        match file_with_versions {
+            Some(f) => Ok(Some(f)),
+            None => {
+                // try fallback and find file even without any versions yet
+                let rows = self.conn()?.query("select id, device_id, original_name from files where identity_hash=$1", &[&identity_hash])?;
+
+                Ok(rows.iter().next().map(|row| {
+                    File {
+                        id: row.get::<_, i64>(0) as u64,
+                        device_id: row.get(1),
+                        original_name: row.get(2),
+                        versions: Vec::new()
+                    }
+                }))
+            }
+        }
+    }
+
+    fn get_hash_size_and_storage_name(&self, account_id: &str, version_id: u64) -> Result<Option<(String, u64, String)>, Error> {
+        let rows = self.conn()?.query("select hash, size, storage_name from files_versions join files on files_versions.file_id=files.id where files_versions.id=$1 and files.account_id=$2",
+                                      &[&(version_id as i64), &account_id])?;
+
+        Ok(rows.iter().next().map(|row| (row.get(0), row.get::<_, i64>(1) as u64, row.get(2))))
+    }
+
+    fn find_storage_name_by_hash(&self, account_id: &str, hash: &str, size: u64) -> Result<Option<String>, Error> {
+        self.query_strings("select storage_name from files_versions join files on files_versions.file_id=files.id where files.account_id=$1 and hash=$2 and size=$3 limit 1",
+                           &[&account_id, &hash, &(size as i64)])
+            .map(|names| names.into_iter().next())
+    }
+
+    fn touch_file_version(&self, account_id: &str, version_id: u64, mtime: &NaiveDateTime) -> Result<bool, Error> {
+        self.execute("update files_versions set mtime=$1 from files where files_versions.file_id=files.id and files_versions.id=$2 and files.account_id=$3",
+                     &[mtime, &(version_id as i64), &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn find_versions_older_than(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String, u64)>, Error> {
+        let rows = self.conn()?.query("select files.account_id, files.device_id, files_versions.id from files_versions join files on files_versions.file_id=files.id \
+                                       where files_versions.created < $1 \
+                                       and files_versions.id < (select max(newest.id) from files_versions newest where newest.file_id=files_versions.file_id)",
+                                      &[threshold])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get::<_, i64>(2) as u64)).collect())
+    }
+
+    fn get_storage_names(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Vec<String>, Error> {
+        self.query_strings("select storage_name from files_versions join files on files_versions.file_id=files.id where files.id=$1 and files.device_id=$2 and files.account_id=$3",
+                           &[&(file_id as i64), &device_id, &account_id])
+    }
+
+    fn list_files(&self, account_id: &str, device_id: &str) -> Result<Vec<File>, Error> {
+        self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where account_id=$1 and device_id=$2",
+                         &[&account_id, &device_id])
+    }
+
+    fn list_account_files(&self, account_id: &str) -> Result<Vec<File>, Error> {
+        self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where account_id=$1",
+                         &[&account_id])
+    }
+
+    fn remove_file_version(&self, account_id: &str, version_id: u64) -> Result<bool, Error> {
+        self.execute("delete from files_versions using files where files_versions.file_id=files.id and files_versions.id=$1 and files.account_id=$2",
+                     &[&(version_id as i64), &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_file_versions(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<u64, Error> {
+        self.execute("delete from files_versions using files where files_versions.file_id=files.id and files.id=$1 and files.device_id=$2 and files.account_id=$3",
+                     &[&(file_id as i64), &device_id, &account_id])
+    }
+
+    fn remove_file(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<bool, Error> {
+        self.execute("delete from files where id=$1 and device_id=$2 and account_id=$3", &[&(file_id as i64), &device_id, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn get_devices(&self, account_id: &str) -> Result<Vec<Device>, Error> {
+        let rows = self.conn()?.query("select id, name, os, created, last_backup from devices where account_id=$1 order by id", &[&account_id])?;
+
+        Ok(rows.iter().map(|row| Device { id: row.get(0), name: row.get(1), os: row.get(2), created: row.get(3), last_backup: row.get(4) }).collect())
+    }
+
+    fn is_known_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        self.query_strings("select id from devices where account_id=$1 and id=$2", &[&account_id, &device_id])
+            .map(|devices| !devices.is_empty())
+    }
+
+    fn create_upload_session(&self, session: &UploadSession, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("insert into upload_sessions (id, account_id, device_id, original_name, size, mtime, hash, created, last_activity) values ($1, $2, $3, $4, $5, $6, $7, $8, $8)",
+                     &[&session.id, &session.account_id, &session.device_id, &session.original_name, &(session.size as i64), &session.mtime, &session.hash, now])
+            .map(|_| ())
+    }
+
+    fn find_upload_session(&self, account_id: &str, device_id: &str, session_id: &str) -> Result<Option<UploadSession>, Error> {
+        let rows = self.conn()?.query("select id, account_id, device_id, original_name, size, mtime, hash from upload_sessions where id=$1 and account_id=$2 and device_id=$3",
+                                      &[&session_id, &account_id, &device_id])?;
+
+        Ok(rows.iter().next().map(|row| {
+            UploadSession {
+                id: row.get(0),
+                account_id: row.get(1),
+                device_id: row.get(2),
+                original_name: row.get(3),
+                size: row.get::<_, i64>(4) as u64,
+                mtime: row.get(5),
+                hash: row.get(6)
+            }
+        }))
+    }
+
+    fn get_upload_chunks(&self, session_id: &str) -> Result<Vec<UploadChunk>, Error> {
+        let rows = self.conn()?.query("select idx, size, hash, storage_name from upload_sessions_chunks where session_id=$1 order by idx", &[&session_id])?;
+
+        Ok(rows.iter().map(|row| {
+            UploadChunk {
+                index: row.get::<_, i64>(0) as u32,
+                size: row.get::<_, i64>(1) as u64,
+                hash: row.get(2),
+                storage_name: row.get(3)
+            }
+        }).collect())
+    }
+
+    fn save_upload_chunk(&self, session_id: &str, chunk: &UploadChunk, max_size: u64, now: &NaiveDateTime) -> Result<SavedUploadChunk, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        // locks the session, so concurrent uploads of chunks of the same session are serialized
+        tx.execute("select id from upload_sessions where id=$1 for update", &[&session_id])?;
+
+        let chunks = tx.query("select idx, size, hash, storage_name from upload_sessions_chunks where session_id=$1", &[&session_id])?
+            .iter().map(|row| {
+                UploadChunk {
+                    index: row.get::<_, i64>(0) as u32,
+                    size: row.get::<_, i64>(1) as u64,
+                    hash: row.get(2),
+                    storage_name: row.get(3)
+                }
+            }).collect();
+
+        let saved = check_upload_chunk(chunks, chunk, max_size);
+
+        if saved == SavedUploadChunk::SizeExceeded {
+            return Ok(saved); // rolled back on drop
+        }
+
+        tx.execute("insert into upload_sessions_chunks (session_id, idx, size, hash, storage_name) values ($1, $2, $3, $4, $5) \
+                    on conflict (session_id, idx) do update set size=excluded.size, hash=excluded.hash, storage_name=excluded.storage_name",
+                   &[&session_id, &(chunk.index as i64), &(chunk.size as i64), &chunk.hash, &chunk.storage_name])?;
+
+        tx.execute("update upload_sessions set last_activity=$1 where id=$2", &[now, &session_id])?;
+
+        tx.commit()?;
+
+        Ok(saved)
+    }
+
+    fn remove_upload_session(&self, session_id: &str) -> Result<bool, Error> {
+        self.execute("delete from upload_sessions where id=$1", &[&session_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn find_upload_sessions_inactive_since(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String)>, Error> {
+        let rows = self.conn()?.query("select id, account_id from upload_sessions where last_activity < $1", &[threshold])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn get_account_ids(&self) -> Result<Vec<String>, Error> {
+        self.query_strings("select id from accounts", &[])
+    }
+
+    fn get_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error> {
+        let conn = self.conn()?;
+
+        let rows = match device_id {
+            Some(device_id) => conn.query("select keep_all_hours, keep_daily_days, keep_weekly_weeks from device_retention_policies where account_id=$1 and device_id=$2",
+                                          &[&account_id, &device_id]),
+            None => conn.query("select keep_all_hours, keep_daily_days, keep_weekly_weeks from account_retention_policies where account_id=$1",
+                               &[&account_id])
+        }?;
+
+        Ok(rows.iter().next().map(PostgresDatabase::to_retention_policy))
+    }
+
+    fn set_retention_policy(&self, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<(), Error> {
+        let keep_all_hours = policy.keep_all_hours as i64;
+        let keep_daily_days = policy.keep_daily_days as i64;
+        let keep_weekly_weeks = policy.keep_weekly_weeks as i64;
+
+        match device_id {
+            Some(device_id) => self.execute("insert into device_retention_policies (account_id, device_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values ($1, $2, $3, $4, $5) \
+                                             on conflict (account_id, device_id) do update set keep_all_hours=excluded.keep_all_hours, keep_daily_days=excluded.keep_daily_days, keep_weekly_weeks=excluded.keep_weekly_weeks",
+                                            &[&account_id, &device_id, &keep_all_hours, &keep_daily_days, &keep_weekly_weeks]),
+            None => self.execute("insert into account_retention_policies (account_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values ($1, $2, $3, $4) \
+                                  on conflict (account_id) do update set keep_all_hours=excluded.keep_all_hours, keep_daily_days=excluded.keep_daily_days, keep_weekly_weeks=excluded.keep_weekly_weeks",
+                                 &[&account_id, &keep_all_hours, &keep_daily_days, &keep_weekly_weeks])
+        }.map(|_| ())
+    }
+
+    fn remove_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<bool, Error> {
+        match device_id {
+            Some(device_id) => self.execute("delete from device_retention_policies where account_id=$1 and device_id=$2", &[&account_id, &device_id]),
+            None => self.execute("delete from account_retention_policies where account_id=$1", &[&account_id])
+        }.map(|affected| affected > 0)
+    }
+
+    fn find_session(&self, hashed_session_id: &str) -> Result<Option<(String, String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        let rows = self.conn()?.query("select device_id, account_id, pass, created, last_used from sessions where id=$1", &[&hashed_session_id])?;
+
+        Ok(rows.iter().next().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4))))
+    }
+
+    fn update_session_pass(&self, hashed_session_id: &str, encrypted_pass: &str) -> Result<(), Error> {
+        self.execute("update sessions set pass=$1 where id=$2", &[&encrypted_pass, &hashed_session_id])
+            .map(|_| ())
+    }
+
+    fn update_sessions_last_used(&self, activity: &[(String, NaiveDateTime)]) -> Result<(), Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        for (hashed_session_id, last_used) in activity {
+            tx.execute("update sessions set last_used=$1 where id=$2", &[last_used, hashed_session_id])?;
+        }
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_expired_sessions(&self, created_before: &NaiveDateTime, last_used_before: &NaiveDateTime) -> Result<u64, Error> {
+        self.execute("delete from sessions where created < $1 or last_used < $2", &[created_before, last_used_before])
+    }
+
+    fn insert_session(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("insert into sessions (id, account_id, device_id, pass, created, last_used) values ($1, $2, $3, $4, $5, $5)",
+                     &[&hashed_session_id, &account_id, &device_id, &encrypted_pass, now])
+            .map(|_| ())
+    }
+
+    fn replace_device_sessions(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let replaced: Vec<String> = tx.query("delete from sessions where account_id=$1 and device_id=$2 returning id", &[&account_id, &device_id])?
+            .iter().map(|row| row.get(0)).collect();
+
+        tx.execute("insert into sessions (id, account_id, device_id, pass, created, last_used) values ($1, $2, $3, $4, $5, $5)",
+                   &[&hashed_session_id, &account_id, &device_id, &encrypted_pass, now])?;
+
+        tx.commit()?;
+
+        Ok(replaced)
+    }
+
+    fn find_throttling(&self, id: &str) -> Result<Option<(u32, NaiveDateTime)>, Error> {
+        let rows = self.conn()?.query("select attempts, last_attempt from throttling where id=$1", &[&id])?;
+
+        Ok(rows.iter().next().map(|row| (row.get::<_, i64>(0) as u32, row.get(1))))
+    }
+
+    fn record_attempt(&self, id: &str, now: &NaiveDateTime, reset_before: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from throttling where id=$1 and last_attempt < $2", &[&id, reset_before])?;
+        tx.execute("insert into throttling (id, attempts, last_attempt) values ($1, 1, $2) on conflict (id) do update set attempts=throttling.attempts + 1, last_attempt=excluded.last_attempt", &[&id, now])?;
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_throttling(&self, id: &str) -> Result<(), Error> {
+        self.execute("delete from throttling where id=$1", &[&id])
+            .map(|_| ())
+    }
+
+    fn remove_stale_throttling(&self, last_attempt_before: &NaiveDateTime) -> Result<u64, Error> {
+        self.execute("delete from throttling where last_attempt < $1", &[last_attempt_before])
+    }
+
+    fn find_accounts(&self, username: &str) -> Result<Vec<(String, String)>, Error> {
+        let rows = self.conn()?.query("select id, password from accounts where username=$1", &[&username])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn insert_account(&self, account_id: &str, username: &str, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: Option<&WrappedSecret>) -> Result<bool, Error> {
+        self.execute("insert into accounts (id, username, password, repo_key_salt, repo_secret, recovery_key_salt, recovery_secret) values ($1, $2, $3, $4, $5, $6, $7)",
+                     &[&account_id, &username, &password_hash, &repo_secret.salt, &repo_secret.secret,
+                       &recovery_secret.map(|s| &s.salt), &recovery_secret.map(|s| &s.secret)])
+            .map(|affected| affected == 1)
+    }
+
+    fn update_account_password(&self, account_id: &str, password_hash: &str) -> Result<bool, Error> {
+        self.execute("update accounts set password=$1 where id=$2", &[&password_hash, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn find_repo_secret(&self, account_id: &str) -> Result<Option<WrappedSecret>, Error> {
+        let rows = self.conn()?.query("select repo_key_salt, repo_secret from accounts where id=$1 and repo_secret is not null", &[&account_id])?;
+
+        Ok(rows.iter().next().map(|row| WrappedSecret { salt: row.get(0), secret: row.get(1) }))
+    }
+
+    fn update_repo_secret(&self, account_id: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.execute("update accounts set repo_key_salt=$1, repo_secret=$2 where id=$3", &[&repo_secret.salt, &repo_secret.secret, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn find_recovery_secrets(&self, username: &str) -> Result<Vec<(String, WrappedSecret)>, Error> {
+        let rows = self.conn()?.query("select id, recovery_key_salt, recovery_secret from accounts where username=$1 and recovery_secret is not null", &[&username])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), WrappedSecret { salt: row.get(1), secret: row.get(2) })).collect())
+    }
+
+    fn find_password_hash(&self, account_id: &str) -> Result<Option<String>, Error> {
+        self.query_strings("select password from accounts where id=$1", &[&account_id])
+            .map(|hashes| hashes.into_iter().next())
+    }
+
+    fn update_account_credentials(&self, account_id: &str, password_hash: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.execute("update accounts set password=$1, repo_key_salt=$2, repo_secret=$3 where id=$4",
+                     &[&password_hash, &repo_secret.salt, &repo_secret.secret, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn update_recovered_credentials(&self, account_id: &str, used_recovery_secret: &WrappedSecret, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: &WrappedSecret) -> Result<bool, Error> {
+        self.execute("update accounts set password=$1, repo_key_salt=$2, repo_secret=$3, recovery_key_salt=$4, recovery_secret=$5 where id=$6 and recovery_secret=$7",
+                     &[&password_hash, &repo_secret.salt, &repo_secret.secret, &recovery_secret.salt, &recovery_secret.secret, &account_id, &used_recovery_secret.secret])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_account_sessions(&self, account_id: &str) -> Result<u64, Error> {
+        self.execute("delete from sessions where account_id=$1", &[&account_id])
+    }
+
+    fn list_sessions(&self, account_id: &str) -> Result<Vec<(String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        let rows = self.conn()?.query("select id, device_id, created, last_used from sessions where account_id=$1 order by created", &[&account_id])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))).collect())
+    }
+
+    fn remove_session(&self, hashed_session_id: &str) -> Result<bool, Error> {
+        self.execute("delete from sessions where id=$1", &[&hashed_session_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_account_session(&self, account_id: &str, hashed_session_id: &str) -> Result<bool, Error> {
+        self.execute("delete from sessions where id=$1 and account_id=$2", &[&hashed_session_id, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_device_sessions(&self, account_id: &str, device_id: &str) -> Result<u64, Error> {
+        self.execute("delete from sessions where account_id=$1 and device_id=$2", &[&account_id, &device_id])
+    }
+
+    fn find_totp(&self, account_id: &str) -> Result<Option<(String, bool, Option<u64>)>, Error> {
+        let rows = self.conn()?.query("select totp_secret, totp_enabled, totp_last_step from accounts where id=$1 and totp_secret is not null", &[&account_id])?;
+
+        Ok(rows.iter().next().map(|row| (row.get(0), row.get(1), row.get::<_, Option<i64>>(2).map(|step| step as u64))))
+    }
+
+    fn set_totp_secret(&self, account_id: &str, secret: &str) -> Result<bool, Error> {
+        self.execute("update accounts set totp_secret=$1, totp_last_step=null where id=$2 and not totp_enabled", &[&secret, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn enable_totp(&self, account_id: &str, step: u64, backup_code_hashes: &[String]) -> Result<bool, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let updated = tx.execute("update accounts set totp_enabled=true, totp_last_step=$1 where id=$2 and not totp_enabled and totp_secret is not null",
+                                 &[&(step as i64), &account_id])?;
+
+        if updated == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.execute("delete from backup_codes where account_id=$1", &[&account_id])?;
+
+        for code_hash in backup_code_hashes {
+            tx.execute("insert into backup_codes (account_id, code_hash) values ($1, $2)", &[&account_id, code_hash])?;
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn update_totp_last_step(&self, account_id: &str, step: u64) -> Result<bool, Error> {
+        self.execute("update accounts set totp_last_step=$1 where id=$2 and (totp_last_step is null or totp_last_step < $1)", &[&(step as i64), &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_backup_code(&self, account_id: &str, code_hash: &str) -> Result<bool, Error> {
+        self.execute("delete from backup_codes where account_id=$1 and code_hash=$2", &[&account_id, &code_hash])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_totp(&self, account_id: &str) -> Result<bool, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from backup_codes where account_id=$1", &[&account_id])?;
+        let updated = tx.execute("update accounts set totp_secret=null, totp_enabled=false, totp_last_step=null where id=$1", &[&account_id])?;
+
+        tx.commit()?;
+
+        Ok(updated > 0)
+    }
+
+    fn insert_api_token(&self, hashed_token: &str, account_id: &str, device_id: &str, name: &str, scope: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("insert into api_tokens (id, account_id, device_id, name, scope, pass, created) values ($1, $2, $3, $4, $5, $6, $7)",
+                     &[&hashed_token, &account_id, &device_id, &name, &scope, &encrypted_pass, now])
+            .map(|_| ())
+    }
+
+    fn find_api_token(&self, hashed_token: &str) -> Result<Option<(String, String, String, String)>, Error> {
+        let rows = self.conn()?.query("select device_id, account_id, scope, pass from api_tokens where id=$1", &[&hashed_token])?;
+
+        Ok(rows.iter().next().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3))))
+    }
+
+    fn list_api_tokens(&self, account_id: &str) -> Result<Vec<(String, String, String, String, NaiveDateTime)>, Error> {
+        let rows = self.conn()?.query("select id, device_id, name, scope, created from api_tokens where account_id=$1 order by created", &[&account_id])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4))).collect())
+    }
+
+    fn remove_api_token(&self, account_id: &str, hashed_token: &str) -> Result<bool, Error> {
+        self.execute("delete from api_tokens where id=$1 and account_id=$2", &[&hashed_token, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn remove_account_api_tokens(&self, account_id: &str) -> Result<u64, Error> {
+        self.execute("delete from api_tokens where account_id=$1", &[&account_id])
+    }
+
+    fn find_device_role(&self, account_id: &str, device_id: &str) -> Result<Option<String>, Error> {
+        let rows = self.conn()?.query("select role from device_roles where account_id=$1 and device_id=$2", &[&account_id, &device_id])?;
+
+        Ok(rows.iter().next().map(|row| row.get(0)))
+    }
+
+    fn set_device_role(&self, account_id: &str, device_id: &str, role: &str) -> Result<(), Error> {
+        self.execute("insert into device_roles (account_id, device_id, role) values ($1, $2, $3)
+                      on conflict (account_id, device_id) do update set role=excluded.role",
+                     &[&account_id, &device_id, &role])
+            .map(|_| ())
+    }
+
+    fn list_device_roles(&self, account_id: &str) -> Result<Vec<(String, String)>, Error> {
+        let rows = self.conn()?.query("select device_id, role from device_roles where account_id=$1 order by device_id", &[&account_id])?;
+
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn insert_device(&self, account_id: &str, device_id: &str, os: Option<&str>, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("insert into devices (account_id, id, os, created) values ($1, $2, $3, $4)
+                      on conflict (account_id, id) do update set os=coalesce(excluded.os, devices.os)",
+                     &[&account_id, &device_id, &os, now])
+            .map(|_| ())
+    }
+
+    fn update_device_name(&self, account_id: &str, device_id: &str, name: &str) -> Result<bool, Error> {
+        self.execute("update devices set name=$1 where account_id=$2 and id=$3", &[&name, &account_id, &device_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn update_device_last_backup(&self, account_id: &str, device_id: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("update devices set last_backup=$1 where account_id=$2 and id=$3", &[now, &account_id, &device_id])
+            .map(|_| ())
+    }
+
+    fn remove_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        for table in &["sessions", "api_tokens", "device_roles", "device_retention_policies"] {
+            tx.execute(&format!("delete from {} where account_id=$1 and device_id=$2", table), &[&account_id, &device_id])?;
+        }
+
+        let removed = tx.execute("delete from devices where account_id=$1 and id=$2", &[&account_id, &device_id])?;
+
+        tx.commit()?;
+
+        Ok(removed > 0)
+    }
+
+    fn schedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<bool, Error> {
+        self.execute("update accounts set deletion_scheduled=$1 where id=$2 and deletion_scheduled is null", &[purge_after, &account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn cancel_account_deletion(&self, account_id: &str) -> Result<bool, Error> {
+        self.execute("update accounts set deletion_scheduled=null where id=$1 and deletion_scheduled is not null", &[&account_id])
+            .map(|affected| affected > 0)
+    }
+
+    fn find_accounts_to_purge(&self, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        self.query_strings("select id from accounts where deletion_scheduled<=$1", &[now])
+    }
+
+    fn claim_account_purge(&self, account_id: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        self.execute("update accounts set deletion_scheduled=null where id=$1 and deletion_scheduled<=$2", &[&account_id, now])
+            .map(|affected| affected > 0)
+    }
+
+    fn reschedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("update accounts set deletion_scheduled=$1 where id=$2", &[purge_after, &account_id])
+            .map(|_| ())
+    }
+
+    fn insert_audit_record(&self, account_id: &str, event: &str, actor: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        self.execute("insert into audit_log (account_id, username, event, actor, created) select id, username, $1, $2, $3 from accounts where id=$4",
+                     &[&event, &actor, now, &account_id])
+            .map(|_| ())
+    }
+
+    fn remove_account(&self, account_id: &str, actor: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        let conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        // the record has to be inserted first, it copies the username from the account
+        tx.execute("insert into audit_log (account_id, username, event, actor, created) select id, username, 'account_deleted', $1, $2 from accounts where id=$3",
+                   &[&actor, now, &account_id])?;
+
+        if tx.execute("delete from accounts where id=$1", &[&account_id])? == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+}
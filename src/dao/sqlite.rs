@@ -0,0 +1,993 @@
+use std::sync::Mutex;
+
+use chrono::{NaiveDateTime, Utc};
+use failure::Error;
+use rusqlite::{Connection, OptionalExtension, Row};
+
+use crate::migrations::{Migration, SQLITE_MIGRATIONS};
+use crate::passwords::WrappedSecret;
+use crate::retention::RetentionPolicy;
+use crate::structs::*;
+
+use super::{Database, check_upload_chunk, to_files};
+
+/// Embedded database; all the queries are serialized through single connection.
+pub struct SqliteDatabase {
+    conn: Mutex<Connection>
+}
+
+impl SqliteDatabase {
+    pub fn new(path: &str) -> Result<SqliteDatabase, Error> {
+        let conn = Connection::open(path)?;
+
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+
+        Ok(SqliteDatabase {
+            conn: Mutex::new(conn)
+        })
+    }
+
+    fn to_file_row(row: &Row) -> rusqlite::Result<((u64, String, String), FileVersion)> {
+        Ok((
+            (row.get::<_, i64>(0)? as u64, row.get(1)?, row.get(2)?),
+            FileVersion {
+                version: row.get::<_, i64>(3)? as u64,
+                size: row.get::<_, i64>(4)? as u64,
+                hash: row.get(5)?,
+                created: row.get(6)?,
+                mtime: row.get(7)?,
+                storage_name: row.get(8)?
+            }
+        ))
+    }
+
+    fn query_files(&self, query: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<File>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(query)?;
+
+        let rows = stmt.query_map(params, SqliteDatabase::to_file_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(to_files(rows))
+    }
+
+    fn query_strings(&self, query: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<String>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(query)?;
+
+        let rows = stmt.query_map(params, |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(rows)
+    }
+}
+
+impl Database for SqliteDatabase {
+    fn migrations(&self) -> &'static [Migration] {
+        SQLITE_MIGRATIONS
+    }
+
+    fn get_schema_version(&self) -> Result<u32, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let exists: i64 = conn.query_row("select count(*) from sqlite_master where type='table' and name='schema_version'", rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+        if exists > 0 {
+            conn.query_row("select coalesce(max(version), 0) from schema_version", rusqlite::NO_PARAMS, |row| row.get(0))
+                .map_err(Error::from)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+
+        // SQLite supports transactional DDL, so the migration is applied completely or not at all
+        let tx = conn.transaction()?;
+
+        tx.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (
+                            version INTEGER NOT NULL PRIMARY KEY,
+                            description varchar(250) NOT NULL,
+                            applied datetime NOT NULL
+                          )")?;
+
+        tx.execute_batch(migration.script)?;
+
+        tx.execute("insert into schema_version (version, description, applied) values (?1, ?2, ?3)",
+                   rusqlite::params![migration.version, migration.description, Utc::now().naive_utc()])?;
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn insert_file(&self, uploaded_file: &UploadedFile) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert or ignore into files (account_id, device_id, original_name, identity_hash) values (?1, ?2, ?3, ?4)",
+                     rusqlite::params![uploaded_file.account_id, uploaded_file.device_id, uploaded_file.original_name, uploaded_file.identity_hash])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn insert_file_version(&self, file_id: u64, file_version: &FileVersion) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into files_versions (file_id, created, mtime, size, hash, storage_name) values (?1, ?2, ?3, ?4, ?5, ?6)",
+                     rusqlite::params![file_id as i64, file_version.created, file_version.mtime, file_version.size as i64, file_version.hash, file_version.storage_name])?;
+
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    fn find_file(&self, identity_hash: &str) -> Result<Option<File>, Error> {
+        let file_with_versions = self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where identity_hash=?1",
+                                                  rusqlite::params![identity_hash])?
+            .into_iter().next();
+
+        match file_with_versions {
+            Some(f) => Ok(Some(f)),
+            None => {
+                // try fallback and find file even without any versions yet
+                let conn = self.conn.lock().unwrap();
+
+                conn.query_row("select id, device_id, original_name from files where identity_hash=?1", rusqlite::params![identity_hash], |row| {
+                    Ok(File {
+                        id: row.get::<_, i64>(0)? as u64,
+                        device_id: row.get(1)?,
+                        original_name: row.get(2)?,
+                        versions: Vec::new()
+                    })
+                }).optional().map_err(Error::from)
+            }
+        }
+    }
+
+    fn get_hash_size_and_storage_name(&self, account_id: &str, version_id: u64) -> Result<Option<(String, u64, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select hash, size, storage_name from files_versions join files on files_versions.file_id=files.id where files_versions.id=?1 and files.account_id=?2",
+                       rusqlite::params![version_id as i64, account_id], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get(2)?))
+        }).optional().map_err(Error::from)
+    }
+
+    fn find_storage_name_by_hash(&self, account_id: &str, hash: &str, size: u64) -> Result<Option<String>, Error> {
+        self.query_strings("select storage_name from files_versions join files on files_versions.file_id=files.id where files.account_id=?1 and hash=?2 and size=?3 limit 1",
+                           rusqlite::params![account_id, hash, size as i64])
+            .map(|names| names.into_iter().next())
+    }
+
+    fn touch_file_version(&self, account_id: &str, version_id: u64, mtime: &NaiveDateTime) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update files_versions set mtime=?1 where id=?2 and file_id in (select id from files where account_id=?3)",
+                     rusqlite::params![mtime, version_id as i64, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_versions_older_than(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String, u64)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select files.account_id, files.device_id, files_versions.id from files_versions join files on files_versions.file_id=files.id \
+                                     where files_versions.created < ?1 \
+                                     and files_versions.id < (select max(newest.id) from files_versions newest where newest.file_id=files_versions.file_id)")?;
+
+        let rows = stmt.query_map(rusqlite::params![threshold], |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn get_storage_names(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<Vec<String>, Error> {
+        self.query_strings("select storage_name from files_versions join files on files_versions.file_id=files.id where files.id=?1 and files.device_id=?2 and files.account_id=?3",
+                           rusqlite::params![file_id as i64, device_id, account_id])
+    }
+
+    fn list_files(&self, account_id: &str, device_id: &str) -> Result<Vec<File>, Error> {
+        self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where account_id=?1 and device_id=?2",
+                         rusqlite::params![account_id, device_id])
+    }
+
+    fn list_account_files(&self, account_id: &str) -> Result<Vec<File>, Error> {
+        self.query_files("select files.id, device_id, original_name, files_versions.id, size, hash, created, mtime, storage_name from files join files_versions on files_versions.file_id = files.id where account_id=?1",
+                         rusqlite::params![account_id])
+    }
+
+    fn remove_file_version(&self, account_id: &str, version_id: u64) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from files_versions where id=?1 and file_id in (select id from files where account_id=?2)", rusqlite::params![version_id as i64, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_file_versions(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from files_versions where file_id in (select id from files where id=?1 and device_id=?2 and account_id=?3)",
+                     rusqlite::params![file_id as i64, device_id, account_id])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn remove_file(&self, account_id: &str, device_id: &str, file_id: u64) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from files where id=?1 and device_id=?2 and account_id=?3", rusqlite::params![file_id as i64, device_id, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn get_devices(&self, account_id: &str) -> Result<Vec<Device>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, name, os, created, last_backup from devices where account_id=?1 order by id")?;
+
+        let rows = stmt.query_map(rusqlite::params![account_id], |row| {
+            Ok(Device { id: row.get(0)?, name: row.get(1)?, os: row.get(2)?, created: row.get(3)?, last_backup: row.get(4)? })
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn is_known_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        self.query_strings("select id from devices where account_id=?1 and id=?2", rusqlite::params![account_id, device_id])
+            .map(|devices| !devices.is_empty())
+    }
+
+    fn create_upload_session(&self, session: &UploadSession, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into upload_sessions (id, account_id, device_id, original_name, size, mtime, hash, created, last_activity) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)",
+                     rusqlite::params![session.id, session.account_id, session.device_id, session.original_name, session.size as i64, session.mtime, session.hash, now])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn find_upload_session(&self, account_id: &str, device_id: &str, session_id: &str) -> Result<Option<UploadSession>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select id, account_id, device_id, original_name, size, mtime, hash from upload_sessions where id=?1 and account_id=?2 and device_id=?3",
+                       rusqlite::params![session_id, account_id, device_id], |row| {
+                Ok(UploadSession {
+                    id: row.get(0)?,
+                    account_id: row.get(1)?,
+                    device_id: row.get(2)?,
+                    original_name: row.get(3)?,
+                    size: row.get::<_, i64>(4)? as u64,
+                    mtime: row.get(5)?,
+                    hash: row.get(6)?
+                })
+            }).optional().map_err(Error::from)
+    }
+
+    fn get_upload_chunks(&self, session_id: &str) -> Result<Vec<UploadChunk>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select idx, size, hash, storage_name from upload_sessions_chunks where session_id=?1 order by idx")?;
+
+        let chunks = stmt.query_map(rusqlite::params![session_id], |row| {
+            Ok(UploadChunk {
+                index: row.get(0)?,
+                size: row.get::<_, i64>(1)? as u64,
+                hash: row.get(2)?,
+                storage_name: row.get(3)?
+            })
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(chunks)
+    }
+
+    fn save_upload_chunk(&self, session_id: &str, chunk: &UploadChunk, max_size: u64, now: &NaiveDateTime) -> Result<SavedUploadChunk, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let chunks = {
+            let mut stmt = tx.prepare("select idx, size, hash, storage_name from upload_sessions_chunks where session_id=?1")?;
+
+            let rows = stmt.query_map(rusqlite::params![session_id], |row| {
+                Ok(UploadChunk {
+                    index: row.get(0)?,
+                    size: row.get::<_, i64>(1)? as u64,
+                    hash: row.get(2)?,
+                    storage_name: row.get(3)?
+                })
+            })?.collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+
+        let saved = check_upload_chunk(chunks, chunk, max_size);
+
+        if saved == SavedUploadChunk::SizeExceeded {
+            return Ok(saved); // rolled back on drop
+        }
+
+        tx.execute("insert into upload_sessions_chunks (session_id, idx, size, hash, storage_name) values (?1, ?2, ?3, ?4, ?5) \
+                    on conflict (session_id, idx) do update set size=excluded.size, hash=excluded.hash, storage_name=excluded.storage_name",
+                   rusqlite::params![session_id, chunk.index, chunk.size as i64, chunk.hash, chunk.storage_name])?;
+
+        tx.execute("update upload_sessions set last_activity=?1 where id=?2", rusqlite::params![now, session_id])?;
+
+        tx.commit()?;
+
+        Ok(saved)
+    }
+
+    fn remove_upload_session(&self, session_id: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from upload_sessions where id=?1", rusqlite::params![session_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_upload_sessions_inactive_since(&self, threshold: &NaiveDateTime) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, account_id from upload_sessions where last_activity < ?1")?;
+
+        let rows = stmt.query_map(rusqlite::params![threshold], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn get_account_ids(&self) -> Result<Vec<String>, Error> {
+        self.query_strings("select id from accounts", rusqlite::NO_PARAMS)
+    }
+
+    fn get_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        let to_policy = |row: &Row| {
+            Ok(RetentionPolicy {
+                keep_all_hours: row.get(0)?,
+                keep_daily_days: row.get(1)?,
+                keep_weekly_weeks: row.get(2)?
+            })
+        };
+
+        match device_id {
+            Some(device_id) => conn.query_row("select keep_all_hours, keep_daily_days, keep_weekly_weeks from device_retention_policies where account_id=?1 and device_id=?2",
+                                              rusqlite::params![account_id, device_id], to_policy),
+            None => conn.query_row("select keep_all_hours, keep_daily_days, keep_weekly_weeks from account_retention_policies where account_id=?1",
+                                   rusqlite::params![account_id], to_policy)
+        }.optional().map_err(Error::from)
+    }
+
+    fn set_retention_policy(&self, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        match device_id {
+            Some(device_id) => conn.execute("insert into device_retention_policies (account_id, device_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values (?1, ?2, ?3, ?4, ?5) \
+                                             on conflict (account_id, device_id) do update set keep_all_hours=excluded.keep_all_hours, keep_daily_days=excluded.keep_daily_days, keep_weekly_weeks=excluded.keep_weekly_weeks",
+                                            rusqlite::params![account_id, device_id, policy.keep_all_hours, policy.keep_daily_days, policy.keep_weekly_weeks]),
+            None => conn.execute("insert into account_retention_policies (account_id, keep_all_hours, keep_daily_days, keep_weekly_weeks) values (?1, ?2, ?3, ?4) \
+                                  on conflict (account_id) do update set keep_all_hours=excluded.keep_all_hours, keep_daily_days=excluded.keep_daily_days, keep_weekly_weeks=excluded.keep_weekly_weeks",
+                                 rusqlite::params![account_id, policy.keep_all_hours, policy.keep_daily_days, policy.keep_weekly_weeks])
+        }.map(|_| ()).map_err(Error::from)
+    }
+
+    fn remove_retention_policy(&self, account_id: &str, device_id: Option<&str>) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        match device_id {
+            Some(device_id) => conn.execute("delete from device_retention_policies where account_id=?1 and device_id=?2", rusqlite::params![account_id, device_id]),
+            None => conn.execute("delete from account_retention_policies where account_id=?1", rusqlite::params![account_id])
+        }.map(|affected| affected > 0).map_err(Error::from)
+    }
+
+    fn find_session(&self, hashed_session_id: &str) -> Result<Option<(String, String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select device_id, account_id, pass, created, last_used from sessions where id=?1", rusqlite::params![hashed_session_id],
+                       |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn update_session_pass(&self, hashed_session_id: &str, encrypted_pass: &str) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update sessions set pass=?1 where id=?2", rusqlite::params![encrypted_pass, hashed_session_id])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn update_sessions_last_used(&self, activity: &[(String, NaiveDateTime)]) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for (hashed_session_id, last_used) in activity {
+            tx.execute("update sessions set last_used=?1 where id=?2", rusqlite::params![last_used, hashed_session_id])?;
+        }
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_expired_sessions(&self, created_before: &NaiveDateTime, last_used_before: &NaiveDateTime) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from sessions where created < ?1 or last_used < ?2", rusqlite::params![created_before, last_used_before])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn insert_session(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into sessions (id, account_id, device_id, pass, created, last_used) values (?1, ?2, ?3, ?4, ?5, ?5)",
+                     rusqlite::params![hashed_session_id, account_id, device_id, encrypted_pass, now])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn replace_device_sessions(&self, hashed_session_id: &str, account_id: &str, device_id: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let replaced = {
+            let mut stmt = tx.prepare("select id from sessions where account_id=?1 and device_id=?2")?;
+
+            let rows = stmt.query_map(rusqlite::params![account_id, device_id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            rows
+        };
+
+        tx.execute("delete from sessions where account_id=?1 and device_id=?2", rusqlite::params![account_id, device_id])?;
+
+        tx.execute("insert into sessions (id, account_id, device_id, pass, created, last_used) values (?1, ?2, ?3, ?4, ?5, ?5)",
+                   rusqlite::params![hashed_session_id, account_id, device_id, encrypted_pass, now])?;
+
+        tx.commit()?;
+
+        Ok(replaced)
+    }
+
+    fn find_throttling(&self, id: &str) -> Result<Option<(u32, NaiveDateTime)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select attempts, last_attempt from throttling where id=?1", rusqlite::params![id],
+                       |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn record_attempt(&self, id: &str, now: &NaiveDateTime, reset_before: &NaiveDateTime) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from throttling where id=?1 and last_attempt < ?2", rusqlite::params![id, reset_before])?;
+        tx.execute("insert or ignore into throttling (id, attempts, last_attempt) values (?1, 0, ?2)", rusqlite::params![id, now])?;
+        tx.execute("update throttling set attempts=attempts + 1, last_attempt=?2 where id=?1", rusqlite::params![id, now])?;
+
+        tx.commit().map_err(Error::from)
+    }
+
+    fn remove_throttling(&self, id: &str) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from throttling where id=?1", rusqlite::params![id])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_stale_throttling(&self, last_attempt_before: &NaiveDateTime) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from throttling where last_attempt < ?1", rusqlite::params![last_attempt_before])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn find_accounts(&self, username: &str) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, password from accounts where username=?1")?;
+
+        let rows = stmt.query_map(rusqlite::params![username], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn insert_account(&self, account_id: &str, username: &str, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: Option<&WrappedSecret>) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into accounts (id, username, password, repo_key_salt, repo_secret, recovery_key_salt, recovery_secret) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                     rusqlite::params![account_id, username, password_hash, repo_secret.salt, repo_secret.secret,
+                                       recovery_secret.map(|s| &s.salt), recovery_secret.map(|s| &s.secret)])
+            .map(|affected| affected == 1)
+            .map_err(Error::from)
+    }
+
+    fn update_account_password(&self, account_id: &str, password_hash: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set password=?1 where id=?2", rusqlite::params![password_hash, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_repo_secret(&self, account_id: &str) -> Result<Option<WrappedSecret>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select repo_key_salt, repo_secret from accounts where id=?1 and repo_secret is not null", rusqlite::params![account_id],
+                       |row| Ok(WrappedSecret { salt: row.get(0)?, secret: row.get(1)? }))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn update_repo_secret(&self, account_id: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set repo_key_salt=?1, repo_secret=?2 where id=?3", rusqlite::params![repo_secret.salt, repo_secret.secret, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_recovery_secrets(&self, username: &str) -> Result<Vec<(String, WrappedSecret)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, recovery_key_salt, recovery_secret from accounts where username=?1 and recovery_secret is not null")?;
+
+        let rows = stmt.query_map(rusqlite::params![username], |row| Ok((row.get(0)?, WrappedSecret { salt: row.get(1)?, secret: row.get(2)? })))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn find_password_hash(&self, account_id: &str) -> Result<Option<String>, Error> {
+        self.query_strings("select password from accounts where id=?1", rusqlite::params![account_id])
+            .map(|hashes| hashes.into_iter().next())
+    }
+
+    fn update_account_credentials(&self, account_id: &str, password_hash: &str, repo_secret: &WrappedSecret) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set password=?1, repo_key_salt=?2, repo_secret=?3 where id=?4",
+                     rusqlite::params![password_hash, repo_secret.salt, repo_secret.secret, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn update_recovered_credentials(&self, account_id: &str, used_recovery_secret: &WrappedSecret, password_hash: &str, repo_secret: &WrappedSecret, recovery_secret: &WrappedSecret) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set password=?1, repo_key_salt=?2, repo_secret=?3, recovery_key_salt=?4, recovery_secret=?5 where id=?6 and recovery_secret=?7",
+                     rusqlite::params![password_hash, repo_secret.salt, repo_secret.secret, recovery_secret.salt, recovery_secret.secret, account_id, used_recovery_secret.secret])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_sessions(&self, account_id: &str) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from sessions where account_id=?1", rusqlite::params![account_id])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn list_sessions(&self, account_id: &str) -> Result<Vec<(String, String, NaiveDateTime, NaiveDateTime)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, device_id, created, last_used from sessions where account_id=?1 order by created")?;
+
+        let rows = stmt.query_map(rusqlite::params![account_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn remove_session(&self, hashed_session_id: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from sessions where id=?1", rusqlite::params![hashed_session_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_session(&self, account_id: &str, hashed_session_id: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from sessions where id=?1 and account_id=?2", rusqlite::params![hashed_session_id, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_device_sessions(&self, account_id: &str, device_id: &str) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from sessions where account_id=?1 and device_id=?2", rusqlite::params![account_id, device_id])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn find_totp(&self, account_id: &str) -> Result<Option<(String, bool, Option<u64>)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select totp_secret, totp_enabled, totp_last_step from accounts where id=?1 and totp_secret is not null", rusqlite::params![account_id],
+                       |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, Option<i64>>(2)?.map(|step| step as u64))))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn set_totp_secret(&self, account_id: &str, secret: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set totp_secret=?1, totp_last_step=null where id=?2 and totp_enabled=0", rusqlite::params![secret, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn enable_totp(&self, account_id: &str, step: u64, backup_code_hashes: &[String]) -> Result<bool, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let updated = tx.execute("update accounts set totp_enabled=1, totp_last_step=?1 where id=?2 and totp_enabled=0 and totp_secret is not null",
+                                 rusqlite::params![step as i64, account_id])?;
+
+        if updated == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.execute("delete from backup_codes where account_id=?1", rusqlite::params![account_id])?;
+
+        for code_hash in backup_code_hashes {
+            tx.execute("insert into backup_codes (account_id, code_hash) values (?1, ?2)", rusqlite::params![account_id, code_hash])?;
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn update_totp_last_step(&self, account_id: &str, step: u64) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set totp_last_step=?1 where id=?2 and (totp_last_step is null or totp_last_step < ?1)", rusqlite::params![step as i64, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_backup_code(&self, account_id: &str, code_hash: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from backup_codes where account_id=?1 and code_hash=?2", rusqlite::params![account_id, code_hash])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_totp(&self, account_id: &str) -> Result<bool, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute("delete from backup_codes where account_id=?1", rusqlite::params![account_id])?;
+        let updated = tx.execute("update accounts set totp_secret=null, totp_enabled=0, totp_last_step=null where id=?1", rusqlite::params![account_id])?;
+
+        tx.commit()?;
+
+        Ok(updated > 0)
+    }
+
+    fn insert_api_token(&self, hashed_token: &str, account_id: &str, device_id: &str, name: &str, scope: &str, encrypted_pass: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into api_tokens (id, account_id, device_id, name, scope, pass, created) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                     rusqlite::params![hashed_token, account_id, device_id, name, scope, encrypted_pass, now])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn find_api_token(&self, hashed_token: &str) -> Result<Option<(String, String, String, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select device_id, account_id, scope, pass from api_tokens where id=?1", rusqlite::params![hashed_token],
+                       |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn list_api_tokens(&self, account_id: &str) -> Result<Vec<(String, String, String, String, NaiveDateTime)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select id, device_id, name, scope, created from api_tokens where account_id=?1 order by created")?;
+
+        let rows = stmt.query_map(rusqlite::params![account_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn remove_api_token(&self, account_id: &str, hashed_token: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from api_tokens where id=?1 and account_id=?2", rusqlite::params![hashed_token, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn remove_account_api_tokens(&self, account_id: &str) -> Result<u64, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("delete from api_tokens where account_id=?1", rusqlite::params![account_id])
+            .map(|affected| affected as u64)
+            .map_err(Error::from)
+    }
+
+    fn find_device_role(&self, account_id: &str, device_id: &str) -> Result<Option<String>, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row("select role from device_roles where account_id=?1 and device_id=?2", rusqlite::params![account_id, device_id],
+                       |row| row.get(0))
+            .optional()
+            .map_err(Error::from)
+    }
+
+    fn set_device_role(&self, account_id: &str, device_id: &str, role: &str) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into device_roles (account_id, device_id, role) values (?1, ?2, ?3)
+                      on conflict (account_id, device_id) do update set role=excluded.role",
+                     rusqlite::params![account_id, device_id, role])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn list_device_roles(&self, account_id: &str) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select device_id, role from device_roles where account_id=?1 order by device_id")?;
+
+        let rows = stmt.query_map(rusqlite::params![account_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(rows)
+    }
+
+    fn insert_device(&self, account_id: &str, device_id: &str, os: Option<&str>, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into devices (account_id, id, os, created) values (?1, ?2, ?3, ?4)
+                      on conflict (account_id, id) do update set os=coalesce(excluded.os, devices.os)",
+                     rusqlite::params![account_id, device_id, os, now])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn update_device_name(&self, account_id: &str, device_id: &str, name: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update devices set name=?1 where account_id=?2 and id=?3", rusqlite::params![name, account_id, device_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn update_device_last_backup(&self, account_id: &str, device_id: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update devices set last_backup=?1 where account_id=?2 and id=?3", rusqlite::params![now, account_id, device_id])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_device(&self, account_id: &str, device_id: &str) -> Result<bool, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for table in &["sessions", "api_tokens", "device_roles", "device_retention_policies"] {
+            tx.execute(&format!("delete from {} where account_id=?1 and device_id=?2", table), rusqlite::params![account_id, device_id])?;
+        }
+
+        let removed = tx.execute("delete from devices where account_id=?1 and id=?2", rusqlite::params![account_id, device_id])?;
+
+        tx.commit()?;
+
+        Ok(removed > 0)
+    }
+
+    fn schedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set deletion_scheduled=?1 where id=?2 and deletion_scheduled is null", rusqlite::params![purge_after, account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn cancel_account_deletion(&self, account_id: &str) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set deletion_scheduled=null where id=?1 and deletion_scheduled is not null", rusqlite::params![account_id])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn find_accounts_to_purge(&self, now: &NaiveDateTime) -> Result<Vec<String>, Error> {
+        self.query_strings("select id from accounts where deletion_scheduled<=?1", rusqlite::params![now])
+    }
+
+    fn claim_account_purge(&self, account_id: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set deletion_scheduled=null where id=?1 and deletion_scheduled<=?2", rusqlite::params![account_id, now])
+            .map(|affected| affected > 0)
+            .map_err(Error::from)
+    }
+
+    fn reschedule_account_deletion(&self, account_id: &str, purge_after: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("update accounts set deletion_scheduled=?1 where id=?2", rusqlite::params![purge_after, account_id])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn insert_audit_record(&self, account_id: &str, event: &str, actor: &str, now: &NaiveDateTime) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("insert into audit_log (account_id, username, event, actor, created) select id, username, ?1, ?2, ?3 from accounts where id=?4",
+                     rusqlite::params![event, actor, now, account_id])
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn remove_account(&self, account_id: &str, actor: &str, now: &NaiveDateTime) -> Result<bool, Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        // the record has to be inserted first, it copies the username from the account
+        tx.execute("insert into audit_log (account_id, username, event, actor, created) select id, username, 'account_deleted', ?1, ?2 from accounts where id=?3",
+                   rusqlite::params![actor, now, account_id])?;
+
+        if tx.execute("delete from accounts where id=?1", rusqlite::params![account_id])? == 0 {
+            return Ok(false); // rolled back on drop
+        }
+
+        tx.commit()?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDateTime, Utc};
+
+    use crate::dao::Database;
+    use crate::structs::*;
+
+    use super::SqliteDatabase;
+
+    fn database() -> SqliteDatabase {
+        let db = SqliteDatabase::new(":memory:").unwrap();
+
+        for migration in db.migrations() {
+            db.apply_migration(migration).unwrap();
+        }
+
+        db.conn.lock().unwrap()
+            .execute("insert into accounts (id, username, password) values ('account', 'user', '')", rusqlite::NO_PARAMS)
+            .unwrap();
+
+        db
+    }
+
+    /// Inserts the file with versions created at given times, returns IDs of the versions.
+    fn insert_file(db: &SqliteDatabase, name: &str, created: &[NaiveDateTime]) -> Vec<u64> {
+        let uploaded_file = crate::to_uploaded_file("account", "device", name, 1, 0);
+        db.insert_file(&uploaded_file).unwrap();
+        let file = db.find_file(&uploaded_file.identity_hash).unwrap().unwrap();
+
+        created.iter().enumerate().map(|(i, created)| {
+            let version = FileVersion {
+                version: 0,
+                size: 1,
+                hash: "hash".to_string(),
+                created: *created,
+                mtime: *created,
+                storage_name: format!("{}-{}", name, i)
+            };
+
+            db.insert_file_version(file.id, &version).unwrap()
+        }).collect()
+    }
+
+    fn chunk(index: u32, size: u64) -> UploadChunk {
+        UploadChunk {
+            index,
+            size,
+            hash: "hash".to_string(),
+            storage_name: format!("chunk-{}-{}", index, size)
+        }
+    }
+
+    #[test]
+    fn replaces_chunks_and_rejects_chunks_exceeding_size() {
+        let db = database();
+        let now = Utc::now().naive_utc();
+
+        db.create_upload_session(&UploadSession {
+            id: "session".to_string(),
+            account_id: "account".to_string(),
+            device_id: "device".to_string(),
+            original_name: "file".to_string(),
+            size: 100,
+            mtime: now,
+            hash: "hash".to_string()
+        }, &now).unwrap();
+
+        assert_eq!(SavedUploadChunk::Saved(None), db.save_upload_chunk("session", &chunk(0, 60), 100, &now).unwrap());
+        assert_eq!(SavedUploadChunk::SizeExceeded, db.save_upload_chunk("session", &chunk(1, 41), 100, &now).unwrap());
+        assert_eq!(SavedUploadChunk::Saved(None), db.save_upload_chunk("session", &chunk(1, 40), 100, &now).unwrap());
+        // the replaced chunk doesn't count into the size
+        assert_eq!(SavedUploadChunk::Saved(Some("chunk-0-60".to_string())), db.save_upload_chunk("session", &chunk(0, 50), 100, &now).unwrap());
+        assert_eq!(SavedUploadChunk::SizeExceeded, db.save_upload_chunk("session", &chunk(0, 61), 100, &now).unwrap());
+
+        let chunks: Vec<(u32, u64)> = db.get_upload_chunks("session").unwrap().into_iter().map(|c| (c.index, c.size)).collect();
+
+        assert_eq!(vec![(0, 50), (1, 40)], chunks);
+    }
+
+    #[test]
+    fn finds_versions_older_than_threshold() {
+        let db = database();
+        let now = Utc::now().naive_utc();
+
+        let recent = insert_file(&db, "recent", &[now - Duration::days(10), now - Duration::days(8), now - Duration::days(1)]);
+        let old = insert_file(&db, "old", &[now - Duration::days(10), now - Duration::days(9)]);
+        insert_file(&db, "new", &[now - Duration::days(1)]);
+
+        let mut found: Vec<u64> = db.find_versions_older_than(&(now - Duration::days(5))).unwrap()
+            .into_iter()
+            .map(|(account_id, device_id, version_id)| {
+                assert_eq!("account", account_id);
+                assert_eq!("device", device_id);
+                version_id
+            })
+            .collect();
+        found.sort();
+
+        // the newest version of each file is kept, even when it's older than the threshold
+        assert_eq!(vec![recent[0], recent[1], old[0]], found);
+    }
+
+    #[test]
+    fn version_at_threshold_is_kept() {
+        let db = database();
+        let threshold = Utc::now().naive_utc() - Duration::days(5);
+
+        let versions = insert_file(&db, "file", &[threshold - Duration::seconds(1), threshold, threshold + Duration::days(1)]);
+
+        let found: Vec<u64> = db.find_versions_older_than(&threshold).unwrap().into_iter().map(|(_, _, version_id)| version_id).collect();
+
+        assert_eq!(vec![versions[0]], found);
+    }
+
+    #[test]
+    fn claims_due_account_purge_once() {
+        let db = database();
+        let now = Utc::now().naive_utc();
+
+        assert!(!db.claim_account_purge("account", &now).unwrap(), "Not scheduled account was claimed");
+
+        assert!(db.schedule_account_deletion("account", &(now + Duration::hours(1))).unwrap());
+        assert!(!db.claim_account_purge("account", &now).unwrap(), "Account was claimed before its grace period was over");
+
+        db.reschedule_account_deletion("account", &now).unwrap();
+        assert_eq!(vec!["account".to_string()], db.find_accounts_to_purge(&now).unwrap());
+        assert!(db.claim_account_purge("account", &now).unwrap());
+
+        // neither another purge nor the cancellation may proceed
+        assert!(!db.claim_account_purge("account", &now).unwrap(), "Account was claimed twice");
+        assert!(!db.cancel_account_deletion("account").unwrap(), "Claimed purge was cancelled");
+        assert!(db.find_accounts_to_purge(&now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn records_only_removed_accounts() {
+        let db = database();
+        let now = Utc::now().naive_utc();
+
+        assert!(db.remove_account("account", "admin-cli", &now).unwrap());
+        assert!(!db.remove_account("account", "admin-cli", &now).unwrap(), "Account was removed twice");
+
+        let conn = db.conn.lock().unwrap();
+        let mut stmt = conn.prepare("select username, event, actor from audit_log where account_id='account'").unwrap();
+        let records: Vec<(String, String, String)> = stmt.query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(vec![("user".to_string(), "account_deleted".to_string(), "admin-cli".to_string())], records);
+    }
+}
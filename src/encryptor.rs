@@ -1,22 +1,103 @@
 use crypto::{symmetriccipher, buffer, aes, blockmodes};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes_gcm::AesGcm;
 use crypto::buffer::{ReadBuffer, WriteBuffer, BufferResult};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use failure::Error;
+use crate::failures::CustomError;
+use hex;
+use rand::Rng;
+
+/// Prefix of values encrypted by AES-GCM. Values without it are hex encoded AES-CBC ciphertexts written by older versions.
+const AEAD_PREFIX: &str = "v2:";
+const HKDF_INFO: &[u8] = b"rbackup session pass";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
 
 pub struct Encryptor {
-    iv: [u8; 16]
+    secret: Vec<u8>,
+    legacy_iv: [u8; 16]
+}
+
+/// Result of the decryption; `legacy` means the value was encrypted by the old scheme and should be re-encrypted.
+pub struct Decrypted {
+    pub data: Vec<u8>,
+    pub legacy: bool
 }
 
 impl Encryptor {
-    pub fn new(iv: String) -> Encryptor {
+    pub fn new(secret: String) -> Encryptor {
         Encryptor {
-            iv: *array_ref!(iv.as_bytes(), 0, 16)
+            legacy_iv: *array_ref!(secret.as_bytes(), 0, 16),
+            secret: secret.into_bytes()
+        }
+    }
+
+    /// Encrypts the data by AES-256-GCM with a key derived from the `key` and a random nonce. The nonce is stored together with the
+    /// ciphertext and the tag.
+    pub fn encrypt(&self, data: &[u8], key: &[u8]) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let key = self.derive_key(key);
+
+        let mut encrypted = vec![0u8; data.len()];
+        let mut tag = [0u8; TAG_LEN];
+
+        AesGcm::new(aes::KeySize::KeySize256, &key, &nonce, &[]).encrypt(data, &mut encrypted, &mut tag);
+
+        let mut result = nonce.to_vec();
+        result.extend_from_slice(&encrypted);
+        result.extend_from_slice(&tag);
+
+        format!("{}{}", AEAD_PREFIX, hex::encode(&result))
+    }
+
+    pub fn decrypt(&self, encrypted: &str, key: &[u8]) -> Result<Decrypted, Error> {
+        if encrypted.starts_with(AEAD_PREFIX) {
+            let encrypted = hex::decode(&encrypted[AEAD_PREFIX.len()..])?;
+
+            if encrypted.len() < NONCE_LEN + TAG_LEN {
+                return Err(Error::from(CustomError::new("Encrypted value is too short")));
+            }
+
+            let (nonce, rest) = encrypted.split_at(NONCE_LEN);
+            let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+            let key = self.derive_key(key);
+
+            let mut decrypted = vec![0u8; ciphertext.len()];
+
+            if AesGcm::new(aes::KeySize::KeySize256, &key, nonce, &[]).decrypt(ciphertext, &mut decrypted, tag) {
+                Ok(Decrypted { data: decrypted, legacy: false })
+            } else {
+                Err(Error::from(CustomError::new("Could not decrypt value, it was modified or the key is wrong")))
+            }
+        } else {
+            let encrypted = hex::decode(encrypted)?;
+
+            self.decrypt_legacy(&encrypted, key)
+                .map(|data| Decrypted { data, legacy: true })
+                .map_err(|_| Error::from(CustomError::new("Could not decrypt legacy value")))
         }
     }
 
-    pub fn encrypt(&self, data: &[u8], key: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
+    /// Derives the AES key by HKDF-SHA256 from the `key` (session ID), salted by the server secret.
+    fn derive_key(&self, key: &[u8]) -> [u8; 32] {
+        let mut prk = [0u8; 32];
+        hkdf_extract(Sha256::new(), &self.secret, key, &mut prk);
+
+        let mut okm = [0u8; 32];
+        hkdf_expand(Sha256::new(), &prk, HKDF_INFO, &mut okm);
+
+        okm
+    }
+
+    /// Encrypts the data the way older versions did, so the migration of their values can be tested.
+    #[cfg(test)]
+    pub(crate) fn encrypt_legacy(&self, data: &[u8], key: &[u8]) -> String {
         let mut encryptor = aes::cbc_encryptor(
             aes::KeySize::KeySize256,
             key,
-            &self.iv,
+            &self.legacy_iv,
             blockmodes::PkcsPadding);
 
         let mut final_result = Vec::<u8>::new();
@@ -25,24 +106,23 @@ impl Encryptor {
         let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
 
         loop {
-            let result = encryptor.encrypt(&mut read_buffer, &mut write_buffer, true)?;
-
+            let result = encryptor.encrypt(&mut read_buffer, &mut write_buffer, true).unwrap();
             final_result.extend(write_buffer.take_read_buffer().take_remaining().iter().map(|&i| i));
-
             match result {
                 BufferResult::BufferUnderflow => break,
                 BufferResult::BufferOverflow => {}
             }
         }
 
-        Ok(final_result)
+        hex::encode(&final_result)
     }
 
-    pub fn decrypt(&self, encrypted_data: &[u8], key: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
+    /// AES-256-CBC with the IV from the server secret and the session ID used directly as the key - used by older versions.
+    fn decrypt_legacy(&self, encrypted_data: &[u8], key: &[u8]) -> Result<Vec<u8>, symmetriccipher::SymmetricCipherError> {
         let mut decryptor = aes::cbc_decryptor(
             aes::KeySize::KeySize256,
             key,
-            &self.iv,
+            &self.legacy_iv,
             blockmodes::PkcsPadding);
 
         let mut final_result = Vec::<u8>::new();
@@ -61,4 +141,74 @@ impl Encryptor {
 
         Ok(final_result)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AEAD_PREFIX, Encryptor};
+
+    // legacy values were encrypted by the session ID used directly as the AES-256 key, so it has to be 32 bytes long
+    const SESSION_ID: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn encryptor() -> Encryptor {
+        Encryptor::new("the server secret, long enough".to_string())
+    }
+
+    #[test]
+    fn round_trip() {
+        let enc = encryptor();
+
+        let encrypted = enc.encrypt(b"repo secret", SESSION_ID);
+        assert!(encrypted.starts_with(AEAD_PREFIX));
+
+        let decrypted = enc.decrypt(&encrypted, SESSION_ID).unwrap();
+        assert_eq!(b"repo secret".to_vec(), decrypted.data);
+        assert!(!decrypted.legacy);
+    }
+
+    #[test]
+    fn uses_random_nonce() {
+        let enc = encryptor();
+
+        assert_ne!(enc.encrypt(b"repo secret", SESSION_ID), enc.encrypt(b"repo secret", SESSION_ID));
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_secret() {
+        let encrypted = encryptor().encrypt(b"repo secret", SESSION_ID);
+
+        assert!(encryptor().decrypt(&encrypted, b"another session id").is_err());
+        assert!(Encryptor::new("another server secret".to_string()).decrypt(&encrypted, SESSION_ID).is_err());
+    }
+
+    #[test]
+    fn rejects_modified_value() {
+        let enc = encryptor();
+        let encrypted = enc.encrypt(b"repo secret", SESSION_ID);
+
+        // flips a bit of the first byte of the ciphertext (right after the nonce)
+        let position = AEAD_PREFIX.len() + 2 * super::NONCE_LEN;
+        let flipped = if &encrypted[position..position + 1] == "0" { "1" } else { "0" };
+        let modified = format!("{}{}{}", &encrypted[..position], flipped, &encrypted[position + 1..]);
+
+        assert!(enc.decrypt(&modified, SESSION_ID).is_err());
+        assert!(enc.decrypt(&encrypted[..AEAD_PREFIX.len() + 20], SESSION_ID).is_err());
+    }
+
+    #[test]
+    fn decrypts_legacy_value() {
+        let enc = encryptor();
+
+        let legacy = enc.encrypt_legacy(b"repo secret", SESSION_ID);
+        assert!(!legacy.starts_with(AEAD_PREFIX));
+
+        let decrypted = enc.decrypt(&legacy, SESSION_ID).unwrap();
+        assert_eq!(b"repo secret".to_vec(), decrypted.data);
+        assert!(decrypted.legacy);
+
+        // the value is re-encrypted by the current scheme
+        let migrated = enc.decrypt(&enc.encrypt(&decrypted.data, SESSION_ID), SESSION_ID).unwrap();
+        assert_eq!(b"repo secret".to_vec(), migrated.data);
+        assert!(!migrated.legacy);
+    }
+}
@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate arrayref;
+extern crate argon2;
 extern crate cache_2q;
 extern crate cadence;
 #[macro_use]
@@ -14,8 +15,13 @@ extern crate multipart;
 #[macro_use]
 extern crate mysql;
 extern crate pipe;
+extern crate postgres;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate rand;
 extern crate rdedup_lib as rdedup;
 extern crate rdedup_lib;
+extern crate rusqlite;
 extern crate rocket;
 #[macro_use]
 extern crate serde_derive;
@@ -45,28 +51,36 @@ use rocket::data::DataStream;
 use sha2::{Digest, Sha256};
 use slog::Logger;
 use stopwatch::Stopwatch;
+use uuid::Uuid;
 
 use crate::dao::Dao;
 use crate::encryptor::Encryptor;
 use crate::failures::*;
 use crate::responses::*;
+use crate::retention::RetentionPolicy;
 use crate::structs::*;
 
 pub mod dao;
 pub mod failures;
+pub mod migrations;
+pub mod passwords;
 pub mod encryptor;
 pub mod structs;
 pub mod responses;
+pub mod retention;
+pub mod sessions;
+pub mod throttling;
+pub mod totp;
 
 pub const APP_VERSION: &str = crate_version!();
 
-struct DigestDataStream {
-    inner: Arc<Mutex<DigestDataStreamInner>>,
+struct DigestDataStream<R: Read> {
+    inner: Arc<Mutex<DigestDataStreamInner<R>>>,
     handle_upload_chunk: Box<dyn Fn(u64) -> () + Send + Sync + 'static>
 }
 
-impl DigestDataStream {
-    pub fn new(inner: Arc<Mutex<DigestDataStreamInner>>, handle_upload_chunk: Box<dyn Fn(u64) -> () + Send + Sync + 'static>) -> DigestDataStream {
+impl<R: Read> DigestDataStream<R> {
+    pub fn new(inner: Arc<Mutex<DigestDataStreamInner<R>>>, handle_upload_chunk: Box<dyn Fn(u64) -> () + Send + Sync + 'static>) -> DigestDataStream<R> {
         DigestDataStream {
             inner,
             handle_upload_chunk
@@ -74,7 +88,7 @@ impl DigestDataStream {
     }
 }
 
-impl Read for DigestDataStream {
+impl<R: Read> Read for DigestDataStream<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
         let mut inner = self.inner.lock().unwrap();
 
@@ -88,16 +102,16 @@ impl Read for DigestDataStream {
     }
 }
 
-struct DigestDataStreamInner {
-    file_entry: MultipartField<Multipart<DataStream>>,
+struct DigestDataStreamInner<R: Read> {
+    reader: R,
     hasher: Sha256,
     size: u64
 }
 
-impl DigestDataStreamInner {
-    pub fn new(file_entry: MultipartField<Multipart<DataStream>>) -> DigestDataStreamInner {
+impl<R: Read> DigestDataStreamInner<R> {
+    pub fn new(reader: R) -> DigestDataStreamInner<R> {
         DigestDataStreamInner {
-            file_entry,
+            reader,
             hasher: Sha256::default(),
             size: 0
         }
@@ -112,9 +126,51 @@ impl DigestDataStreamInner {
     }
 }
 
-impl Read for DigestDataStreamInner {
+impl<R: Read> Read for DigestDataStreamInner<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.file_entry.data.read(buf)
+        self.reader.read(buf)
+    }
+}
+
+/// Writes all data from the reader into the repo under given name, returning size and SHA256 hash of the data.
+fn write_with_digest<R: Read>(repo: &Repo, reader: R, storage_name: &str, handle_upload_chunk: Box<dyn Fn(u64) -> () + Send + Sync + 'static>) -> Result<(u64, String), Error> {
+    let data_inner = Arc::new(Mutex::new(DigestDataStreamInner::new(reader)));
+
+    let stream = DigestDataStream::new(data_inner.clone(), handle_upload_chunk);
+
+    let encrypt_handle = repo.repo.unlock_encrypt(&*repo.pass)?;
+    repo.repo.write(storage_name, stream, &encrypt_handle)?;
+
+    let data = {
+        Arc::try_unwrap(data_inner).map_err(|_| Error::from(CustomError::new("Could not unlock the data stream after reading")))?.into_inner()?
+    };
+
+    Ok((data.size, hex::encode(data.hasher.result())))
+}
+
+fn report_uploaded_bytes(statsd_client: StatsdClient, device_id: &str) -> Box<dyn Fn(u64) -> () + Send + Sync + 'static> {
+    let device_id = String::from(device_id);
+
+    Box::from(move |copied_bytes: u64| {
+        #[allow(unused_must_use)] {
+            statsd_client.count("upload.total.bytes", copied_bytes as i64);
+            statsd_client.count(&format!("upload.devices.{}.bytes", &device_id), copied_bytes as i64);
+        }
+    })
+}
+
+fn check_hash(logger: &Logger, statsd_client: &StatsdClient, device_id: &str, size: u64, hash_calculated: String, hash_declared: &str) -> UploadedData {
+    trace!(logger, "Declared hash '{}', calculated '{}'", hash_declared, &hash_calculated);
+
+    if hash_calculated == hash_declared {
+        UploadedData::Success(size, hash_calculated)
+    } else {
+        warn!(logger, "Declared hash '{}' doesn't match calculated '{}'", hash_declared, &hash_calculated);
+        #[allow(unused_must_use)] {
+            statsd_client.count("upload.total.failed", 1);
+            statsd_client.count(format!("upload.devices.{}.failed", device_id).as_ref(), 1);
+        }
+        UploadedData::MismatchSha256
     }
 }
 
@@ -123,7 +179,7 @@ fn process_multipart_upload(logger: &Logger, statsd_client: StatsdClient, repo:
 
     // read file:
 
-    let file_entry: MultipartField<Multipart<DataStream>> = match multipart.read_entry() {
+    let mut file_entry: MultipartField<Multipart<DataStream>> = match multipart.read_entry() {
         ReadEntryResult::Entry(entry) => {
             if entry.headers.name.as_ref() != "file" { return Err(Error::from(CustomError::new("'file' part is missing or is misplaced"))); }
             entry
@@ -134,32 +190,10 @@ fn process_multipart_upload(logger: &Logger, statsd_client: StatsdClient, repo:
 
     debug!(logger, "Handling file upload");
 
-    let data_inner = Arc::new(Mutex::new(DigestDataStreamInner::new(file_entry)));
-
-    let statsd_client_cp = statsd_client.clone();
-    let device_id_cp = device_id.clone();
-
-    let stream = DigestDataStream::new(data_inner.clone(),
-                                       Box::from(move |copied_bytes| {
-                                           #[allow(unused_must_use)] {
-                                               statsd_client_cp.count("upload.total.bytes", copied_bytes as i64);
-                                               statsd_client_cp.count(&format!("upload.devices.{}.bytes", &device_id_cp), copied_bytes as i64);
-                                           }
-                                       }));
-
-    let encrypt_handle = repo.repo.unlock_encrypt(&*repo.pass)?;
-    repo.repo.write(storage_name, stream, &encrypt_handle)?;
-
-    let data = {
-        Arc::try_unwrap(data_inner).map_err(|_| Error::from(CustomError::new("Could not unlock the file_entry after reading")))?.into_inner()?
-    };
-
-    let hash_calculated: String = hex::encode(data.hasher.result());
+    let (size, hash_calculated) = write_with_digest(repo, &mut file_entry.data, storage_name, report_uploaded_bytes(statsd_client.clone(), &device_id))?;
 
     // read file hash:
 
-    let file_entry: MultipartField<Multipart<DataStream>> = data.file_entry;
-
     let mut file_entry = match file_entry.next_entry() {
         ReadEntryResult::Entry(entry) => {
             if entry.headers.name.as_ref() != "file-hash" { return Err(Error::from(CustomError::new("'file-hash' part is missing or is misplaced"))); }
@@ -173,45 +207,274 @@ fn process_multipart_upload(logger: &Logger, statsd_client: StatsdClient, repo:
     file_entry.data.read_to_end(&mut hash_declared)?;
     let hash_declared: String = String::from_utf8(hash_declared)?;
 
-    trace!(logger, "Declared hash '{}', calculated '{}'", &hash_declared, &hash_calculated);
-
     // check hash and return
 
-    if hash_calculated == hash_declared {
-        Ok(UploadedData::Success(data.size, hash_calculated))
-    } else {
-        warn!(logger, "Declared hash '{}' doesn't match calculated '{}'", &hash_declared, &hash_calculated);
-        #[allow(unused_must_use)] {
-            statsd_client.count("upload.total.failed", 1);
-            statsd_client.count(format!("upload.devices.{}.failed", &device_id).as_ref(), 1);
+    Ok(check_hash(logger, &statsd_client, &device_id, size, hash_calculated, &hash_declared))
+}
+
+pub fn register(logger: &Logger, dao: &Dao, repo_root: &str, client_ip: Option<&str>, username: &str, pass: &str, with_recovery_key: bool) -> Result<RegisterResult, Error> {
+    if let Some(ip) = client_ip {
+        if let Some(blocked_for) = dao.registration_blocked_for(ip)? {
+            warn!(logger, "Registration from {} blocked, too many registrations", ip);
+            return Ok(RegisterResult::TooManyAttempts(blocked_for.num_seconds() + 1));
         }
-        Ok(UploadedData::MismatchSha256)
+
+        dao.record_registration(ip)?;
     }
-}
 
-pub fn register(logger: &Logger, dao: &Dao, repo_root: &str, username: &str, pass: &str) -> Result<RegisterResult, Error> {
-    dao.register(username, pass)
+    let repo_secret = passwords::generate_repo_secret();
+    let recovery_key = if with_recovery_key { Some(passwords::generate_recovery_key()) } else { None };
+
+    dao.register(username, pass, &repo_secret, recovery_key)
         .and_then(|r| match r {
-            RegisterResult::Created(account_id) => {
+            RegisterResult::Created(account_id, recovery_key) => {
                 info!(logger, "Registered new account with ID {}", account_id);
-                RdedupRepo::init(&url::Url::parse(&format!("file://{}/{}", repo_root, account_id)).unwrap(), &*Box::new(move || { Ok(String::from(pass)) }), rdedup::settings::Repo::new(), logger.clone())
-                    .map(|_| RegisterResult::Created(account_id))
+                RdedupRepo::init(&url::Url::parse(&format!("file://{}/{}", repo_root, account_id)).unwrap(), &*Box::new(move || { Ok(repo_secret.clone()) }), rdedup::settings::Repo::new(), logger.clone())
+                    .map(|_| RegisterResult::Created(account_id, recovery_key))
                     .map_err(Error::from)
             },
-            RegisterResult::Exists => Ok(RegisterResult::Exists)
+            other => Ok(other)
         })
 }
 
-pub fn login(dao: &Dao, enc: &Encryptor, device_id: &str, username: &str, pass: &str) -> Result<responses::LoginResult, Error> {
-    dao.login(enc, device_id, username, pass)
-        .map_err(Error::from)
+pub fn recover_account(logger: &Logger, dao: &Dao, client_ip: Option<&str>, username: &str, recovery_key: &str, new_pass: &str) -> Result<RecoverAccountResult, Error> {
+    if let Some(blocked_for) = dao.recovery_blocked_for(username, client_ip)? {
+        warn!(logger, "Recovery of '{}' from {:?} blocked, too many failed attempts", username, client_ip);
+        return Ok(RecoverAccountResult::TooManyAttempts(blocked_for.num_seconds() + 1));
+    }
+
+    let result = dao.recover_account(username, recovery_key, new_pass)?;
+
+    match result {
+        RecoverAccountResult::Recovered(_) => {
+            info!(logger, "Account '{}' was recovered using its recovery key", username);
+            dao.clear_recovery_failures(username)?;
+        },
+        RecoverAccountResult::InvalidRecoveryKey => dao.record_recovery_failure(username, client_ip)?,
+        RecoverAccountResult::TooManyAttempts(_) => ()
+    }
+
+    Ok(result)
+}
+
+pub fn login(logger: &Logger, dao: &Dao, enc: &Encryptor, repo_root: &str, client_ip: Option<&str>, device_id: &str, device_os: Option<&str>, username: &str, pass: &str, totp_code: Option<&str>) -> Result<responses::LoginResult, Error> {
+    if let Some(blocked_for) = dao.login_blocked_for(username, client_ip)? {
+        warn!(logger, "Login of '{}' from {:?} blocked, too many failed attempts", username, client_ip);
+        return Ok(responses::LoginResult::TooManyAttempts(blocked_for.num_seconds() + 1));
+    }
+
+    match dao.verify_password(username, pass)? {
+        Some(account_id) => {
+            match dao.verify_second_factor(&account_id, totp_code)? {
+                SecondFactorVerification::Missing => return Ok(responses::LoginResult::SecondFactorRequired),
+                SecondFactorVerification::Invalid => {
+                    dao.record_login_failure(username, client_ip)?;
+                    return Ok(responses::LoginResult::InvalidSecondFactor);
+                },
+                SecondFactorVerification::NotEnabled | SecondFactorVerification::Valid => ()
+            }
+
+            dao.clear_login_failures(username)?;
+
+            let repo_secret = unlock_repo_secret(logger, dao, repo_root, &account_id, pass)?;
+
+            dao.create_session(enc, device_id, device_os, &account_id, &repo_secret)
+        },
+        None => {
+            dao.record_login_failure(username, client_ip)?;
+            Ok(responses::LoginResult::AccountNotFound)
+        }
+    }
+}
+
+pub fn change_password(logger: &Logger, dao: &Dao, enc: &Encryptor, repo_root: &str, device: &DeviceIdentity, old_pass: &str, new_pass: &str) -> Result<ChangePasswordResult, Error> {
+    if !dao.verify_account_password(&device.account_id, old_pass)? {
+        return Ok(ChangePasswordResult::InvalidPassword);
+    }
+
+    let repo_secret = unlock_repo_secret(logger, dao, repo_root, &device.account_id, old_pass)?;
+
+    info!(logger, "Changing password of account {}", device.account_id);
+
+    dao.change_password(enc, &device.account_id, &device.id, new_pass, &repo_secret)
+        .map(ChangePasswordResult::Changed)
+}
+
+/// Schedules the removal of the account with all its data after the grace period; until then, the removal can be cancelled.
+pub fn request_account_deletion(logger: &Logger, dao: &Dao, device: &DeviceIdentity, pass: &str, grace_period: chrono::Duration) -> Result<RequestAccountDeletionResult, Error> {
+    if !dao.verify_account_password(&device.account_id, pass)? {
+        return Ok(RequestAccountDeletionResult::InvalidPassword);
+    }
+
+    dao.request_account_deletion(&device.account_id, &format!("device:{}", device.id), grace_period)
+        .map(|scheduled| match scheduled {
+            Some(purge_after) => {
+                info!(logger, "Deletion of account {} requested by device {}, scheduled after {}", device.account_id, device.id, purge_after);
+                RequestAccountDeletionResult::Scheduled(purge_after)
+            },
+            None => RequestAccountDeletionResult::AlreadyScheduled
+        })
+}
+
+pub fn cancel_account_deletion(logger: &Logger, dao: &Dao, device: &DeviceIdentity) -> Result<CancelAccountDeletionResult, Error> {
+    dao.cancel_account_deletion(&device.account_id, &format!("device:{}", device.id))
+        .map(|cancelled| {
+            if cancelled {
+                info!(logger, "Deletion of account {} cancelled by device {}", device.account_id, device.id);
+                CancelAccountDeletionResult::Cancelled
+            } else {
+                CancelAccountDeletionResult::NotScheduled
+            }
+        })
+}
+
+pub fn enroll_totp(logger: &Logger, dao: &Dao, account_id: &str) -> Result<EnrollTotpResult, Error> {
+    match dao.enroll_totp(account_id)? {
+        Some(secret) => {
+            info!(logger, "Enrolling TOTP of account {}", account_id);
+
+            let (encoded_secret, uri) = totp::export_secret(&secret)?;
+            Ok(EnrollTotpResult::Enrolled(encoded_secret, uri))
+        },
+        None => Ok(EnrollTotpResult::AlreadyEnabled)
+    }
+}
+
+pub fn confirm_totp(logger: &Logger, dao: &Dao, account_id: &str, code: &str) -> Result<ConfirmTotpResult, Error> {
+    dao.confirm_totp(account_id, code)
+        .map(|result| {
+            if let ConfirmTotpResult::Enabled(_) = result {
+                info!(logger, "TOTP of account {} was enabled", account_id);
+            }
+            result
+        })
+}
+
+pub fn disable_totp(logger: &Logger, dao: &Dao, account_id: &str, pass: &str, code: &str) -> Result<DisableTotpResult, Error> {
+    dao.disable_totp(account_id, pass, code)
+        .map(|result| {
+            if let DisableTotpResult::Disabled = result {
+                info!(logger, "TOTP of account {} was disabled", account_id);
+            }
+            result
+        })
 }
 
-pub fn authenticate(dao: &Dao, enc: &Encryptor, session_pass: &str) -> Result<Option<DeviceIdentity>, Error> {
+/// Returns the secret of the account's repo, migrating legacy repos (encrypted directly by the login password) to a random secret.
+fn unlock_repo_secret(logger: &Logger, dao: &Dao, repo_root: &str, account_id: &str, pass: &str) -> Result<String, Error> {
+    match dao.unlock_repo_secret(account_id, pass)? {
+        Some(repo_secret) => Ok(repo_secret),
+        None => {
+            info!(logger, "Migrating repo of account {} to a random secret", account_id);
+
+            dao.migrate_repo_secret(account_id, pass, |old_secret, new_secret| {
+                let repo = Repo::new(repo_root, account_id, String::from(old_secret), logger)?;
+                let new_secret = String::from(new_secret);
+
+                repo.repo.change_passphrase(&*repo.pass, &*Box::new(move || { Ok(new_secret.clone()) }))
+                    .map_err(Error::from)
+            })
+        }
+    }
+}
+
+pub fn authenticate(dao: &Dao, enc: &Encryptor, session_pass: &str) -> Result<Authentication, Error> {
     dao.authenticate(enc, session_pass)
         .map_err(Error::from)
 }
 
+pub fn logout(logger: &Logger, dao: &Dao, device: &DeviceIdentity, session_pass: &str) -> Result<LogoutResult, Error> {
+    dao.logout(session_pass)
+        .map(|removed| {
+            if removed {
+                info!(logger, "Device {} of account {} logged out", device.id, device.account_id);
+                LogoutResult::Success
+            } else {
+                LogoutResult::SessionNotFound
+            }
+        })
+}
+
+pub fn list_sessions(dao: &Dao, account_id: &str, session_pass: &str) -> Result<ListSessionsResult, Error> {
+    dao.list_sessions(account_id, session_pass)
+        .map(ListSessionsResult::Success)
+}
+
+pub fn revoke_sessions(logger: &Logger, dao: &Dao, account_id: &str, session_id: Option<&str>, device_id: Option<&str>) -> Result<RevokeSessionsResult, Error> {
+    match (session_id, device_id) {
+        (Some(session_id), None) => {
+            dao.revoke_session(account_id, session_id)
+                .map(|removed| {
+                    if removed {
+                        info!(logger, "Revoked session {} of account {}", session_id, account_id);
+                        RevokeSessionsResult::Success(1)
+                    } else {
+                        RevokeSessionsResult::NotFound
+                    }
+                })
+        },
+        (None, Some(device_id)) => {
+            dao.revoke_device_sessions(account_id, device_id)
+                .map(|removed| {
+                    info!(logger, "Revoked {} sessions of device {} of account {}", removed, device_id, account_id);
+                    RevokeSessionsResult::Success(removed)
+                })
+        },
+        _ => Ok(RevokeSessionsResult::InvalidRequest("Exactly one of session_id and device_id has to be provided".to_string()))
+    }
+}
+
+/// Creates an API token of the device (the current one, if not specified). The token contains the repo secret of the current session.
+pub fn create_api_token(logger: &Logger, dao: &Dao, enc: &Encryptor, device: &DeviceIdentity, device_id: Option<&str>, name: &str, scope: TokenScope) -> Result<CreateApiTokenResult, Error> {
+    let device_id = device_id.unwrap_or(&device.id);
+
+    // the token would make an unknown device known, with the default role
+    if !dao.is_known_device(&device.account_id, device_id)? {
+        return Ok(CreateApiTokenResult::DeviceNotFound);
+    }
+
+    dao.create_api_token(enc, &device.account_id, device_id, name, scope, &device.repo_pass)
+        .map(|(token, token_id)| {
+            info!(logger, "Created '{}' API token {} of device {} of account {}", scope.name(), token_id, device_id, device.account_id);
+            CreateApiTokenResult::Created(token, token_id)
+        })
+}
+
+pub fn list_device_roles(dao: &Dao, account_id: &str) -> Result<ListDeviceRolesResult, Error> {
+    dao.list_device_roles(account_id)
+        .map(ListDeviceRolesResult::Success)
+}
+
+/// Sets role of another device of the account; the current device can't change its own role, so the account always keeps an admin.
+pub fn set_device_role(logger: &Logger, dao: &Dao, device: &DeviceIdentity, device_id: &str, role: DeviceRole) -> Result<SetDeviceRoleResult, Error> {
+    if device_id == device.id {
+        return Ok(SetDeviceRoleResult::CurrentDevice);
+    }
+
+    dao.set_device_role(&device.account_id, device_id, role)
+        .map(|_| {
+            info!(logger, "Set role '{}' of device {} of account {}", role.name(), device_id, device.account_id);
+            SetDeviceRoleResult::Updated
+        })
+}
+
+pub fn list_api_tokens(dao: &Dao, account_id: &str) -> Result<ListApiTokensResult, Error> {
+    dao.list_api_tokens(account_id)
+        .map(ListApiTokensResult::Success)
+}
+
+pub fn revoke_api_token(logger: &Logger, dao: &Dao, account_id: &str, token_id: &str) -> Result<RevokeApiTokenResult, Error> {
+    dao.revoke_api_token(account_id, token_id)
+        .map(|removed| {
+            if removed {
+                info!(logger, "Revoked API token {} of account {}", token_id, account_id);
+                RevokeApiTokenResult::Revoked
+            } else {
+                RevokeApiTokenResult::NotFound
+            }
+        })
+}
+
 pub fn save(logger: &Logger, statsd_client: StatsdClient, repo: &Repo, dao: &Dao, uploaded_file: UploadedFile, boundary: &str, data: Data) -> Result<UploadResult, Error> {
     let current_time = SystemTime::now()
         .duration_since(UNIX_EPOCH)?;
@@ -237,26 +500,354 @@ pub fn save(logger: &Logger, statsd_client: StatsdClient, repo: &Repo, dao: &Dao
                     statsd_client.time(format!("upload.devices.{}.length", uploaded_file.device_id).as_ref(), duration);
                 }
 
-                // TODO check whether there is not already last version with the same hash
-                let new_version = FileVersion {
-                    version: 0, // cannot know now, will be filled in after DB insertion
-                    size,
-                    hash,
-                    created: time_stamp,
-                    mtime: uploaded_file.mtime,
-                    storage_name
-                };
-
-                dao.save_file_version(&uploaded_file, new_version)
-                    .map(UploadResult::Success)
-                    .map_err(Error::from)
+                store_uploaded_version(logger, repo, dao, &uploaded_file, size, hash, storage_name, time_stamp)
             },
             UploadedData::MismatchSha256 => Ok(UploadResult::MismatchSha256)
         })
 }
 
-pub fn load(logger: Logger, repo: &Repo, dao: &Dao, version_id: u64) -> Result<Option<(String, u64, Box<dyn Read>)>, Error> {
-    dao.get_hash_size_and_storage_name(version_id)
+pub fn save_by_hash(logger: &Logger, repo: &Repo, dao: &Dao, uploaded_file: UploadedFile, hash: &str) -> Result<UploadResult, Error> {
+    let hash = hash.to_lowercase();
+
+    debug!(logger, "Looking for already stored content of file {:?}", &uploaded_file; "hash" => &hash);
+
+    if let Some(file) = touch_unchanged_file(logger, dao, &uploaded_file, uploaded_file.size, &hash)? {
+        return Ok(UploadResult::Unchanged(file));
+    }
+
+    match dao.find_storage_name_by_hash(&uploaded_file.account_id, &hash, uploaded_file.size)? {
+        Some(existing_storage_name) => {
+            let current_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)?;
+
+            // round the timestamp to millis
+            let time_stamp = NaiveDateTime::from_timestamp(current_time.as_secs() as i64, current_time.subsec_nanos() / 1000000 * 1000000);
+            let storage_name = to_storage_name(&uploaded_file.device_id, &uploaded_file.original_name, current_time);
+
+            debug!(logger, "Content found in the repository, creating new version by reference"; "existing_storage_name" => &existing_storage_name, "storage_name" => &storage_name);
+
+            // the data are deduplicated by the repo so the copy takes almost no space
+            let (_, copied_hash) = copy_in_repo(logger, repo, vec![existing_storage_name], &storage_name)?;
+
+            if copied_hash != hash {
+                #[allow(unused_must_use)] {
+                    repo.repo.rm(&storage_name);
+                }
+                return Err(Error::from(CustomError::new("Hash of the copied data doesn't match")));
+            }
+
+            let new_version = FileVersion {
+                version: 0, // cannot know now, will be filled in after DB insertion
+                size: uploaded_file.size,
+                hash,
+                created: time_stamp,
+                mtime: uploaded_file.mtime,
+                storage_name
+            };
+
+            dao.save_file_version(&uploaded_file, new_version)
+                .map(UploadResult::Success)
+                .map_err(Error::from)
+        },
+        None => Ok(UploadResult::ContentNotFound)
+    }
+}
+
+pub fn create_upload_session(dao: &Dao, uploaded_file: UploadedFile, hash: &str) -> Result<UploadSessionResult, Error> {
+    let session = UploadSession {
+        id: Uuid::new_v4().hyphenated().to_string(),
+        account_id: uploaded_file.account_id,
+        device_id: uploaded_file.device_id,
+        original_name: uploaded_file.original_name,
+        size: uploaded_file.size,
+        mtime: uploaded_file.mtime,
+        hash: hash.to_lowercase()
+    };
+
+    dao.create_upload_session(&session)
+        .map(|_| UploadSessionResult::Created(session.id))
+        .map_err(Error::from)
+}
+
+pub fn upload_session_status(dao: &Dao, account_id: &str, device_id: &str, session_id: &str) -> Result<UploadSessionResult, Error> {
+    match dao.find_upload_session(account_id, device_id, session_id)? {
+        Some(session) => {
+            let chunks = dao.get_upload_chunks(&session.id)?;
+            Ok(UploadSessionResult::Progress(received_bytes(&chunks)))
+        },
+        None => Ok(UploadSessionResult::NotFound)
+    }
+}
+
+pub fn save_upload_chunk(logger: &Logger, statsd_client: StatsdClient, repo: &Repo, dao: &Dao, account_id: &str, device_id: &str, session_id: &str, index: u32, hash: &str, data: Data) -> Result<UploadSessionResult, Error> {
+    let session = match dao.find_upload_session(account_id, device_id, session_id)? {
+        Some(session) => session,
+        None => return Ok(UploadSessionResult::NotFound)
+    };
+
+    let current_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?;
+
+    let storage_name = to_storage_name(&session.id, &format!("chunk-{}", index), current_time);
+
+    // the chunk may replace the one with the same index
+    let max_size = session.size.saturating_sub(dao.get_upload_chunks(&session.id)?.iter()
+        .filter(|chunk| chunk.index != index)
+        .map(|chunk| chunk.size)
+        .sum());
+
+    debug!(logger, "Receiving chunk {} of upload session", index; "session_id" => &session.id, "storage_name" => &storage_name, "max_size" => max_size);
+
+    // one byte over the limit is enough to reject the chunk, the rest of the data is not read at all
+    let (size, hash_calculated) = write_with_digest(repo, data.open().take(max_size + 1), &storage_name, report_uploaded_bytes(statsd_client.clone(), device_id))?;
+
+    if size > max_size {
+        repo.repo.rm(&storage_name)?;
+        return Ok(chunk_size_exceeded(index, session.size));
+    }
+
+    match check_hash(logger, &statsd_client, device_id, size, hash_calculated, &hash.to_lowercase()) {
+        UploadedData::Success(size, hash) => {
+            let chunk = UploadChunk {
+                index,
+                size,
+                hash,
+                storage_name
+            };
+
+            let saved = match dao.save_upload_chunk(&session.id, &chunk, session.size) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    // e.g. the session was removed in the meantime
+                    if let Err(err) = repo.repo.rm(&chunk.storage_name) {
+                        warn!(logger, "Could not remove unsaved chunk from repository: {}", err; "storage_name" => &chunk.storage_name);
+                    }
+
+                    return Err(e);
+                }
+            };
+
+            match saved {
+                SavedUploadChunk::Saved(Some(replaced)) => {
+                    if let Err(e) = repo.repo.rm(&replaced) {
+                        warn!(logger, "Could not remove replaced chunk from repository: {}", e; "storage_name" => &replaced);
+                    }
+                },
+                SavedUploadChunk::Saved(None) => (),
+                SavedUploadChunk::SizeExceeded => {
+                    repo.repo.rm(&chunk.storage_name)?;
+                    return Ok(chunk_size_exceeded(index, session.size));
+                }
+            }
+
+            let chunks = dao.get_upload_chunks(&session.id)?;
+            Ok(UploadSessionResult::Progress(received_bytes(&chunks)))
+        },
+        UploadedData::MismatchSha256 => {
+            repo.repo.rm(&storage_name)?;
+            Ok(UploadSessionResult::ChunkMismatchSha256)
+        }
+    }
+}
+
+pub fn finalize_upload_session(logger: &Logger, statsd_client: StatsdClient, repo: &Repo, dao: &Dao, account_id: &str, device_id: &str, session_id: &str) -> Result<UploadSessionResult, Error> {
+    let session = match dao.find_upload_session(account_id, device_id, session_id)? {
+        Some(session) => session,
+        None => return Ok(UploadSessionResult::NotFound)
+    };
+
+    let chunks = dao.get_upload_chunks(&session.id)?;
+    let received = received_bytes(&chunks);
+
+    if received != session.size || chunks.iter().enumerate().any(|(i, chunk)| chunk.index != i as u32) {
+        return Ok(UploadSessionResult::InvalidRequest(format!("Upload is not complete, received {} of {} B", received, session.size)));
+    }
+
+    let current_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?;
+
+    // round the timestamp to millis
+    let time_stamp = NaiveDateTime::from_timestamp(current_time.as_secs() as i64, current_time.subsec_nanos() / 1000000 * 1000000);
+    let storage_name = to_storage_name(&session.device_id, &session.original_name, current_time);
+
+    debug!(logger, "Finalizing upload session"; "session_id" => &session.id, "chunks" => chunks.len(), "storage_name" => &storage_name);
+
+    let (size, hash_calculated) = copy_in_repo(logger, repo, chunks.into_iter().map(|chunk| chunk.storage_name).collect(), &storage_name)?;
+
+    let uploaded_file = to_uploaded_file(&session.account_id, &session.device_id, &session.original_name, session.size, session.mtime.timestamp_millis() as u64);
+
+    let result = match check_hash(logger, &statsd_client, device_id, size, hash_calculated, &session.hash) {
+        UploadedData::Success(size, hash) => store_uploaded_version(logger, repo, dao, &uploaded_file, size, hash, storage_name, time_stamp)?,
+        UploadedData::MismatchSha256 => {
+            repo.repo.rm(&storage_name)?;
+            UploadResult::MismatchSha256
+        }
+    };
+
+    remove_upload_session_data(logger, repo, dao, &session.id)?;
+
+    Ok(UploadSessionResult::Finished(result))
+}
+
+pub fn abort_upload_session(logger: &Logger, repo: &Repo, dao: &Dao, account_id: &str, device_id: &str, session_id: &str) -> Result<UploadSessionResult, Error> {
+    match dao.find_upload_session(account_id, device_id, session_id)? {
+        Some(session) => {
+            remove_upload_session_data(logger, repo, dao, &session.id)
+                .map(|_| UploadSessionResult::Aborted)
+        },
+        None => Ok(UploadSessionResult::NotFound)
+    }
+}
+
+pub fn remove_abandoned_upload_sessions(logger: &Logger, dao: &Dao, repo_root: &str, timeout: chrono::Duration) -> Result<u64, Error> {
+    let threshold = Utc::now().naive_utc() - timeout;
+
+    debug!(logger, "Looking for upload sessions inactive since {}", threshold);
+
+    let sessions = dao.find_upload_sessions_inactive_since(&threshold)?
+        .into_iter()
+        .map(|(session_id, account_id)| (account_id, session_id))
+        .collect::<multimap::MultiMap<String, String>>();
+
+    let mut removed = 0;
+
+    for (account_id, session_ids) in sessions {
+        // the password is not needed for removing the data from the repo
+        let repo = match Repo::new(repo_root, &account_id, String::new(), logger) {
+            Ok(repo) => repo,
+            Err(e) => {
+                warn!(logger, "Could not open repository: {}", e; "account_id" => &account_id);
+                continue;
+            }
+        };
+
+        for session_id in session_ids {
+            match remove_upload_session_data(logger, &repo, dao, &session_id) {
+                Ok(_) => removed += 1,
+                Err(e) => warn!(logger, "Could not remove upload session: {}", e; "account_id" => &account_id, "session_id" => &session_id)
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+fn remove_upload_session_data(logger: &Logger, repo: &Repo, dao: &Dao, session_id: &str) -> Result<(), Error> {
+    for chunk in dao.get_upload_chunks(session_id)? {
+        if let Err(e) = repo.repo.rm(&chunk.storage_name) {
+            warn!(logger, "Could not remove chunk from repository: {}", e; "session_id" => session_id, "storage_name" => &chunk.storage_name);
+        }
+    }
+
+    dao.remove_upload_session(session_id)
+        .map(|_| ())
+        .map_err(Error::from)
+}
+
+/// Number of bytes received in the continuous sequence of chunks from the beginning of the file.
+fn chunk_size_exceeded(index: u32, file_size: u64) -> UploadSessionResult {
+    UploadSessionResult::InvalidRequest(format!("Chunk {} exceeds size of the file ({} B)", index, file_size))
+}
+
+fn received_bytes(chunks: &[UploadChunk]) -> u64 {
+    chunks.iter()
+        .enumerate()
+        .take_while(|(i, chunk)| chunk.index == *i as u32)
+        .map(|(_, chunk)| chunk.size)
+        .sum()
+}
+
+fn store_uploaded_version(logger: &Logger, repo: &Repo, dao: &Dao, uploaded_file: &UploadedFile, size: u64, hash: String, storage_name: String, time_stamp: NaiveDateTime) -> Result<UploadResult, Error> {
+    match touch_unchanged_file(logger, dao, uploaded_file, size, &hash)? {
+        Some(file) => {
+            // the content is already stored under the name of the last version
+            repo.repo.rm(&storage_name)?;
+
+            Ok(UploadResult::Unchanged(file))
+        },
+        None => {
+            let new_version = FileVersion {
+                version: 0, // cannot know now, will be filled in after DB insertion
+                size,
+                hash,
+                created: time_stamp,
+                mtime: uploaded_file.mtime,
+                storage_name
+            };
+
+            dao.save_file_version(uploaded_file, new_version)
+                .map(UploadResult::Success)
+                .map_err(Error::from)
+        }
+    }
+}
+
+fn touch_unchanged_file(logger: &Logger, dao: &Dao, uploaded_file: &UploadedFile, size: u64, hash: &str) -> Result<Option<File>, Error> {
+    match find_last_version(dao, &uploaded_file.identity_hash)? {
+        Some((mut file, last_version)) if last_version.hash == hash && last_version.size == size => {
+            debug!(logger, "Uploaded file is the same as its last version, only updating mtime"; "version" => last_version.version);
+
+            dao.touch_file_version(&uploaded_file.account_id, last_version.version, &uploaded_file.mtime)?;
+            dao.record_backup(&uploaded_file.account_id, &uploaded_file.device_id)?;
+
+            for version in file.versions.iter_mut().filter(|v| v.version == last_version.version) {
+                version.mtime = uploaded_file.mtime;
+            }
+
+            Ok(Some(file))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Writes concatenated content of the source names into the target name, returning size and SHA256 hash of the written data.
+fn copy_in_repo(logger: &Logger, repo: &Repo, source_names: Vec<String>, target_name: &str) -> Result<(u64, String), Error> {
+    use std::thread::spawn;
+
+    let (reader, mut writer) = pipe::pipe();
+
+    let source_repo = repo.repo.clone();
+    let decrypt_handle = repo.repo.unlock_decrypt(&*repo.pass)?;
+
+    let reading = spawn(move || {
+        source_names.iter().fold(Ok(()), |acc, source_name| {
+            acc.and_then(|_| source_repo.read(source_name, &mut writer, &decrypt_handle))
+        })
+    });
+
+    let written = write_with_digest(repo, reader, target_name, Box::from(|_: u64| ()));
+
+    // the writer is closed even when reading fails, so the result of the reading has to be checked too
+    let read = reading.join().map_err(|_| Error::from(CustomError::new("Reading from the repository has panicked")))?;
+
+    match (written, read) {
+        (Ok(written), Ok(_)) => Ok(written),
+        (written, read) => {
+            let e = written.err().unwrap_or_else(|| Error::from(read.unwrap_err()));
+            warn!(logger, "Could not copy data in repository: {}", e; "target_name" => target_name);
+            #[allow(unused_must_use)] {
+                repo.repo.rm(target_name);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn find_last_version(dao: &Dao, identity_hash: &str) -> Result<Option<(File, FileVersion)>, Error> {
+    dao.find_file(identity_hash)
+        .map(|file| {
+            file.and_then(|file| {
+                file.versions.iter()
+                    .max_by_key(|v| v.version)
+                    .cloned()
+                    .map(|last_version| (file, last_version))
+            })
+        })
+        .map_err(Error::from)
+}
+
+pub fn load(logger: Logger, repo: &Repo, dao: &Dao, account_id: &str, version_id: u64) -> Result<Option<(String, u64, Box<dyn Read>)>, Error> {
+    dao.get_hash_size_and_storage_name(account_id, version_id)
         .map(|n| {
             n.map(|(hash, size, storage_name)| {
                 use std::thread::spawn;
@@ -294,8 +885,8 @@ pub fn list_devices(dao: &Dao, account_id: &str) -> Result<ListDevicesResult, Er
         .map_err(Error::from)
 }
 
-pub fn remove_file_version(repo: &Repo, dao: &Dao, version_id: u64) -> Result<RemoveFileVersionResult, Error> {
-    dao.remove_file_version(version_id)
+pub fn remove_file_version(repo: &Repo, dao: &Dao, account_id: &str, version_id: u64) -> Result<RemoveFileVersionResult, Error> {
+    dao.remove_file_version(account_id, version_id)
         .map_err(Error::from)
         .map(|opt| opt.map(|sn| repo.repo.rm(&sn).map_err(Error::from)))
         .and_then(|r| match r {
@@ -305,8 +896,8 @@ pub fn remove_file_version(repo: &Repo, dao: &Dao, version_id: u64) -> Result<Re
         })
 }
 
-pub fn remove_file(logger: &Logger, repo: &Repo, dao: &Dao, device_id: &str, file_id: u64) -> Result<RemoveFileResult, Error> {
-    dao.remove_file(device_id, file_id)
+pub fn remove_file(logger: &Logger, repo: &Repo, dao: &Dao, account_id: &str, device_id: &str, file_id: u64) -> Result<RemoveFileResult, Error> {
+    dao.remove_file(account_id, device_id, file_id)
         .map(|opt| match opt {
             Some(storage_names) => {
                 let (_, failures): (Vec<_>, Vec<_>) = (&storage_names)
@@ -329,6 +920,273 @@ pub fn remove_file(logger: &Logger, repo: &Repo, dao: &Dao, device_id: &str, fil
         })
 }
 
+pub fn rename_device(logger: &Logger, dao: &Dao, account_id: &str, device_id: &str, name: &str) -> Result<RenameDeviceResult, Error> {
+    dao.rename_device(account_id, device_id, name)
+        .map(|renamed| {
+            if renamed {
+                info!(logger, "Renamed device {} of account {} to '{}'", device_id, account_id, name);
+                RenameDeviceResult::Renamed
+            } else {
+                RenameDeviceResult::NotFound
+            }
+        })
+}
+
+/// Removes another device of the account, optionally with all its files. Returns number of removed files.
+pub fn decommission_device(logger: &Logger, repo: &Repo, dao: &Dao, device: &DeviceIdentity, device_id: &str, delete_files: bool) -> Result<DecommissionDeviceResult, Error> {
+    if device_id == device.id {
+        return Ok(DecommissionDeviceResult::CurrentDevice);
+    }
+
+    if !dao.is_known_device(&device.account_id, device_id)? {
+        return Ok(DecommissionDeviceResult::NotFound);
+    }
+
+    let mut removed_files = 0;
+
+    if delete_files {
+        for file in dao.list_files(&device.account_id, device_id)?.unwrap_or_else(Vec::new) {
+            match remove_file(logger, repo, dao, &device.account_id, device_id, file.id)? {
+                RemoveFileResult::Success | RemoveFileResult::PartialFailure(_) => removed_files += 1,
+                RemoveFileResult::FileNotFound => () // removed in the meantime
+            }
+        }
+    }
+
+    dao.remove_device(&device.account_id, device_id)?;
+
+    info!(logger, "Decommissioned device {} of account {}, removed {} files", device_id, device.account_id, removed_files);
+
+    Ok(DecommissionDeviceResult::Decommissioned(removed_files))
+}
+
+/// Removes the account whose removal is due together with its repository. Returns `false` if there is no such account, or its removal
+/// is not due (e.g. it was cancelled or another purge of it is running).
+pub fn purge_account(logger: &Logger, dao: &Dao, repo_root: &str, account_id: &str, actor: &str) -> Result<bool, Error> {
+    if !dao.claim_account_purge(account_id)? {
+        return Ok(false);
+    }
+
+    // the claim unscheduled the removal, so it has to be scheduled again if anything fails, for the purge to be retried
+    match std::fs::remove_dir_all(format!("{}/{}", repo_root, account_id)) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => debug!(logger, "Repository of account {} doesn't exist", account_id),
+        Err(e) => {
+            reschedule_purge(logger, dao, account_id);
+            return Err(Error::from(e));
+        }
+    }
+
+    match dao.remove_account(account_id, actor) {
+        Ok(removed) => {
+            if removed {
+                info!(logger, "Purged account {}", account_id; "actor" => actor);
+            }
+            Ok(removed)
+        },
+        Err(e) => {
+            reschedule_purge(logger, dao, account_id);
+            Err(e)
+        }
+    }
+}
+
+fn reschedule_purge(logger: &Logger, dao: &Dao, account_id: &str) -> () {
+    if let Err(e) = dao.schedule_account_deletion_now(account_id) {
+        error!(logger, "Could not schedule the purge of account {} again: {}", account_id, e);
+    }
+}
+
+/// Purges accounts whose grace period after the deletion request is over, returns number of purged accounts.
+pub fn purge_deleted_accounts(logger: &Logger, dao: &Dao, repo_root: &str) -> Result<u64, Error> {
+    let mut purged = 0;
+
+    for account_id in dao.find_accounts_to_purge()? {
+        match purge_account(logger, dao, repo_root, &account_id, "maintenance") {
+            Ok(true) => purged += 1,
+            Ok(false) => (), // cancelled or purged in the meantime
+            Err(e) => warn!(logger, "Could not purge account: {}", e; "account_id" => &account_id)
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Removes file versions older than `max_age` of devices without any retention policy; the policies take precedence over the flat
+/// maximal age, so with the global policy configured nothing is removed.
+pub fn remove_old_versions(logger: &Logger, dao: &Dao, repo_root: &str, max_age: chrono::Duration, global_policy: &Option<RetentionPolicy>) -> Result<u64, Error> {
+    if global_policy.is_some() {
+        debug!(logger, "Skipping removal of old file versions, the global retention policy applies");
+        return Ok(0);
+    }
+
+    let threshold = Utc::now().naive_utc() - max_age;
+
+    debug!(logger, "Looking for file versions created before {}", threshold);
+
+    let mut policies = std::collections::HashMap::new();
+    let mut versions = multimap::MultiMap::new();
+
+    for (account_id, device_id, version_id) in dao.find_versions_older_than(&threshold)? {
+        let key = (account_id, device_id);
+
+        if !policies.contains_key(&key) {
+            let policy = find_retention_policy(dao, global_policy, &key.0, Some(&key.1))?;
+            policies.insert(key.clone(), policy.is_some());
+        }
+
+        if !policies[&key] {
+            versions.insert(key.0, version_id);
+        }
+    }
+
+    let mut removed = 0;
+
+    for (account_id, version_ids) in versions {
+        removed += remove_versions(logger, dao, repo_root, &account_id, version_ids);
+    }
+
+    Ok(removed)
+}
+
+pub fn get_retention_policy(dao: &Dao, global_policy: &Option<RetentionPolicy>, account_id: &str, device_id: Option<&str>) -> Result<RetentionPolicyResult, Error> {
+    if !is_account_device(dao, account_id, device_id)? {
+        return Ok(RetentionPolicyResult::DeviceNotFound);
+    }
+
+    find_retention_policy(dao, global_policy, account_id, device_id)
+        .map(|policy| match policy {
+            Some(policy) => RetentionPolicyResult::Success(policy),
+            None => RetentionPolicyResult::NotFound
+        })
+}
+
+pub fn set_retention_policy(dao: &Dao, account_id: &str, device_id: Option<&str>, policy: &RetentionPolicy) -> Result<SetRetentionPolicyResult, Error> {
+    if !is_account_device(dao, account_id, device_id)? {
+        return Ok(SetRetentionPolicyResult::DeviceNotFound);
+    }
+
+    dao.set_retention_policy(account_id, device_id, policy)
+        .map(|_| SetRetentionPolicyResult::Success)
+        .map_err(Error::from)
+}
+
+pub fn remove_retention_policy(dao: &Dao, account_id: &str, device_id: Option<&str>) -> Result<RemoveRetentionPolicyResult, Error> {
+    if !is_account_device(dao, account_id, device_id)? {
+        return Ok(RemoveRetentionPolicyResult::DeviceNotFound);
+    }
+
+    dao.remove_retention_policy(account_id, device_id)
+        .map(|removed| {
+            if removed { RemoveRetentionPolicyResult::Success } else { RemoveRetentionPolicyResult::NotFound }
+        })
+        .map_err(Error::from)
+}
+
+pub fn retention_dry_run(dao: &Dao, global_policy: &Option<RetentionPolicy>, account_id: &str, device_id: Option<&str>) -> Result<RetentionDryRunResult, Error> {
+    if !is_account_device(dao, account_id, device_id)? {
+        return Ok(RetentionDryRunResult::DeviceNotFound);
+    }
+
+    let files = match device_id {
+        Some(device_id) => dao.list_files(account_id, device_id)?.unwrap_or_else(Vec::new),
+        None => dao.list_account_files(account_id)?
+    };
+
+    find_versions_to_prune(dao, global_policy, account_id, files)
+        .map(RetentionDryRunResult::Success)
+}
+
+pub fn apply_retention_policies(logger: &Logger, dao: &Dao, repo_root: &str, global_policy: &Option<RetentionPolicy>) -> Result<u64, Error> {
+    let mut removed = 0;
+
+    for account_id in dao.get_account_ids()? {
+        let version_ids = match dao.list_account_files(&account_id).map_err(Error::from)
+            .and_then(|files| find_versions_to_prune(dao, global_policy, &account_id, files)) {
+            Ok(version_ids) => version_ids,
+            Err(e) => {
+                warn!(logger, "Could not evaluate retention policies: {}", e; "account_id" => &account_id);
+                continue;
+            }
+        };
+
+        if version_ids.is_empty() {
+            continue;
+        }
+
+        debug!(logger, "Pruning {} file versions according to retention policy", version_ids.len(); "account_id" => &account_id);
+
+        removed += remove_versions(logger, dao, repo_root, &account_id, version_ids);
+    }
+
+    Ok(removed)
+}
+
+/// Removes the file versions from the account's repository and from the DB, returns number of removed versions.
+fn remove_versions(logger: &Logger, dao: &Dao, repo_root: &str, account_id: &str, version_ids: Vec<u64>) -> u64 {
+    // the password is not needed for removing the data from the repo
+    let repo = match Repo::new(repo_root, account_id, String::new(), logger) {
+        Ok(repo) => repo,
+        Err(e) => {
+            warn!(logger, "Could not open repository: {}", e; "account_id" => account_id);
+            return 0;
+        }
+    };
+
+    let mut removed = 0;
+
+    for version_id in version_ids {
+        match remove_file_version(&repo, dao, account_id, version_id) {
+            Ok(RemoveFileVersionResult::Success) => removed += 1,
+            Ok(RemoveFileVersionResult::FileNotFound) => (), // removed in the meantime
+            Err(e) => warn!(logger, "Could not remove file version: {}", e; "account_id" => account_id, "version_id" => version_id)
+        }
+    }
+
+    removed
+}
+
+/// Returns `false` if the device is given and it's not a device of the account.
+fn is_account_device(dao: &Dao, account_id: &str, device_id: Option<&str>) -> Result<bool, Error> {
+    match device_id {
+        Some(device_id) => dao.is_known_device(account_id, device_id),
+        None => Ok(true)
+    }
+}
+
+fn find_retention_policy(dao: &Dao, global_policy: &Option<RetentionPolicy>, account_id: &str, device_id: Option<&str>) -> Result<Option<RetentionPolicy>, Error> {
+    let device_policy = match device_id {
+        Some(device_id) => dao.get_retention_policy(account_id, Some(device_id))?,
+        None => None
+    };
+
+    match device_policy {
+        Some(policy) => Ok(Some(policy)),
+        None => dao.get_retention_policy(account_id, None)
+            .map(|policy| policy.or_else(|| global_policy.clone()))
+            .map_err(Error::from)
+    }
+}
+
+fn find_versions_to_prune(dao: &Dao, global_policy: &Option<RetentionPolicy>, account_id: &str, files: Vec<File>) -> Result<Vec<u64>, Error> {
+    let now = Utc::now().naive_utc();
+    let mut policies = std::collections::HashMap::new();
+    let mut version_ids = Vec::new();
+
+    for file in files {
+        if !policies.contains_key(&file.device_id) {
+            let policy = find_retention_policy(dao, global_policy, account_id, Some(&file.device_id))?;
+            policies.insert(file.device_id.clone(), policy);
+        }
+
+        if let Some(Some(policy)) = policies.get(&file.device_id) {
+            version_ids.extend(policy.versions_to_remove(&file.versions, &now));
+        }
+    }
+
+    Ok(version_ids)
+}
+
 fn to_storage_name(pc_id: &str, orig_file_name: &str, time_stamp: Duration) -> String {
     let mut hasher = Sha256::default();
 
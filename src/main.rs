@@ -1,17 +1,22 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
 extern crate cadence;
+extern crate chrono;
 extern crate clap;
 extern crate config;
 extern crate either;
 extern crate failure;
+extern crate job_scheduler;
 extern crate mysql;
 extern crate pipe;
 extern crate rbackup;
 extern crate rdedup_lib as rdedup;
 #[macro_use]
 extern crate rocket;
+extern crate rocket_contrib;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 #[macro_use]
 extern crate slog;
@@ -20,8 +25,11 @@ extern crate slog_stream;
 extern crate slog_term;
 extern crate stopwatch;
 
+use std::net::IpAddr;
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use cadence::StatsdClient;
 use clap::{App, Arg, SubCommand};
@@ -31,13 +39,19 @@ use slog::{Drain, Level, Logger};
 use slog_async::Async;
 use slog_term::{FullFormat, TermDecorator};
 
-use rbackup::dao::Dao;
+use rbackup::dao::{Dao, Database, MySqlDatabase, PostgresDatabase, SqliteDatabase};
 use rbackup::encryptor::Encryptor;
+use rbackup::passwords::{PasswordHasher, PasswordHashingConfig};
+use rbackup::retention::RetentionPolicy;
+use rbackup::sessions::SessionConfig;
+use rbackup::throttling::ThrottlingConfig;
 
+use crate::maintenance::{MaintenanceConfig, UploadSessionConfig};
 use crate::server::*;
 
 mod server;
 mod commands;
+mod maintenance;
 
 struct NoOpSink;
 
@@ -62,7 +76,14 @@ struct GeneralConfig {
 }
 
 #[derive(Debug)]
-pub struct DatabaseConfig {
+pub enum DatabaseConfig {
+    MySql(MySqlConfig),
+    Postgres(PostgresConfig),
+    Sqlite(SqliteConfig)
+}
+
+#[derive(Debug)]
+pub struct MySqlConfig {
     user: String,
     pass: String,
     host: String,
@@ -71,7 +92,7 @@ pub struct DatabaseConfig {
     prefer_socket: bool
 }
 
-impl DatabaseConfig {
+impl MySqlConfig {
     pub fn create_connection_query(&self) -> String {
         format!("mysql://{}:{}@{}:{}?prefer_socket={}",
                 self.user,
@@ -82,6 +103,31 @@ impl DatabaseConfig {
     }
 }
 
+#[derive(Debug)]
+pub struct PostgresConfig {
+    user: String,
+    pass: String,
+    host: String,
+    port: u16,
+    name: String
+}
+
+impl PostgresConfig {
+    pub fn create_connection_url(&self) -> String {
+        format!("postgres://{}:{}@{}:{}/{}",
+                self.user,
+                self.pass,
+                self.host,
+                self.port,
+                self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteConfig {
+    path: String
+}
+
 #[derive(Debug)]
 struct TlsConfig {
     key: String,
@@ -94,7 +140,25 @@ struct ServerConfig {
     port: u16,
     workers: u16,
     tls_config: Option<TlsConfig>,
-    secret: String
+    secret: String,
+    legacy_credentials_in_query: bool,
+    trusted_proxies: Vec<IpAddr>
+}
+
+#[derive(Debug)]
+struct AccountsConfig {
+    deletion_grace_period: chrono::Duration,
+    /// How often are the accounts whose grace period is over looked for.
+    purge_interval: Duration
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            deletion_grace_period: chrono::Duration::days(7),
+            purge_interval: Duration::from_secs(60 * 60)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -102,12 +166,28 @@ struct AppConfig {
     general: GeneralConfig,
     statsd: Option<StatsdConfig>,
     server: ServerConfig,
-    database: DatabaseConfig
+    database: DatabaseConfig,
+    password_hashing: PasswordHashingConfig,
+    sessions: SessionConfig,
+    throttling: ThrottlingConfig,
+    accounts: AccountsConfig,
+    upload_sessions: UploadSessionConfig,
+    maintenance: Option<MaintenanceConfig>,
+    retention: Option<RetentionPolicy>
 }
 
 #[derive(Debug)]
 pub enum AppCommand {
-    DbInit(DatabaseConfig)
+    DbMigrate(DatabaseConfig),
+    DeleteAccount(DeleteAccountCommand)
+}
+
+#[derive(Debug)]
+pub struct DeleteAccountCommand {
+    database: DatabaseConfig,
+    data_dir: String,
+    username: String,
+    action: commands::AccountDeletion
 }
 
 fn exec_command(logger: &Logger, app_command: AppCommand) -> i32 {
@@ -116,9 +196,17 @@ fn exec_command(logger: &Logger, app_command: AppCommand) -> i32 {
     info!(logger, "Executing command: {:?}", app_command);
 
     match app_command {
-        DbInit(db_config) => {
-            init_dao(logger.clone(), None, &db_config)
-                .and_then(|dao| commands::db_init(logger, dao))
+        DbMigrate(db_config) => {
+            init_dao(logger.clone(), None, &db_config, PasswordHashingConfig::default(), SessionConfig::default(), ThrottlingConfig::default())
+                .and_then(|dao| commands::db_migrate(logger, dao))
+                .unwrap_or_else(|err| {
+                    error!(logger, "Error while executing the command: {}", err);
+                    1
+                })
+        },
+        DeleteAccount(command) => {
+            init_dao(logger.clone(), None, &command.database, PasswordHashingConfig::default(), SessionConfig::default(), ThrottlingConfig::default())
+                .and_then(|dao| commands::delete_account(logger, dao, &command.data_dir, &command.username, command.action))
                 .unwrap_or_else(|err| {
                     error!(logger, "Error while executing the command: {}", err);
                     1
@@ -168,8 +256,23 @@ fn get_app_config() -> Result<Either<AppConfig, (Level, AppCommand)>, Error> {
             .value_name("FILE")
             .help("Sets a custom config file (TOML format)")
             .takes_value(true))
+        .subcommand(SubCommand::with_name("dbmigrate")
+            .about("Applies pending DB migrations using provided (or default) config file"))
         .subcommand(SubCommand::with_name("dbinit")
-            .about("Initializes DB using provided (or default) config file"))
+            .about("Deprecated alias for dbmigrate"))
+        .subcommand(SubCommand::with_name("deleteaccount")
+            .about("Schedules deletion of the account after the configured grace period")
+            .arg(Arg::with_name("username")
+                .value_name("USERNAME")
+                .help("Username of the account")
+                .required(true))
+            .arg(Arg::with_name("now")
+                .long("now")
+                .help("Deletes the account with all its data immediately")
+                .conflicts_with("cancel"))
+            .arg(Arg::with_name("cancel")
+                .long("cancel")
+                .help("Cancels the scheduled deletion of the account")))
         .get_matches();
 
     let config_file = matches.value_of("config").unwrap_or("config.toml").to_string();
@@ -182,8 +285,29 @@ fn get_app_config() -> Result<Either<AppConfig, (Level, AppCommand)>, Error> {
         .expect("Wrong format of logging level; allowed 'debug', 'info', 'warn', 'error'");
 
     if let Some(_) = matches.subcommand_matches("dbinit") {
+        println!("The 'dbinit' command is deprecated, use 'dbmigrate' instead");
+    };
+
+    if matches.subcommand_matches("dbmigrate").is_some() || matches.subcommand_matches("dbinit").is_some() {
         return create_database_config(&config)
-            .map(|c| Right((logging_level.clone(), AppCommand::DbInit(c))))
+            .map(|c| Right((logging_level.clone(), AppCommand::DbMigrate(c))))
+    };
+
+    if let Some(matches) = matches.subcommand_matches("deleteaccount") {
+        let action = if matches.is_present("now") {
+            commands::AccountDeletion::Now
+        } else if matches.is_present("cancel") {
+            commands::AccountDeletion::Cancel
+        } else {
+            commands::AccountDeletion::Schedule(create_accounts_config(&config).deletion_grace_period)
+        };
+
+        return Ok(Right((logging_level.clone(), AppCommand::DeleteAccount(DeleteAccountCommand {
+            database: create_database_config(&config)?,
+            data_dir: config.get_str("general.data_dir")?,
+            username: matches.value_of("username").unwrap().to_string(),
+            action
+        }))));
     };
 
     // TODO check permissions to data_dir
@@ -220,25 +344,75 @@ fn get_app_config() -> Result<Either<AppConfig, (Level, AppCommand)>, Error> {
                 } else {
                     None
                 },
-                secret: config.get_str("server.secret")?
+                secret: config.get_str("server.secret")?,
+                legacy_credentials_in_query: config.get_bool("server.legacy_credentials_in_query").unwrap_or(false),
+                trusted_proxies: create_trusted_proxies(&config)?
             },
-            database: create_database_config(&config)?
+            database: create_database_config(&config)?,
+            password_hashing: create_password_hashing_config(&config),
+            sessions: create_session_config(&config),
+            throttling: create_throttling_config(&config),
+            accounts: create_accounts_config(&config),
+            upload_sessions: create_upload_session_config(&config),
+            maintenance: if config.get_table("maintenance").is_ok() {
+                Some(MaintenanceConfig {
+                    cron: config.get_str("maintenance.cron")?,
+                    max_version_age_days: config.get_int("maintenance.max_version_age_days").ok().map(|d| d as u32)
+                })
+            } else {
+                None
+            },
+            retention: if config.get_table("retention").is_ok() {
+                Some(RetentionPolicy {
+                    keep_all_hours: config.get_int("retention.keep_all_hours")? as u32,
+                    keep_daily_days: config.get_int("retention.keep_daily_days")? as u32,
+                    keep_weekly_weeks: config.get_int("retention.keep_weekly_weeks")? as u32
+                })
+            } else {
+                None
+            }
         }
     ))
 }
 
 fn create_database_config(config: &config::Config) -> Result<DatabaseConfig, Error> {
-    Ok(DatabaseConfig {
-        user: config.get_str("database.user")?,
-        pass: config.get_str("database.pass")?,
-        host: config.get_str("database.host")?,
-        port: config.get_int("database.port")? as u16,
-        name: config.get_str("database.name")?,
-        prefer_socket: config.get_bool("database.prefer_socket").unwrap_or(true),
-    })
+    match config.get_str("database.driver").unwrap_or_else(|_| "mysql".to_string()).as_ref() {
+        "mysql" => Ok(DatabaseConfig::MySql(MySqlConfig {
+            user: config.get_str("database.user")?,
+            pass: config.get_str("database.pass")?,
+            host: config.get_str("database.host")?,
+            port: config.get_int("database.port")? as u16,
+            name: config.get_str("database.name")?,
+            prefer_socket: config.get_bool("database.prefer_socket").unwrap_or(true),
+        })),
+        "postgres" => Ok(DatabaseConfig::Postgres(PostgresConfig {
+            user: config.get_str("database.user")?,
+            pass: config.get_str("database.pass")?,
+            host: config.get_str("database.host")?,
+            port: config.get_int("database.port")? as u16,
+            name: config.get_str("database.name")?
+        })),
+        "sqlite" => Ok(DatabaseConfig::Sqlite(SqliteConfig {
+            path: config.get_str("database.path")?
+        })),
+        driver => Err(Error::from(rbackup::failures::CustomError::new(&format!("Unknown database driver '{}'; allowed 'mysql', 'postgres', 'sqlite'", driver))))
+    }
+}
+
+fn create_trusted_proxies(config: &config::Config) -> Result<Vec<IpAddr>, Error> {
+    match config.get_array("server.trusted_proxies") {
+        Ok(proxies) => proxies.into_iter()
+            .map(|proxy| {
+                let proxy = proxy.into_str()?;
+                IpAddr::from_str(&proxy)
+                    .map_err(|_| Error::from(rbackup::failures::CustomError::new(&format!("Invalid trusted proxy address '{}'", proxy))))
+            })
+            .collect(),
+        Err(_) => Ok(Vec::new())
+    }
 }
 
-fn start_server(logger: Logger, config: AppConfig, dao: Dao, statsd_client: StatsdClient) -> () {
+fn start_server(logger: Logger, config: AppConfig, dao: Arc<Dao>, statsd_client: StatsdClient) -> () {
     info!(logger, "Configuring server"; "address" => &config.server.address, "port" => &config.server.port, "workers" => &config.server.workers);
 
     let config_builder = rocket::Config::build(rocket::config::Environment::Production)
@@ -256,35 +430,145 @@ fn start_server(logger: Logger, config: AppConfig, dao: Dao, statsd_client: Stat
         .log_level(rocket::logger::LoggingLevel::Critical)
         .unwrap();
 
-    rocket::custom(rocket_config)
+    let legacy_credentials_in_query = config.server.legacy_credentials_in_query;
+
+    let rocket = rocket::custom(rocket_config)
         .mount("/", routes![status])
         .mount("/", routes![upload])
+        .mount("/", routes![upload_by_hash])
+        .mount("/", routes![create_upload_session])
+        .mount("/", routes![upload_session_status])
+        .mount("/", routes![upload_chunk])
+        .mount("/", routes![finalize_upload_session])
+        .mount("/", routes![abort_upload_session])
         .mount("/", routes![download])
         .mount("/", routes![list_files])
         .mount("/", routes![list_files_for_device])
         .mount("/", routes![list_devices])
         .mount("/", routes![remove_file])
         .mount("/", routes![remove_file_version])
-        .mount("/", routes![login])
-        .mount("/", routes![register])
+        .mount("/", routes![login_json, login_form])
+        .mount("/", routes![register_json, register_form])
+        .mount("/", routes![change_password_json, change_password_form])
+        .mount("/", routes![recover_account_json, recover_account_form])
+        .mount("/", routes![delete_account_json, delete_account_form])
+        .mount("/", routes![cancel_account_deletion])
+        .mount("/", routes![logout])
+        .mount("/", routes![list_sessions])
+        .mount("/", routes![revoke_sessions])
+        .mount("/", routes![create_api_token_json, create_api_token_form])
+        .mount("/", routes![list_api_tokens])
+        .mount("/", routes![revoke_api_token])
+        .mount("/", routes![list_device_roles])
+        .mount("/", routes![set_device_role])
+        .mount("/", routes![rename_device])
+        .mount("/", routes![decommission_device])
+        .mount("/", routes![enroll_totp])
+        .mount("/", routes![confirm_totp_json, confirm_totp_form])
+        .mount("/", routes![disable_totp_json, disable_totp_form])
+        .mount("/", routes![get_retention_policy])
+        .mount("/", routes![set_retention_policy])
+        .mount("/", routes![remove_retention_policy])
+        .mount("/", routes![retention_dry_run])
         .manage(HandlerConfig {
             repo_root: config.general.data_dir,
             dao,
             encryptor: Encryptor::new(config.general.secret),
+            retention_policy: config.retention,
+            account_deletion_grace_period: config.accounts.deletion_grace_period,
+            trusted_proxies: config.server.trusted_proxies,
             logger: logger.new(o!("component" => "server")),
             statsd_client
-        })
-        .launch();
+        });
+
+    let rocket = if legacy_credentials_in_query {
+        warn!(logger, "Deprecated GET login and registration with credentials in the query string are enabled");
+        rocket.mount("/", routes![login_legacy, register_legacy])
+    } else {
+        rocket
+    };
+
+    rocket.launch();
+}
+
+fn create_password_hashing_config(config: &config::Config) -> PasswordHashingConfig {
+    let default = PasswordHashingConfig::default();
+
+    PasswordHashingConfig {
+        memory_cost_kib: config.get_int("password_hashing.memory_cost_kib").map(|v| v as u32).unwrap_or(default.memory_cost_kib),
+        time_cost: config.get_int("password_hashing.time_cost").map(|v| v as u32).unwrap_or(default.time_cost),
+        parallelism: config.get_int("password_hashing.parallelism").map(|v| v as u32).unwrap_or(default.parallelism)
+    }
+}
+
+fn create_session_config(config: &config::Config) -> SessionConfig {
+    let default = SessionConfig::default();
+
+    SessionConfig {
+        max_lifetime: config.get_int("sessions.max_lifetime_hours").map(chrono::Duration::hours).unwrap_or(default.max_lifetime),
+        idle_timeout: config.get_int("sessions.idle_timeout_hours").map(chrono::Duration::hours).unwrap_or(default.idle_timeout),
+        last_used_flush_interval: config.get_int("sessions.last_used_flush_secs").map(|s| Duration::from_secs(s as u64)).unwrap_or(default.last_used_flush_interval),
+        sweep_interval: config.get_int("sessions.sweep_interval_mins").map(|m| Duration::from_secs(m as u64 * 60)).unwrap_or(default.sweep_interval),
+        multiple_per_device: config.get_bool("sessions.multiple_per_device").unwrap_or(default.multiple_per_device)
+    }
 }
 
-fn init_dao(logger: Logger, statsd_client: Option<StatsdClient>, config: &DatabaseConfig) -> Result<Dao, Error> {
-    info!(logger, "Connecting to DB"; "host" => &config.host, "port" => &config.port, "database" => &config.name);
+fn create_throttling_config(config: &config::Config) -> ThrottlingConfig {
+    let default = ThrottlingConfig::default();
+
+    ThrottlingConfig {
+        username_free_failures: config.get_int("throttling.username_free_failures").map(|v| v as u32).unwrap_or(default.username_free_failures),
+        ip_free_failures: config.get_int("throttling.ip_free_failures").map(|v| v as u32).unwrap_or(default.ip_free_failures),
+        base_delay: config.get_int("throttling.base_delay_secs").map(chrono::Duration::seconds).unwrap_or(default.base_delay),
+        max_delay: config.get_int("throttling.max_delay_mins").map(chrono::Duration::minutes).unwrap_or(default.max_delay),
+        reset_after: config.get_int("throttling.reset_after_hours").map(chrono::Duration::hours).unwrap_or(default.reset_after),
+        registrations_per_ip: config.get_int("throttling.registrations_per_ip").map(|v| v as u32).unwrap_or(default.registrations_per_ip),
+        registration_window: config.get_int("throttling.registration_window_mins").map(chrono::Duration::minutes).unwrap_or(default.registration_window)
+    }
+}
+
+fn create_accounts_config(config: &config::Config) -> AccountsConfig {
+    let default = AccountsConfig::default();
+
+    AccountsConfig {
+        deletion_grace_period: config.get_int("accounts.deletion_grace_period_hours").map(chrono::Duration::hours).unwrap_or(default.deletion_grace_period),
+        purge_interval: config.get_int("accounts.purge_interval_mins").map(|m| Duration::from_secs(m as u64 * 60)).unwrap_or(default.purge_interval)
+    }
+}
+
+fn create_upload_session_config(config: &config::Config) -> UploadSessionConfig {
+    let default = UploadSessionConfig::default();
+
+    UploadSessionConfig {
+        // the timeout used to be configured in the maintenance section
+        timeout: config.get_int("uploads.session_timeout_hours")
+            .or_else(|_| config.get_int("maintenance.upload_session_timeout_hours"))
+            .map(chrono::Duration::hours)
+            .unwrap_or(default.timeout),
+        cleanup_interval: config.get_int("uploads.cleanup_interval_mins").map(|m| Duration::from_secs(m as u64 * 60)).unwrap_or(default.cleanup_interval)
+    }
+}
+
+fn init_dao(logger: Logger, statsd_client: Option<StatsdClient>, config: &DatabaseConfig, password_hashing: PasswordHashingConfig, sessions: SessionConfig, throttling: ThrottlingConfig) -> Result<Dao, Error> {
+    let db: Box<dyn Database> = match config {
+        DatabaseConfig::MySql(config) => {
+            info!(logger, "Connecting to MySQL DB"; "host" => &config.host, "port" => &config.port, "database" => &config.name);
+
+            Box::new(MySqlDatabase::new(&config.create_connection_query(), &config.name)?)
+        },
+        DatabaseConfig::Postgres(config) => {
+            info!(logger, "Connecting to PostgreSQL DB"; "host" => &config.host, "port" => &config.port, "database" => &config.name);
+
+            Box::new(PostgresDatabase::new(&config.create_connection_url())?)
+        },
+        DatabaseConfig::Sqlite(config) => {
+            info!(logger, "Opening SQLite DB"; "path" => &config.path);
 
-    Dao::new(&config.create_connection_query(),
-             &config.name,
-             logger,
-             statsd_client
-    )
+            Box::new(SqliteDatabase::new(&config.path)?)
+        }
+    };
+
+    Ok(Dao::new(db, PasswordHasher::new(password_hashing)?, sessions, throttling, logger, statsd_client))
 }
 
 fn create_statsd_client(logger: Logger, config: &Option<StatsdConfig>) -> Result<StatsdClient, Error> {
@@ -320,7 +604,7 @@ fn create_statsd_client(logger: Logger, config: &Option<StatsdConfig>) -> Result
 fn main() {
     println!("RBackup server {}", rbackup::APP_VERSION);
 
-    let app_config = match get_app_config() {
+    let mut app_config = match get_app_config() {
         Ok(Left(app_config)) => app_config,
         Ok(Right((logging_level, command))) => {
             let logger = init_logger(logging_level);
@@ -349,11 +633,50 @@ fn main() {
             exit(1);
         });
 
-    let dao = init_dao(logger.clone(), Some(statsd_client.clone()), &app_config.database)
+    let dao = init_dao(logger.clone(), Some(statsd_client.clone()), &app_config.database, app_config.password_hashing.clone(), app_config.sessions.clone(), app_config.throttling.clone())
+        .map(Arc::new)
         .unwrap_or_else(|e| {
             println!("Could not initialize connection to DB: {}", e);
             exit(1);
         });
 
+    match rbackup::migrations::pending_migrations(&dao) {
+        Ok(ref pending) if pending.is_empty() => (),
+        Ok(pending) => {
+            println!("The DB schema is outdated ({} pending migrations), run the 'dbmigrate' command first", pending.len());
+            exit(1);
+        },
+        Err(e) => {
+            println!("Could not check the DB schema version: {}", e);
+            exit(1);
+        }
+    }
+
+    if let Some(maintenance_config) = app_config.maintenance.take() {
+        maintenance::start(logger.new(o!("component" => "maintenance")), maintenance_config, app_config.retention.clone(), app_config.general.data_dir.clone(), dao.clone())
+            .unwrap_or_else(|e| {
+                println!("Could not start maintenance: {}", e);
+                exit(1);
+            });
+    }
+
+    maintenance::start_session_housekeeping(logger.new(o!("component" => "sessions")), app_config.sessions.clone(), dao.clone())
+        .unwrap_or_else(|e| {
+            println!("Could not start session housekeeping: {}", e);
+            exit(1);
+        });
+
+    maintenance::start_upload_session_cleanup(logger.new(o!("component" => "uploads")), app_config.upload_sessions.clone(), app_config.general.data_dir.clone(), dao.clone())
+        .unwrap_or_else(|e| {
+            println!("Could not start upload session cleanup: {}", e);
+            exit(1);
+        });
+
+    maintenance::start_account_purge(logger.new(o!("component" => "accounts")), app_config.accounts.purge_interval, app_config.general.data_dir.clone(), dao.clone())
+        .unwrap_or_else(|e| {
+            println!("Could not start account purge: {}", e);
+            exit(1);
+        });
+
     start_server(logger, app_config, dao, statsd_client)
 }
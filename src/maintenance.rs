@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use failure::Error;
+use job_scheduler::{Job, JobScheduler, Schedule};
+use slog::Logger;
+
+use rbackup;
+use rbackup::dao::Dao;
+use rbackup::failures::CustomError;
+use rbackup::retention::RetentionPolicy;
+use rbackup::sessions::SessionConfig;
+
+#[derive(Debug)]
+pub struct MaintenanceConfig {
+    pub cron: String,
+    pub max_version_age_days: Option<u32>
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadSessionConfig {
+    /// Upload sessions without any activity for this time are removed.
+    pub timeout: chrono::Duration,
+    /// How often are the abandoned upload sessions looked for.
+    pub cleanup_interval: std::time::Duration
+}
+
+impl Default for UploadSessionConfig {
+    fn default() -> Self {
+        UploadSessionConfig {
+            timeout: chrono::Duration::hours(24),
+            cleanup_interval: std::time::Duration::from_secs(60 * 60)
+        }
+    }
+}
+
+pub fn start(logger: Logger, config: MaintenanceConfig, retention_policy: Option<RetentionPolicy>, repo_root: String, dao: Arc<Dao>) -> Result<(), Error> {
+    let schedule: Schedule = config.cron.parse()
+        .map_err(|e| Error::from(CustomError::new(&format!("Invalid maintenance cron expression '{}': {}", config.cron, e))))?;
+
+    info!(logger, "Starting maintenance scheduler"; "cron" => &config.cron, "max_version_age_days" => config.max_version_age_days, "retention_policy" => ?retention_policy);
+
+    if config.max_version_age_days.is_some() && retention_policy.is_some() {
+        warn!(logger, "The max_version_age_days is ignored, the global retention policy applies to all devices");
+    }
+
+    thread::Builder::new()
+        .name("maintenance".to_string())
+        .spawn(move || {
+            let mut scheduler = JobScheduler::new();
+
+            scheduler.add(Job::new(schedule, || {
+                run(&logger, &config, &retention_policy, &repo_root, &dao)
+            }));
+
+            loop {
+                scheduler.tick();
+                thread::sleep(scheduler.time_till_next_job());
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Starts the thread flushing the `last_used` time of sessions and removing the expired ones, together with stale login throttling
+/// counters. Unlike the rest of the maintenance, it always runs.
+pub fn start_session_housekeeping(logger: Logger, config: SessionConfig, dao: Arc<Dao>) -> Result<(), Error> {
+    info!(logger, "Starting session housekeeping"; "flush_interval" => ?config.last_used_flush_interval, "sweep_interval" => ?config.sweep_interval);
+
+    thread::Builder::new()
+        .name("session-housekeeping".to_string())
+        .spawn(move || {
+            let mut last_sweep = Instant::now();
+
+            loop {
+                thread::sleep(config.last_used_flush_interval);
+
+                if let Err(e) = dao.flush_session_activity() {
+                    error!(logger, "Error while saving activity of sessions: {}", e);
+                }
+
+                if last_sweep.elapsed() >= config.sweep_interval {
+                    last_sweep = Instant::now();
+
+                    match dao.remove_expired_sessions() {
+                        Ok(removed) => debug!(logger, "Removed {} expired sessions", removed),
+                        Err(e) => error!(logger, "Error while removing expired sessions: {}", e)
+                    }
+
+                    match dao.remove_stale_throttling() {
+                        Ok(removed) => debug!(logger, "Removed {} stale throttling counters", removed),
+                        Err(e) => error!(logger, "Error while removing stale throttling counters: {}", e)
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Starts the thread removing abandoned upload sessions together with their chunks. Unlike the rest of the maintenance, it always runs.
+pub fn start_upload_session_cleanup(logger: Logger, config: UploadSessionConfig, repo_root: String, dao: Arc<Dao>) -> Result<(), Error> {
+    info!(logger, "Starting upload session cleanup"; "timeout" => ?config.timeout, "cleanup_interval" => ?config.cleanup_interval);
+
+    thread::Builder::new()
+        .name("upload-session-cleanup".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(config.cleanup_interval);
+
+                match rbackup::remove_abandoned_upload_sessions(&logger, &dao, &repo_root, config.timeout) {
+                    Ok(removed) => info!(logger, "Removed {} abandoned upload sessions", removed),
+                    Err(e) => error!(logger, "Error while removing abandoned upload sessions: {}", e)
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Starts the thread purging accounts whose deletion grace period is over. Unlike the rest of the maintenance, it always runs.
+pub fn start_account_purge(logger: Logger, purge_interval: std::time::Duration, repo_root: String, dao: Arc<Dao>) -> Result<(), Error> {
+    info!(logger, "Starting account purge"; "purge_interval" => ?purge_interval);
+
+    thread::Builder::new()
+        .name("account-purge".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(purge_interval);
+
+                match rbackup::purge_deleted_accounts(&logger, &dao, &repo_root) {
+                    Ok(purged) => debug!(logger, "Purged {} deleted accounts", purged),
+                    Err(e) => error!(logger, "Error while purging deleted accounts: {}", e)
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn run(logger: &Logger, config: &MaintenanceConfig, retention_policy: &Option<RetentionPolicy>, repo_root: &str, dao: &Dao) -> () {
+    info!(logger, "Running maintenance");
+
+    if let Some(max_version_age_days) = config.max_version_age_days {
+        let max_age = chrono::Duration::days(max_version_age_days as i64);
+
+        match rbackup::remove_old_versions(logger, dao, repo_root, max_age, retention_policy) {
+            Ok(removed) => info!(logger, "Removed {} old file versions", removed),
+            Err(e) => error!(logger, "Error while removing old file versions: {}", e)
+        }
+    }
+
+    match rbackup::apply_retention_policies(logger, dao, repo_root, retention_policy) {
+        Ok(removed) => info!(logger, "Removed {} file versions according to retention policies", removed),
+        Err(e) => error!(logger, "Error while applying retention policies: {}", e)
+    }
+
+    info!(logger, "Maintenance finished");
+}
@@ -0,0 +1,235 @@
+use failure::Error;
+use slog::Logger;
+
+use crate::dao::Dao;
+
+/// Database migration embedded in the binary. Each backend has its own set of migrations; versions of migrations doing the same
+/// change are kept aligned across the backends.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub script: &'static str
+}
+
+/// MySQL migrations, ordered by their version. The `DBNAME` placeholder in the script is replaced by the name of the configured database.
+/// Never change an already released migration, add a new one instead.
+pub const MYSQL_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial",
+        script: include_str!("../resources/migrations/mysql/001_initial.sql")
+    },
+    Migration {
+        version: 2,
+        description: "retention policies",
+        script: include_str!("../resources/migrations/mysql/002_retention_policies.sql")
+    },
+    Migration {
+        version: 3,
+        description: "upload sessions",
+        script: include_str!("../resources/migrations/mysql/003_upload_sessions.sql")
+    },
+    Migration {
+        version: 4,
+        description: "large files",
+        script: include_str!("../resources/migrations/mysql/004_large_files.sql")
+    },
+    Migration {
+        version: 5,
+        description: "password hashes",
+        script: include_str!("../resources/migrations/mysql/005_password_hashes.sql")
+    },
+    Migration {
+        version: 6,
+        description: "repo secrets",
+        script: include_str!("../resources/migrations/mysql/006_repo_secrets.sql")
+    },
+    Migration {
+        version: 7,
+        description: "recovery keys",
+        script: include_str!("../resources/migrations/mysql/007_recovery_keys.sql")
+    },
+    Migration {
+        version: 8,
+        description: "throttling",
+        script: include_str!("../resources/migrations/mysql/008_throttling.sql")
+    },
+    Migration {
+        version: 9,
+        description: "TOTP",
+        script: include_str!("../resources/migrations/mysql/009_totp.sql")
+    },
+    Migration {
+        version: 10,
+        description: "API tokens",
+        script: include_str!("../resources/migrations/mysql/010_api_tokens.sql")
+    },
+    Migration {
+        version: 11,
+        description: "device roles",
+        script: include_str!("../resources/migrations/mysql/011_device_roles.sql")
+    },
+    Migration {
+        version: 12,
+        description: "devices",
+        script: include_str!("../resources/migrations/mysql/012_devices.sql")
+    },
+    Migration {
+        version: 13,
+        description: "account deletion",
+        script: include_str!("../resources/migrations/mysql/013_account_deletion.sql")
+    },
+];
+
+/// SQLite migrations, ordered by their version. Never change an already released migration, add a new one instead.
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial",
+        script: include_str!("../resources/migrations/sqlite/001_initial.sql")
+    },
+    Migration {
+        version: 2,
+        description: "retention policies",
+        script: include_str!("../resources/migrations/sqlite/002_retention_policies.sql")
+    },
+    Migration {
+        version: 3,
+        description: "upload sessions",
+        script: include_str!("../resources/migrations/sqlite/003_upload_sessions.sql")
+    },
+    // version 4 (large files) is not needed, SQLite integers are 64-bit
+    // version 5 (password hashes) is not needed, SQLite doesn't enforce length of varchar
+    Migration {
+        version: 6,
+        description: "repo secrets",
+        script: include_str!("../resources/migrations/sqlite/006_repo_secrets.sql")
+    },
+    Migration {
+        version: 7,
+        description: "recovery keys",
+        script: include_str!("../resources/migrations/sqlite/007_recovery_keys.sql")
+    },
+    Migration {
+        version: 8,
+        description: "throttling",
+        script: include_str!("../resources/migrations/sqlite/008_throttling.sql")
+    },
+    Migration {
+        version: 9,
+        description: "TOTP",
+        script: include_str!("../resources/migrations/sqlite/009_totp.sql")
+    },
+    Migration {
+        version: 10,
+        description: "API tokens",
+        script: include_str!("../resources/migrations/sqlite/010_api_tokens.sql")
+    },
+    Migration {
+        version: 11,
+        description: "device roles",
+        script: include_str!("../resources/migrations/sqlite/011_device_roles.sql")
+    },
+    Migration {
+        version: 12,
+        description: "devices",
+        script: include_str!("../resources/migrations/sqlite/012_devices.sql")
+    },
+    Migration {
+        version: 13,
+        description: "account deletion",
+        script: include_str!("../resources/migrations/sqlite/013_account_deletion.sql")
+    },
+];
+
+/// PostgreSQL migrations, ordered by their version. Never change an already released migration, add a new one instead.
+pub const POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial",
+        script: include_str!("../resources/migrations/postgres/001_initial.sql")
+    },
+    Migration {
+        version: 2,
+        description: "retention policies",
+        script: include_str!("../resources/migrations/postgres/002_retention_policies.sql")
+    },
+    Migration {
+        version: 3,
+        description: "upload sessions",
+        script: include_str!("../resources/migrations/postgres/003_upload_sessions.sql")
+    },
+    // version 4 (large files) is not needed, the sizes are `bigint` from the beginning
+    Migration {
+        version: 5,
+        description: "password hashes",
+        script: include_str!("../resources/migrations/postgres/005_password_hashes.sql")
+    },
+    Migration {
+        version: 6,
+        description: "repo secrets",
+        script: include_str!("../resources/migrations/postgres/006_repo_secrets.sql")
+    },
+    Migration {
+        version: 7,
+        description: "recovery keys",
+        script: include_str!("../resources/migrations/postgres/007_recovery_keys.sql")
+    },
+    Migration {
+        version: 8,
+        description: "throttling",
+        script: include_str!("../resources/migrations/postgres/008_throttling.sql")
+    },
+    Migration {
+        version: 9,
+        description: "TOTP",
+        script: include_str!("../resources/migrations/postgres/009_totp.sql")
+    },
+    Migration {
+        version: 10,
+        description: "API tokens",
+        script: include_str!("../resources/migrations/postgres/010_api_tokens.sql")
+    },
+    Migration {
+        version: 11,
+        description: "device roles",
+        script: include_str!("../resources/migrations/postgres/011_device_roles.sql")
+    },
+    Migration {
+        version: 12,
+        description: "devices",
+        script: include_str!("../resources/migrations/postgres/012_devices.sql")
+    },
+    Migration {
+        version: 13,
+        description: "account deletion",
+        script: include_str!("../resources/migrations/postgres/013_account_deletion.sql")
+    },
+];
+
+pub fn latest_version(dao: &Dao) -> u32 {
+    dao.migrations().last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn pending_migrations(dao: &Dao) -> Result<Vec<&'static Migration>, Error> {
+    let current_version = dao.get_schema_version()?;
+
+    Ok(dao.migrations().iter().filter(|m| m.version > current_version).collect())
+}
+
+pub fn migrate(logger: &Logger, dao: &Dao) -> Result<usize, Error> {
+    let pending = pending_migrations(dao)?;
+
+    if pending.is_empty() {
+        info!(logger, "Database schema is up to date"; "version" => latest_version(dao));
+        return Ok(0);
+    }
+
+    for migration in pending.iter() {
+        info!(logger, "Applying DB migration"; "version" => migration.version, "description" => migration.description);
+
+        dao.apply_migration(migration)?;
+    }
+
+    Ok(pending.len())
+}
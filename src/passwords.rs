@@ -0,0 +1,194 @@
+use argon2::{Config, ThreadMode, Variant, Version};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::util::fixed_time_eq;
+use failure::Error;
+use crate::failures::CustomError;
+use hex;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Cost parameters of the Argon2id password hashing.
+#[derive(Debug, Clone)]
+pub struct PasswordHashingConfig {
+    pub memory_cost_kib: u32,
+    pub time_cost: u32,
+    pub parallelism: u32
+}
+
+impl Default for PasswordHashingConfig {
+    fn default() -> PasswordHashingConfig {
+        PasswordHashingConfig {
+            memory_cost_kib: 19456,
+            time_cost: 2,
+            parallelism: 1
+        }
+    }
+}
+
+/// Secret of the account's repository encrypted by a key derived from the login password. Both parts are hex encoded.
+#[derive(Debug, Clone)]
+pub struct WrappedSecret {
+    pub salt: String,
+    pub secret: String
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordVerification {
+    Valid { needs_rehash: bool },
+    Invalid
+}
+
+pub struct PasswordHasher {
+    config: PasswordHashingConfig,
+    /// Hash of a random password, verified instead of a missing one so that unknown usernames take as long as the known ones.
+    dummy_hash: String
+}
+
+impl PasswordHasher {
+    pub fn new(config: PasswordHashingConfig) -> Result<PasswordHasher, Error> {
+        let mut hasher = PasswordHasher { config, dummy_hash: String::new() };
+        hasher.dummy_hash = hasher.hash(&random_hex())?;
+
+        Ok(hasher)
+    }
+
+    /// Hashes the password with a random salt; the result contains all the parameters needed for the verification.
+    pub fn hash(&self, pass: &str) -> Result<String, Error> {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+
+        argon2::hash_encoded(pass.as_bytes(), &salt, &self.argon2_config())
+            .map_err(Error::from)
+    }
+
+    /// Verifies the password against the stored hash. Hashes created by older versions (unsalted SHA-256) or with different cost
+    /// parameters are valid but should be replaced by a new hash.
+    pub fn verify(&self, stored_hash: &str, pass: &str) -> Result<PasswordVerification, Error> {
+        if stored_hash.starts_with("$argon2") {
+            let valid = argon2::verify_encoded(stored_hash, pass.as_bytes())?;
+
+            Ok(if valid {
+                PasswordVerification::Valid { needs_rehash: !stored_hash.starts_with(&self.encoded_prefix()) }
+            } else {
+                PasswordVerification::Invalid
+            })
+        } else {
+            let legacy_hash = {
+                let mut hasher = Sha256::new();
+                hasher.input(pass.as_bytes());
+                hex::encode(&hasher.result())
+            };
+
+            Ok(if fixed_time_eq(legacy_hash.as_bytes(), stored_hash.as_bytes()) {
+                PasswordVerification::Valid { needs_rehash: true }
+            } else {
+                PasswordVerification::Invalid
+            })
+        }
+    }
+
+    /// Verifies the password against a hash no password matches, with the same cost as the verification of an existing account.
+    pub fn verify_dummy(&self, pass: &str) -> Result<(), Error> {
+        argon2::verify_encoded(&self.dummy_hash, pass.as_bytes())
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn argon2_config(&self) -> Config {
+        Config {
+            variant: Variant::Argon2id,
+            version: Version::Version13,
+            mem_cost: self.config.memory_cost_kib,
+            time_cost: self.config.time_cost,
+            lanes: self.config.parallelism,
+            thread_mode: ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: 32
+        }
+    }
+
+    fn encoded_prefix(&self) -> String {
+        format!("$argon2id$v=19$m={},t={},p={}$", self.config.memory_cost_kib, self.config.time_cost, self.config.parallelism)
+    }
+}
+
+/// Generates a random secret for a new repository.
+pub fn generate_repo_secret() -> String {
+    random_hex()
+}
+
+/// Generates a random recovery key. It's shown to the user only once, the server stores just the repo secret wrapped by it.
+pub fn generate_recovery_key() -> String {
+    random_hex()
+}
+
+/// Encrypts the repo secret by AES-GCM with a key derived from the password and a fresh random salt.
+pub fn wrap_secret(pass: &str, repo_secret: &str) -> Result<WrappedSecret, Error> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    let nonce: [u8; 12] = rand::thread_rng().gen();
+    let key = derive_key(pass, &salt)?;
+
+    let data = repo_secret.as_bytes();
+    let mut encrypted = vec![0u8; data.len()];
+    let mut tag = [0u8; 16];
+
+    AesGcm::new(KeySize::KeySize256, &key, &nonce, &[]).encrypt(data, &mut encrypted, &mut tag);
+
+    let mut secret = nonce.to_vec();
+    secret.extend_from_slice(&encrypted);
+    secret.extend_from_slice(&tag);
+
+    Ok(WrappedSecret {
+        salt: hex::encode(&salt),
+        secret: hex::encode(&secret)
+    })
+}
+
+/// Decrypts the repo secret; returns `None` if the password doesn't match.
+pub fn unwrap_secret(pass: &str, wrapped: &WrappedSecret) -> Result<Option<String>, Error> {
+    let salt = hex::decode(&wrapped.salt)?;
+    let secret = hex::decode(&wrapped.secret)?;
+
+    if secret.len() < 12 + 16 {
+        return Err(Error::from(CustomError::new("Wrapped repo secret is too short")));
+    }
+
+    let (nonce, rest) = secret.split_at(12);
+    let (encrypted, tag) = rest.split_at(rest.len() - 16);
+    let key = derive_key(pass, &salt)?;
+
+    let mut decrypted = vec![0u8; encrypted.len()];
+
+    if AesGcm::new(KeySize::KeySize256, &key, nonce, &[]).decrypt(encrypted, &mut decrypted, tag) {
+        Ok(Some(String::from_utf8(decrypted)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn random_hex() -> String {
+    let random_bytes: [u8; 32] = rand::thread_rng().gen();
+    hex::encode(&random_bytes)
+}
+
+/// The key derivation intentionally doesn't follow the configured cost parameters - the secret must be possible to unwrap even after
+/// they were changed.
+fn derive_key(pass: &str, salt: &[u8]) -> Result<Vec<u8>, Error> {
+    let params = PasswordHashingConfig::default();
+
+    let config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: params.memory_cost_kib,
+        time_cost: params.time_cost,
+        lanes: params.parallelism,
+        thread_mode: ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: 32
+    };
+
+    argon2::hash_raw(pass.as_bytes(), salt, &config).map_err(Error::from)
+}
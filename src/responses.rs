@@ -7,6 +7,8 @@ use rocket::request::Request;
 use rocket::response::{Responder, Response};
 use rocket::response::status::Custom as CustomStatus;
 use std::io::{Cursor, Error as IoError};
+use chrono::NaiveDateTime;
+use crate::retention::RetentionPolicy;
 use crate::structs::*;
 
 #[derive(Serialize)]
@@ -16,14 +18,104 @@ pub struct StatusResult {
 }
 
 pub enum RegisterResult {
-    Created(String),
-    Exists
+    Created(String, Option<String>),
+    Exists,
+    /// Blocked by the throttling, contains seconds to wait.
+    TooManyAttempts(i64)
+}
+
+pub enum RecoverAccountResult {
+    /// Contains the new recovery key, the used one is not valid anymore.
+    Recovered(String),
+    InvalidRecoveryKey,
+    /// Blocked by the throttling, contains seconds to wait.
+    TooManyAttempts(i64)
 }
 
 pub enum LoginResult {
     NewSession(String),
     RenewedSession(String),
-    AccountNotFound
+    AccountNotFound,
+    /// The account has the TOTP enabled and the code was not provided.
+    SecondFactorRequired,
+    InvalidSecondFactor,
+    /// Blocked by the throttling, contains seconds to wait.
+    TooManyAttempts(i64)
+}
+
+pub enum EnrollTotpResult {
+    /// Contains the secret (base32) and the `otpauth://` URI.
+    Enrolled(String, String),
+    AlreadyEnabled
+}
+
+pub enum ConfirmTotpResult {
+    /// Contains the backup codes.
+    Enabled(Vec<String>),
+    NotEnrolled,
+    InvalidCode
+}
+
+pub enum DisableTotpResult {
+    Disabled,
+    NotEnabled,
+    InvalidCredentials
+}
+
+pub enum RequestAccountDeletionResult {
+    /// Contains the time after which the account is removed.
+    Scheduled(NaiveDateTime),
+    AlreadyScheduled,
+    InvalidPassword
+}
+
+pub enum CancelAccountDeletionResult {
+    Cancelled,
+    NotScheduled
+}
+
+pub enum ChangePasswordResult {
+    Changed(String),
+    InvalidPassword
+}
+
+pub enum LogoutResult {
+    Success,
+    SessionNotFound
+}
+
+pub enum ListSessionsResult {
+    Success(Vec<SessionInfo>)
+}
+
+pub enum RevokeSessionsResult {
+    Success(u64),
+    NotFound,
+    InvalidRequest(String)
+}
+
+pub enum CreateApiTokenResult {
+    /// Contains the token and its ID.
+    Created(String, String),
+    DeviceNotFound
+}
+
+pub enum ListApiTokensResult {
+    Success(Vec<ApiTokenInfo>)
+}
+
+pub enum RevokeApiTokenResult {
+    Revoked,
+    NotFound
+}
+
+pub enum ListDeviceRolesResult {
+    Success(Vec<DeviceRoleInfo>)
+}
+
+pub enum SetDeviceRoleResult {
+    Updated,
+    CurrentDevice
 }
 
 pub enum UploadedData {
@@ -33,8 +125,20 @@ pub enum UploadedData {
 
 pub enum UploadResult {
     Success(File),
+    Unchanged(File),
     InvalidRequest(String),
-    MismatchSha256
+    MismatchSha256,
+    ContentNotFound
+}
+
+pub enum UploadSessionResult {
+    Created(String),
+    Progress(u64),
+    Finished(UploadResult),
+    Aborted,
+    ChunkMismatchSha256,
+    InvalidRequest(String),
+    NotFound
 }
 
 pub enum ListFileResult {
@@ -43,7 +147,18 @@ pub enum ListFileResult {
 }
 
 pub enum ListDevicesResult {
-    Success(Vec<String>)
+    Success(Vec<Device>)
+}
+
+pub enum RenameDeviceResult {
+    Renamed,
+    NotFound
+}
+
+pub enum DecommissionDeviceResult {
+    Decommissioned(u64),
+    NotFound,
+    CurrentDevice
 }
 
 pub enum RemoveFileResult {
@@ -57,6 +172,28 @@ pub enum RemoveFileVersionResult {
     FileNotFound
 }
 
+pub enum RetentionPolicyResult {
+    Success(RetentionPolicy),
+    NotFound,
+    DeviceNotFound
+}
+
+pub enum SetRetentionPolicyResult {
+    Success,
+    DeviceNotFound
+}
+
+pub enum RemoveRetentionPolicyResult {
+    Success,
+    NotFound,
+    DeviceNotFound
+}
+
+pub enum RetentionDryRunResult {
+    Success(Vec<u64>),
+    DeviceNotFound
+}
+
 impl<'r> Responder<'r> for StatusResult {
     fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
         serde_json::to_string(&self)
@@ -73,16 +210,23 @@ impl<'r> Responder<'r> for StatusResult {
 impl<'r> Responder<'r> for RegisterResult {
     fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
         match self {
-            RegisterResult::Created(account_id) =>
+            RegisterResult::Created(account_id, None) =>
                 Response::build()
                     .status(Status::Created)
                     .sized_body(Cursor::new(format!("{{\"account_id\": \"{}\"}}", account_id)))
                     .header(ContentType::JSON)
                     .ok(),
+            RegisterResult::Created(account_id, Some(recovery_key)) =>
+                Response::build()
+                    .status(Status::Created)
+                    .sized_body(Cursor::new(format!("{{\"account_id\": \"{}\", \"recovery_key\": \"{}\"}}", account_id, recovery_key)))
+                    .header(ContentType::JSON)
+                    .ok(),
             RegisterResult::Exists =>
                 Response::build()
                     .status(Status::Conflict)
                     .ok(),
+            RegisterResult::TooManyAttempts(retry_after) => too_many_attempts(retry_after),
         }
     }
 }
@@ -105,6 +249,292 @@ impl<'r> Responder<'r> for LoginResult {
             LoginResult::AccountNotFound =>
                 Response::build()
                     .status(Status::Unauthorized)
+                    .ok(),
+            LoginResult::SecondFactorRequired =>
+                Response::build()
+                    .status(Status::Unauthorized)
+                    .sized_body(Cursor::new("{\"second_factor_required\": \"totp\"}"))
+                    .header(ContentType::JSON)
+                    .ok(),
+            LoginResult::InvalidSecondFactor =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid second factor code"))
+                    .ok(),
+            LoginResult::TooManyAttempts(retry_after) => too_many_attempts(retry_after)
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RecoverAccountResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RecoverAccountResult::Recovered(recovery_key) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"recovery_key\": \"{}\"}}", recovery_key)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            RecoverAccountResult::InvalidRecoveryKey =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid recovery key"))
+                    .ok(),
+            RecoverAccountResult::TooManyAttempts(retry_after) => too_many_attempts(retry_after)
+        }
+    }
+}
+
+impl<'r> Responder<'r> for EnrollTotpResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            EnrollTotpResult::Enrolled(secret, uri) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"secret\": \"{}\", \"uri\": \"{}\"}}", secret, uri)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            EnrollTotpResult::AlreadyEnabled =>
+                Response::build()
+                    .status(Status::Conflict)
+                    .sized_body(Cursor::new("TOTP is already enabled"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ConfirmTotpResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            ConfirmTotpResult::Enabled(backup_codes) =>
+                serde_json::to_string(&backup_codes)
+                    .map(|codes| format!("{{\"backup_codes\": {}}}", codes))
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+            ConfirmTotpResult::NotEnrolled =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("There is no pending TOTP enrollment"))
+                    .ok(),
+            ConfirmTotpResult::InvalidCode =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid code"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for DisableTotpResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            DisableTotpResult::Disabled =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            DisableTotpResult::NotEnabled =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("TOTP is not enabled"))
+                    .ok(),
+            DisableTotpResult::InvalidCredentials =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid password or code"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ChangePasswordResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            ChangePasswordResult::Changed(session_id) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"session_id\": \"{}\"}}", session_id)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            ChangePasswordResult::InvalidPassword =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid password"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RequestAccountDeletionResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RequestAccountDeletionResult::Scheduled(purge_after) =>
+                Response::build()
+                    .status(Status::Accepted)
+                    .sized_body(Cursor::new(format!("{{\"purge_after\": \"{}\"}}", purge_after.format("%Y-%m-%dT%H:%M:%S"))))
+                    .header(ContentType::JSON)
+                    .ok(),
+            RequestAccountDeletionResult::AlreadyScheduled =>
+                Response::build()
+                    .status(Status::Conflict)
+                    .sized_body(Cursor::new("Deletion of the account is already scheduled"))
+                    .ok(),
+            RequestAccountDeletionResult::InvalidPassword =>
+                Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new("Invalid password"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for CancelAccountDeletionResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            CancelAccountDeletionResult::Cancelled =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            CancelAccountDeletionResult::NotScheduled =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Deletion of the account is not scheduled"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for LogoutResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            LogoutResult::Success =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            LogoutResult::SessionNotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ListSessionsResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            ListSessionsResult::Success(sessions) =>
+                serde_json::to_string(&sessions)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for CreateApiTokenResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            CreateApiTokenResult::Created(token, id) =>
+                Response::build()
+                    .status(Status::Created)
+                    .sized_body(Cursor::new(format!("{{\"token\": \"{}\", \"id\": \"{}\"}}", token, id)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            CreateApiTokenResult::DeviceNotFound => device_not_found()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ListApiTokensResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            ListApiTokensResult::Success(tokens) =>
+                serde_json::to_string(&tokens)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RevokeApiTokenResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RevokeApiTokenResult::Revoked =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            RevokeApiTokenResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("API token not found"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ListDeviceRolesResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            ListDeviceRolesResult::Success(roles) =>
+                serde_json::to_string(&roles)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for SetDeviceRoleResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            SetDeviceRoleResult::Updated =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            SetDeviceRoleResult::CurrentDevice =>
+                Response::build()
+                    .status(Status::Conflict)
+                    .sized_body(Cursor::new("Cannot change role of the current device"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RevokeSessionsResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RevokeSessionsResult::Success(revoked) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"revoked\": {}}}", revoked)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            RevokeSessionsResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Session not found"))
+                    .ok(),
+            RevokeSessionsResult::InvalidRequest(desc) =>
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(Cursor::new(desc))
                     .ok()
         }
     }
@@ -122,6 +552,16 @@ impl<'r> Responder<'r> for UploadResult {
                         resp.adjoin_header(ContentType::JSON);
                         resp
                     }),
+            UploadResult::Unchanged(file) =>
+                serde_json::to_string(&file)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.set_status(Status::AlreadyReported);
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
             UploadResult::MismatchSha256 =>
                 Response::build()
                     .status(Status::PreconditionFailed)
@@ -131,6 +571,50 @@ impl<'r> Responder<'r> for UploadResult {
                 Response::build()
                     .status(Status::BadRequest)
                     .sized_body(Cursor::new(desc))
+                    .ok(),
+            UploadResult::ContentNotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Content not found"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for UploadSessionResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            UploadSessionResult::Created(session_id) =>
+                Response::build()
+                    .status(Status::Created)
+                    .sized_body(Cursor::new(format!("{{\"upload_session_id\": \"{}\"}}", session_id)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            UploadSessionResult::Progress(received) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"received\": {}}}", received)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            UploadSessionResult::Finished(result) => result.respond_to(req),
+            UploadSessionResult::Aborted =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            UploadSessionResult::ChunkMismatchSha256 =>
+                Response::build()
+                    .status(Status::PreconditionFailed)
+                    .sized_body(Cursor::new("Mismatch SHA 256"))
+                    .ok(),
+            UploadSessionResult::InvalidRequest(desc) =>
+                Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(Cursor::new(desc))
+                    .ok(),
+            UploadSessionResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Upload session not found"))
                     .ok()
         }
     }
@@ -208,6 +692,127 @@ impl<'r> Responder<'r> for ListDevicesResult {
     }
 }
 
+impl<'r> Responder<'r> for RenameDeviceResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RenameDeviceResult::Renamed =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            RenameDeviceResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Device not found"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for DecommissionDeviceResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            DecommissionDeviceResult::Decommissioned(removed_files) =>
+                Response::build()
+                    .status(Status::Ok)
+                    .sized_body(Cursor::new(format!("{{\"removed_files\": {}}}", removed_files)))
+                    .header(ContentType::JSON)
+                    .ok(),
+            DecommissionDeviceResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Device not found"))
+                    .ok(),
+            DecommissionDeviceResult::CurrentDevice =>
+                Response::build()
+                    .status(Status::Conflict)
+                    .sized_body(Cursor::new("Cannot decommission the current device"))
+                    .ok()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RetentionPolicyResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RetentionPolicyResult::Success(policy) =>
+                serde_json::to_string(&policy)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+            RetentionPolicyResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .sized_body(Cursor::new("Retention policy not found"))
+                    .ok(),
+            RetentionPolicyResult::DeviceNotFound => device_not_found()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for SetRetentionPolicyResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            SetRetentionPolicyResult::Success =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            SetRetentionPolicyResult::DeviceNotFound => device_not_found()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RemoveRetentionPolicyResult {
+    fn respond_to(self, _: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RemoveRetentionPolicyResult::Success =>
+                Response::build()
+                    .status(Status::Ok)
+                    .ok(),
+            RemoveRetentionPolicyResult::NotFound =>
+                Response::build()
+                    .status(Status::NotFound)
+                    .ok(),
+            RemoveRetentionPolicyResult::DeviceNotFound => device_not_found()
+        }
+    }
+}
+
+impl<'r> Responder<'r> for RetentionDryRunResult {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            RetentionDryRunResult::Success(version_ids) =>
+                serde_json::to_string(&version_ids)
+                    .map_err(failure::Error::from)
+                    .map_err(status_internal_server_error)
+                    .respond_to(req)
+                    .map(|mut resp| {
+                        resp.adjoin_header(ContentType::JSON);
+                        resp
+                    }),
+            RetentionDryRunResult::DeviceNotFound => device_not_found()
+        }
+    }
+}
+
+fn device_not_found<'r>() -> Result<Response<'r>, Status> {
+    Response::build()
+        .status(Status::NotFound)
+        .sized_body(Cursor::new("Device not found"))
+        .ok()
+}
+
+fn too_many_attempts<'r>(retry_after: i64) -> Result<Response<'r>, Status> {
+    Response::build()
+        .status(Status::TooManyRequests)
+        .raw_header("Retry-After", retry_after.to_string())
+        .sized_body(Cursor::new(format!("Too many attempts, try again in {} seconds", retry_after)))
+        .ok()
+}
+
 fn status_internal_server_error(e: failure::Error) -> CustomStatus<String> {
     CustomStatus(Status::InternalServerError, format!("{}", e))
 }
\ No newline at end of file
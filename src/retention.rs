@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::structs::FileVersion;
+
+/// Grandfather-father-son retention policy.
+///
+/// All versions younger than `keep_all_hours` are kept, then one version per day is kept for `keep_daily_days` days and one version per
+/// week for `keep_weekly_weeks` weeks. Anything older is removed. The newest version of a file is always kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetentionPolicy {
+    pub keep_all_hours: u32,
+    pub keep_daily_days: u32,
+    pub keep_weekly_weeks: u32
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Bucket {
+    Day(NaiveDate),
+    Week(i32, u32)
+}
+
+impl RetentionPolicy {
+    pub fn versions_to_remove(&self, versions: &[FileVersion], now: &NaiveDateTime) -> Vec<u64> {
+        let mut sorted: Vec<&FileVersion> = versions.iter().collect();
+        sorted.sort_by(|a, b| b.created.cmp(&a.created).then(b.version.cmp(&a.version)));
+
+        let keep_all = Duration::hours(self.keep_all_hours as i64);
+        let keep_daily = Duration::days(self.keep_daily_days as i64);
+        let keep_weekly = Duration::weeks(self.keep_weekly_weeks as i64);
+
+        let mut used_buckets = HashSet::new();
+
+        sorted.into_iter()
+            .enumerate()
+            .filter(|(index, version)| {
+                if *index == 0 {
+                    return false; // the newest version
+                }
+
+                let age = now.signed_duration_since(version.created);
+
+                if age < keep_all {
+                    false
+                } else if age < keep_daily {
+                    !used_buckets.insert(Bucket::Day(version.created.date()))
+                } else if age < keep_weekly {
+                    let week = version.created.iso_week();
+                    !used_buckets.insert(Bucket::Week(week.year(), week.week()))
+                } else {
+                    true
+                }
+            })
+            .map(|(_, version)| version.version)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::prelude::*;
+    use chrono::Duration;
+
+    use crate::structs::FileVersion;
+
+    use super::RetentionPolicy;
+
+    fn now() -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 3, 20).and_hms(12, 0, 0) // Wednesday, ISO week 12
+    }
+
+    fn version(version: u64, created: NaiveDateTime) -> FileVersion {
+        FileVersion {
+            version,
+            size: 1,
+            hash: "hash".to_string(),
+            created,
+            mtime: created,
+            storage_name: format!("storage-{}", version)
+        }
+    }
+
+    fn to_remove(policy: RetentionPolicy, versions: &[FileVersion], now: &NaiveDateTime) -> Vec<u64> {
+        let mut removed = policy.versions_to_remove(versions, now);
+        removed.sort();
+        removed
+    }
+
+    #[test]
+    fn keeps_newest_version() {
+        let policy = RetentionPolicy { keep_all_hours: 0, keep_daily_days: 0, keep_weekly_weeks: 0 };
+        let versions = [version(1, now() - Duration::days(400)), version(2, now() - Duration::days(365))];
+
+        assert_eq!(vec![1], to_remove(policy, &versions, &now()));
+    }
+
+    #[test]
+    fn keeps_newest_of_versions_created_at_the_same_time() {
+        let policy = RetentionPolicy { keep_all_hours: 0, keep_daily_days: 0, keep_weekly_weeks: 0 };
+        let versions = [version(2, now() - Duration::days(1)), version(1, now() - Duration::days(1))];
+
+        assert_eq!(vec![1], to_remove(policy, &versions, &now()));
+    }
+
+    #[test]
+    fn keeps_all_versions_younger_than_keep_all_hours() {
+        let policy = RetentionPolicy { keep_all_hours: 24, keep_daily_days: 0, keep_weekly_weeks: 0 };
+        let versions = [
+            version(1, now()),
+            version(2, now() - Duration::hours(1)),
+            version(3, now() - Duration::hours(24) + Duration::seconds(1)),
+            version(4, now() - Duration::hours(24)),
+        ];
+
+        assert_eq!(vec![4], to_remove(policy, &versions, &now()));
+    }
+
+    #[test]
+    fn keeps_newest_version_of_each_day() {
+        let policy = RetentionPolicy { keep_all_hours: 0, keep_daily_days: 3, keep_weekly_weeks: 0 };
+        let versions = [
+            version(1, now()),
+            version(2, NaiveDate::from_ymd(2019, 3, 19).and_hms(20, 0, 0)),
+            version(3, NaiveDate::from_ymd(2019, 3, 19).and_hms(8, 0, 0)),
+            version(4, NaiveDate::from_ymd(2019, 3, 18).and_hms(0, 0, 0)),
+            version(5, NaiveDate::from_ymd(2019, 3, 17).and_hms(12, 0, 1)),
+            version(6, NaiveDate::from_ymd(2019, 3, 17).and_hms(12, 0, 0)), // exactly 3 days old
+        ];
+
+        assert_eq!(vec![3, 6], to_remove(policy, &versions, &now()));
+    }
+
+    #[test]
+    fn keeps_newest_version_of_each_week() {
+        let policy = RetentionPolicy { keep_all_hours: 0, keep_daily_days: 0, keep_weekly_weeks: 2 };
+        let versions = [
+            version(1, now()),
+            version(2, NaiveDate::from_ymd(2019, 3, 18).and_hms(10, 0, 0)), // Monday, week 12
+            version(3, NaiveDate::from_ymd(2019, 3, 17).and_hms(10, 0, 0)), // Sunday, week 11
+            version(4, NaiveDate::from_ymd(2019, 3, 11).and_hms(10, 0, 0)), // Monday, week 11
+            version(5, NaiveDate::from_ymd(2019, 3, 6).and_hms(12, 0, 1)), // week 10
+            version(6, NaiveDate::from_ymd(2019, 3, 6).and_hms(12, 0, 0)), // exactly 2 weeks old
+        ];
+
+        assert_eq!(vec![4, 6], to_remove(policy, &versions, &now()));
+    }
+
+    #[test]
+    fn weeks_are_iso_weeks_across_year_boundary() {
+        let now = NaiveDate::from_ymd(2019, 1, 10).and_hms(12, 0, 0);
+        let policy = RetentionPolicy { keep_all_hours: 0, keep_daily_days: 0, keep_weekly_weeks: 4 };
+        let versions = [
+            version(1, now),
+            version(2, NaiveDate::from_ymd(2019, 1, 1).and_hms(10, 0, 0)), // week 1 of 2019
+            version(3, NaiveDate::from_ymd(2018, 12, 31).and_hms(10, 0, 0)), // week 1 of 2019 as well
+            version(4, NaiveDate::from_ymd(2018, 12, 30).and_hms(10, 0, 0)), // week 52 of 2018
+        ];
+
+        assert_eq!(vec![3], to_remove(policy, &versions, &now));
+    }
+
+    #[test]
+    fn combines_all_periods_regardless_of_order() {
+        let policy = RetentionPolicy { keep_all_hours: 24, keep_daily_days: 7, keep_weekly_weeks: 4 };
+        let versions = [
+            version(5, now() - Duration::days(10)),
+            version(1, now() - Duration::hours(2)),
+            version(7, now() - Duration::days(40)),
+            version(3, now() - Duration::days(2)),
+            version(2, now() - Duration::hours(12)),
+            version(4, now() - Duration::days(2) - Duration::hours(1)),
+            version(6, now() - Duration::days(10) - Duration::hours(1)),
+        ];
+
+        assert_eq!(vec![4, 6, 7], to_remove(policy, &versions, &now()));
+    }
+}
@@ -6,16 +6,20 @@ use rocket::Data;
 use rocket::http::{ContentType, Status};
 use rocket::Outcome;
 use rocket::request::{self, FromRequest, Request, Form};
-use rocket::response::{Response, status};
+use rocket::response::{Body, Response, status};
 use rocket::State;
+use rocket_contrib::json::Json;
 use slog;
 use slog::Logger;
+use std::net::IpAddr;
+use std::sync::Arc;
 use stopwatch;
 
 use rbackup;
 use rbackup::dao::Dao;
 use rbackup::encryptor::Encryptor;
 use rbackup::responses::*;
+use rbackup::retention::RetentionPolicy;
 use rbackup::structs::*;
 
 type HandlerResult<T> = Result<T, status::Custom<String>>;
@@ -27,6 +31,34 @@ pub struct UploadMetadata {
     mtime: u64,
 }
 
+#[derive(FromForm)]
+pub struct UploadByHashMetadata {
+    file_path: String,
+    size: u64,
+    mtime: u64,
+    sha256: String,
+}
+
+#[derive(FromForm)]
+pub struct CreateUploadSessionMetadata {
+    file_path: String,
+    size: u64,
+    mtime: u64,
+    sha256: String,
+}
+
+#[derive(FromForm)]
+pub struct UploadSessionMetadata {
+    upload_session_id: String,
+}
+
+#[derive(FromForm)]
+pub struct UploadChunkMetadata {
+    upload_session_id: String,
+    index: u32,
+    sha256: String,
+}
+
 #[derive(FromForm)]
 pub struct DownloadMetadata {
     file_version_id: u64,
@@ -47,17 +79,98 @@ pub struct RemoveFileVersionMetadata {
     file_version_id: u64,
 }
 
-#[derive(FromForm)]
+#[derive(FromForm, Deserialize)]
 pub struct LoginMetadata {
     device_id: String,
+    device_os: Option<String>,
     username: String,
+    password: String,
+    totp_code: Option<String>
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct ChangePasswordMetadata {
+    old_password: String,
+    new_password: String
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct ConfirmTotpMetadata {
+    code: String
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct DisableTotpMetadata {
+    password: String,
+    code: String
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct DeleteAccountMetadata {
     password: String
 }
 
+#[derive(FromForm, Deserialize)]
+pub struct CreateApiTokenMetadata {
+    name: String,
+    scope: String,
+    device_id: Option<String>
+}
+
+#[derive(FromForm)]
+pub struct RevokeApiTokenMetadata {
+    id: String
+}
+
 #[derive(FromForm)]
+pub struct RenameDeviceMetadata {
+    device_id: String,
+    name: String
+}
+
+#[derive(FromForm)]
+pub struct DecommissionDeviceMetadata {
+    device_id: String,
+    delete_files: Option<bool>
+}
+
+#[derive(FromForm)]
+pub struct SetDeviceRoleMetadata {
+    device_id: String,
+    role: String
+}
+
+#[derive(FromForm)]
+pub struct RevokeSessionsMetadata {
+    session_id: Option<String>,
+    device_id: Option<String>
+}
+
+#[derive(FromForm, Deserialize)]
 pub struct RegisterMetadata {
     username: String,
-    password: String
+    password: String,
+    recovery_key: Option<bool>
+}
+
+#[derive(FromForm, Deserialize)]
+pub struct RecoverAccountMetadata {
+    username: String,
+    recovery_key: String,
+    new_password: String
+}
+
+#[derive(FromForm)]
+pub struct RetentionPolicyMetadata {
+    device_id: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct SetRetentionPolicyMetadata {
+    device_id: Option<String>,
+    keep_all_hours: u32,
+    keep_daily_days: u32,
+    keep_weekly_weeks: u32
 }
 
 pub struct Headers {
@@ -68,14 +181,24 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Headers, ()> {
-        // device id
+        // session ID or API token; the latter may be passed as a bearer token, too
 
         let values: Vec<_> = request.headers().get("RBackup-Session-Pass").collect();
-        if values.len() != 1 {
-            return Outcome::Failure((Status::Unauthorized, ()));
-        }
 
-        let session_pass = String::from(values[0]);
+        let session_pass = if values.len() == 1 {
+            String::from(values[0])
+        } else {
+            let bearer_tokens: Vec<_> = request.headers().get("Authorization")
+                .filter(|value| value.starts_with("Bearer "))
+                .map(|value| value["Bearer ".len()..].trim())
+                .collect();
+
+            if values.len() > 1 || bearer_tokens.len() != 1 {
+                return Outcome::Failure((Status::Unauthorized, ()));
+            }
+
+            String::from(bearer_tokens[0])
+        };
 
         return Outcome::Success(Headers {
             session_pass
@@ -83,6 +206,26 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
     }
 }
 
+/// IP address of the client. The `X-Real-IP` header is used only when the request comes from one of the trusted proxies,
+/// otherwise anyone could pick the address the throttling counts with.
+pub struct ClientIp(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ClientIp, ()> {
+        let config = request.guard::<State<HandlerConfig>>()?;
+        let remote_ip = request.remote().map(|addr| addr.ip());
+
+        let client_ip = match remote_ip {
+            Some(ip) if config.trusted_proxies.contains(&ip) => request.real_ip().or(remote_ip),
+            _ => remote_ip
+        };
+
+        Outcome::Success(ClientIp(client_ip.map(|ip| ip.to_string())))
+    }
+}
+
 #[get("/status")]
 pub fn status(config: State<HandlerConfig>) -> HandlerResult<StatusResult> {
     debug!(config.logger, "Requesting server status");
@@ -93,65 +236,333 @@ pub fn status(config: State<HandlerConfig>) -> HandlerResult<StatusResult> {
     })
 }
 
+#[post("/account/register", format = "json", data = "<metadata>")]
+pub fn register_json(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Json<RegisterMetadata>) -> HandlerResult<RegisterResult> {
+    register(config, client_ip, metadata.into_inner())
+}
+
+#[post("/account/register", format = "form", data = "<metadata>")]
+pub fn register_form(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Form<RegisterMetadata>) -> HandlerResult<RegisterResult> {
+    register(config, client_ip, metadata.into_inner())
+}
+
+/// Deprecated - the credentials end up in access logs of proxies. Mounted only when `server.legacy_credentials_in_query` is enabled.
 #[get("/account/register?<metadata..>")]
-pub fn register(config: State<HandlerConfig>, metadata: Form<RegisterMetadata>) -> HandlerResult<RegisterResult> {
+pub fn register_legacy(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Form<RegisterMetadata>) -> HandlerResult<RegisterResult> {
+    warn!(config.logger, "Deprecated 'GET /account/register' was used");
+    register(config, client_ip, metadata.into_inner())
+}
+
+fn register(config: State<HandlerConfig>, client_ip: ClientIp, metadata: RegisterMetadata) -> HandlerResult<RegisterResult> {
     debug!(config.logger, "Registering account '{}'", &metadata.username);
 
-    // TODO validate args
+    validate_username(&metadata.username)
+        .and_then(|_| validate_not_empty("password", &metadata.password))?;
 
     with_metrics(&config.logger, &config.statsd_client, "register", || {
-        rbackup::register(&config.logger, &config.dao, &config.repo_root, &metadata.username, &metadata.password)
+        rbackup::register(&config.logger, &config.dao, &config.repo_root, client_ip.0.as_ref().map(String::as_str), &metadata.username, &metadata.password, metadata.recovery_key.unwrap_or(false))
+            .map(|result| {
+                if let RegisterResult::TooManyAttempts(_) = result {
+                    #[allow(unused_must_use)] { config.statsd_client.count("register.blocked", 1); }
+                }
+                result
+            })
             .map_err(status_internal_server_error)
     })
 }
 
+#[post("/account/login", format = "json", data = "<metadata>")]
+pub fn login_json(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Json<LoginMetadata>) -> HandlerResult<LoginResult> {
+    login(config, client_ip, metadata.into_inner())
+}
+
+#[post("/account/login", format = "form", data = "<metadata>")]
+pub fn login_form(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Form<LoginMetadata>) -> HandlerResult<LoginResult> {
+    login(config, client_ip, metadata.into_inner())
+}
+
+/// Deprecated - the credentials end up in access logs of proxies. Mounted only when `server.legacy_credentials_in_query` is enabled.
 #[get("/account/login?<metadata..>")]
-pub fn login(config: State<HandlerConfig>, metadata: Form<LoginMetadata>) -> HandlerResult<LoginResult> {
+pub fn login_legacy(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Form<LoginMetadata>) -> HandlerResult<LoginResult> {
+    warn!(config.logger, "Deprecated 'GET /account/login' was used");
+    login(config, client_ip, metadata.into_inner())
+}
+
+fn login(config: State<HandlerConfig>, client_ip: ClientIp, metadata: LoginMetadata) -> HandlerResult<LoginResult> {
     info!(&config.logger, "Logging-in account '{}'", &metadata.username);
 
-    // TODO validate args
+    validate_device_id(&metadata.device_id)
+        .and_then(|_| validate_not_empty("username", &metadata.username))
+        .and_then(|_| validate_not_empty("password", &metadata.password))?;
+
+    if let Some(ref device_os) = metadata.device_os {
+        validate_name("device_os", device_os)?;
+    }
 
     with_metrics(&config.logger, &config.statsd_client, "login", || {
-        rbackup::login(&config.dao, &config.encryptor, &metadata.device_id, &metadata.username, &metadata.password)
+        rbackup::login(&config.logger, &config.dao, &config.encryptor, &config.repo_root, client_ip.0.as_ref().map(String::as_str), &metadata.device_id, metadata.device_os.as_ref().map(String::as_str), &metadata.username, &metadata.password, metadata.totp_code.as_ref().map(String::as_str))
+            .map(|result| {
+                if let LoginResult::TooManyAttempts(_) = result {
+                    #[allow(unused_must_use)] { config.statsd_client.count("login.blocked", 1); }
+                }
+                result
+            })
+            .map_err(status_internal_server_error)
+    })
+}
+
+#[post("/account/recover", format = "json", data = "<metadata>")]
+pub fn recover_account_json(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Json<RecoverAccountMetadata>) -> HandlerResult<RecoverAccountResult> {
+    recover_account(config, client_ip, metadata.into_inner())
+}
+
+#[post("/account/recover", format = "form", data = "<metadata>")]
+pub fn recover_account_form(config: State<HandlerConfig>, client_ip: ClientIp, metadata: Form<RecoverAccountMetadata>) -> HandlerResult<RecoverAccountResult> {
+    recover_account(config, client_ip, metadata.into_inner())
+}
+
+fn recover_account(config: State<HandlerConfig>, client_ip: ClientIp, metadata: RecoverAccountMetadata) -> HandlerResult<RecoverAccountResult> {
+    info!(&config.logger, "Recovering account '{}'", &metadata.username);
+
+    validate_not_empty("username", &metadata.username)
+        .and_then(|_| validate_not_empty("recovery_key", &metadata.recovery_key))
+        .and_then(|_| validate_not_empty("new_password", &metadata.new_password))?;
+
+    with_metrics(&config.logger, &config.statsd_client, "recover_account", || {
+        rbackup::recover_account(&config.logger, &config.dao, client_ip.0.as_ref().map(String::as_str), &metadata.username, &metadata.recovery_key, &metadata.new_password)
+            .map(|result| {
+                if let RecoverAccountResult::TooManyAttempts(_) = result {
+                    #[allow(unused_must_use)] { config.statsd_client.count("recover_account.blocked", 1); }
+                }
+                result
+            })
             .map_err(status_internal_server_error)
     })
 }
 
+#[post("/account/password", format = "json", data = "<metadata>")]
+pub fn change_password_json(config: State<HandlerConfig>, headers: Headers, metadata: Json<ChangePasswordMetadata>) -> HandlerResult<ChangePasswordResult> {
+    change_password(config, headers, metadata.into_inner())
+}
+
+#[post("/account/password", format = "form", data = "<metadata>")]
+pub fn change_password_form(config: State<HandlerConfig>, headers: Headers, metadata: Form<ChangePasswordMetadata>) -> HandlerResult<ChangePasswordResult> {
+    change_password(config, headers, metadata.into_inner())
+}
+
+fn change_password(config: State<HandlerConfig>, headers: Headers, metadata: ChangePasswordMetadata) -> HandlerResult<ChangePasswordResult> {
+    validate_not_empty("new_password", &metadata.new_password)?;
+
+    with_authentication(&config.logger, "change_password", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::change_password(&config.logger, &config.dao, &config.encryptor, &config.repo_root, &device, &metadata.old_password, &metadata.new_password)
+    })
+}
+
+#[post("/account/totp")]
+pub fn enroll_totp(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<EnrollTotpResult> {
+    with_authentication(&config.logger, "enroll_totp", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::enroll_totp(&config.logger, &config.dao, &device.account_id)
+    })
+}
+
+#[post("/account/totp/confirm", format = "json", data = "<metadata>")]
+pub fn confirm_totp_json(config: State<HandlerConfig>, headers: Headers, metadata: Json<ConfirmTotpMetadata>) -> HandlerResult<ConfirmTotpResult> {
+    confirm_totp(config, headers, metadata.into_inner())
+}
+
+#[post("/account/totp/confirm", format = "form", data = "<metadata>")]
+pub fn confirm_totp_form(config: State<HandlerConfig>, headers: Headers, metadata: Form<ConfirmTotpMetadata>) -> HandlerResult<ConfirmTotpResult> {
+    confirm_totp(config, headers, metadata.into_inner())
+}
+
+fn confirm_totp(config: State<HandlerConfig>, headers: Headers, metadata: ConfirmTotpMetadata) -> HandlerResult<ConfirmTotpResult> {
+    validate_not_empty("code", &metadata.code)?;
+
+    with_authentication(&config.logger, "confirm_totp", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::confirm_totp(&config.logger, &config.dao, &device.account_id, &metadata.code)
+    })
+}
+
+#[post("/account/totp/disable", format = "json", data = "<metadata>")]
+pub fn disable_totp_json(config: State<HandlerConfig>, headers: Headers, metadata: Json<DisableTotpMetadata>) -> HandlerResult<DisableTotpResult> {
+    disable_totp(config, headers, metadata.into_inner())
+}
+
+#[post("/account/totp/disable", format = "form", data = "<metadata>")]
+pub fn disable_totp_form(config: State<HandlerConfig>, headers: Headers, metadata: Form<DisableTotpMetadata>) -> HandlerResult<DisableTotpResult> {
+    disable_totp(config, headers, metadata.into_inner())
+}
+
+fn disable_totp(config: State<HandlerConfig>, headers: Headers, metadata: DisableTotpMetadata) -> HandlerResult<DisableTotpResult> {
+    validate_not_empty("password", &metadata.password)
+        .and_then(|_| validate_not_empty("code", &metadata.code))?;
+
+    with_authentication(&config.logger, "disable_totp", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::disable_totp(&config.logger, &config.dao, &device.account_id, &metadata.password, &metadata.code)
+    })
+}
+
+#[post("/account/delete", format = "json", data = "<metadata>")]
+pub fn delete_account_json(config: State<HandlerConfig>, headers: Headers, metadata: Json<DeleteAccountMetadata>) -> HandlerResult<RequestAccountDeletionResult> {
+    delete_account(config, headers, metadata.into_inner())
+}
+
+#[post("/account/delete", format = "form", data = "<metadata>")]
+pub fn delete_account_form(config: State<HandlerConfig>, headers: Headers, metadata: Form<DeleteAccountMetadata>) -> HandlerResult<RequestAccountDeletionResult> {
+    delete_account(config, headers, metadata.into_inner())
+}
+
+fn delete_account(config: State<HandlerConfig>, headers: Headers, metadata: DeleteAccountMetadata) -> HandlerResult<RequestAccountDeletionResult> {
+    validate_not_empty("password", &metadata.password)?;
+
+    with_authentication(&config.logger, "delete_account", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::request_account_deletion(&config.logger, &config.dao, &device, &metadata.password, config.account_deletion_grace_period)
+    })
+}
+
+#[post("/account/delete/cancel")]
+pub fn cancel_account_deletion(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<CancelAccountDeletionResult> {
+    with_authentication(&config.logger, "cancel_account_deletion", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::cancel_account_deletion(&config.logger, &config.dao, &device)
+    })
+}
+
+#[post("/account/logout")]
+pub fn logout(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<LogoutResult> {
+    with_authentication(&config.logger, "logout", Permission::Session, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::logout(&config.logger, &config.dao, &device, &headers.session_pass)
+    })
+}
+
+#[get("/account/sessions")]
+pub fn list_sessions(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<ListSessionsResult> {
+    with_authentication(&config.logger, "list_sessions", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::list_sessions(&config.dao, &device.account_id, &headers.session_pass)
+    })
+}
+
+#[delete("/account/sessions?<metadata..>")]
+pub fn revoke_sessions(config: State<HandlerConfig>, headers: Headers, metadata: Form<RevokeSessionsMetadata>) -> HandlerResult<RevokeSessionsResult> {
+    with_authentication(&config.logger, "revoke_sessions", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::revoke_sessions(&config.logger, &config.dao, &device.account_id, metadata.session_id.as_ref().map(String::as_str), metadata.device_id.as_ref().map(String::as_str))
+    })
+}
+
+#[post("/account/tokens", format = "json", data = "<metadata>")]
+pub fn create_api_token_json(config: State<HandlerConfig>, headers: Headers, metadata: Json<CreateApiTokenMetadata>) -> HandlerResult<CreateApiTokenResult> {
+    create_api_token(config, headers, metadata.into_inner())
+}
+
+#[post("/account/tokens", format = "form", data = "<metadata>")]
+pub fn create_api_token_form(config: State<HandlerConfig>, headers: Headers, metadata: Form<CreateApiTokenMetadata>) -> HandlerResult<CreateApiTokenResult> {
+    create_api_token(config, headers, metadata.into_inner())
+}
+
+fn create_api_token(config: State<HandlerConfig>, headers: Headers, metadata: CreateApiTokenMetadata) -> HandlerResult<CreateApiTokenResult> {
+    validate_name("name", &metadata.name)?;
+
+    if let Some(ref device_id) = metadata.device_id {
+        validate_device_id(device_id)?;
+    }
+
+    let scope = TokenScope::from_name(&metadata.scope)
+        .ok_or_else(|| status::Custom(Status::BadRequest, "Invalid scope; it has to be one of 'upload', 'read', 'full'".to_string()))?;
+
+    with_authentication(&config.logger, "create_api_token", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::create_api_token(&config.logger, &config.dao, &config.encryptor, &device, metadata.device_id.as_ref().map(String::as_str), &metadata.name, scope)
+    })
+}
+
+#[get("/account/tokens")]
+pub fn list_api_tokens(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<ListApiTokensResult> {
+    with_authentication(&config.logger, "list_api_tokens", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::list_api_tokens(&config.dao, &device.account_id)
+    })
+}
+
+#[delete("/account/tokens?<metadata..>")]
+pub fn revoke_api_token(config: State<HandlerConfig>, headers: Headers, metadata: Form<RevokeApiTokenMetadata>) -> HandlerResult<RevokeApiTokenResult> {
+    with_authentication(&config.logger, "revoke_api_token", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::revoke_api_token(&config.logger, &config.dao, &device.account_id, &metadata.id)
+    })
+}
+
+#[get("/account/devices/roles")]
+pub fn list_device_roles(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<ListDeviceRolesResult> {
+    with_authentication(&config.logger, "list_device_roles", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::list_device_roles(&config.dao, &device.account_id)
+    })
+}
+
+#[put("/account/devices/role?<metadata..>")]
+pub fn set_device_role(config: State<HandlerConfig>, headers: Headers, metadata: Form<SetDeviceRoleMetadata>) -> HandlerResult<SetDeviceRoleResult> {
+    validate_device_id(&metadata.device_id)?;
+
+    let role = DeviceRole::from_name(&metadata.role)
+        .ok_or_else(|| status::Custom(Status::BadRequest, "Invalid role; it has to be one of 'admin', 'read-only', 'write-only'".to_string()))?;
+
+    with_authentication(&config.logger, "set_device_role", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::set_device_role(&config.logger, &config.dao, &device, &metadata.device_id, role)
+    })
+}
+
 #[get("/list/files")]
 pub fn list_files(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<ListFileResult> {
-    with_authentication(&config.logger, "list_files", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "list_files", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         rbackup::list_files(&config.dao, &device.account_id, &device.id)
     })
 }
 
 #[get("/list/files?<metadata..>")]
 pub fn list_files_for_device(config: State<HandlerConfig>, headers: Headers, metadata: Form<ListFilesMetadata>) -> HandlerResult<ListFileResult> {
-    with_authentication(&config.logger, "list_files", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "list_files", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         rbackup::list_files(&config.dao, &device.account_id, &metadata.into_inner().device_id.unwrap_or(device.id))
     })
 }
 
 #[get("/list/devices")]
 pub fn list_devices(config: State<HandlerConfig>, headers: Headers) -> HandlerResult<ListDevicesResult> {
-    with_authentication(&config.logger, "list_devices", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "list_devices", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         rbackup::list_devices(&config.dao, &device.account_id)
     })
 }
 
+#[put("/account/devices/name?<metadata..>")]
+pub fn rename_device(config: State<HandlerConfig>, headers: Headers, metadata: Form<RenameDeviceMetadata>) -> HandlerResult<RenameDeviceResult> {
+    validate_device_id(&metadata.device_id)
+        .and_then(|_| validate_name("name", &metadata.name))?;
+
+    with_authentication(&config.logger, "rename_device", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::rename_device(&config.logger, &config.dao, &device.account_id, &metadata.device_id, &metadata.name)
+    })
+}
+
+#[delete("/account/devices?<metadata..>")]
+pub fn decommission_device(config: State<HandlerConfig>, headers: Headers, metadata: Form<DecommissionDeviceMetadata>) -> HandlerResult<DecommissionDeviceResult> {
+    validate_device_id(&metadata.device_id)?;
+
+    with_authentication(&config.logger, "decommission_device", Permission::Account, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass.clone(), &config.logger)
+            .and_then(|repo| {
+                rbackup::decommission_device(&config.logger, &repo, &config.dao, &device, &metadata.device_id, metadata.delete_files.unwrap_or(false))
+            })
+    })
+}
+
 #[get("/download?<metadata..>")]
 pub fn download(config: State<HandlerConfig>, headers: Headers, metadata: Form<DownloadMetadata>) -> HandlerResult<Response> {
-    with_authentication(&config.logger, "download", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "download", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         debug!(config.logger, "Opening repo");
 
-        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
-            .and_then(|repo| rbackup::load(config.logger.clone(), &repo, &config.dao, metadata.file_version_id))
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass.clone(), &config.logger)
+            .and_then(|repo| rbackup::load(config.logger.clone(), &repo, &config.dao, &device.account_id, metadata.file_version_id))
             .and_then(|o| {
                 match o {
                     Some((hash, size, read)) => {
+                        // sized body sets the Content-Length from u64, so it works for files of any size
                         rocket::response::Response::build()
                             .raw_header("RBackup-File-Hash", hash)
-                            .raw_header("Content-Length", format!("{}", size))
-                            .streamed_body(read)
+                            .raw_body(Body::Sized(read, size))
                             .ok()
                     },
                     None => {
@@ -166,7 +577,7 @@ pub fn download(config: State<HandlerConfig>, headers: Headers, metadata: Form<D
 
 #[put("/upload?<metadata..>", data = "<data>")]
 pub fn upload(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadMetadata>, data: Data, cont_type: &ContentType) -> HandlerResult<UploadResult> {
-    with_authentication(&config.logger, "upload", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "upload", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         let uploaded_file_metadata = rbackup::to_uploaded_file(&device.account_id, &device.id, &metadata.file_path, metadata.size, metadata.mtime);
 
         if !cont_type.is_form_data() {
@@ -186,37 +597,139 @@ pub fn upload(config: State<HandlerConfig>, headers: Headers, metadata: Form<Upl
     })
 }
 
+#[put("/upload/byHash?<metadata..>")]
+pub fn upload_by_hash(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadByHashMetadata>) -> HandlerResult<UploadResult> {
+    with_authentication(&config.logger, "upload_by_hash", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        let uploaded_file_metadata = rbackup::to_uploaded_file(&device.account_id, &device.id, &metadata.file_path, metadata.size, metadata.mtime);
+
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
+            .and_then(|repo| {
+                rbackup::save_by_hash(&config.logger, &repo, &config.dao, uploaded_file_metadata, &metadata.sha256)
+            })
+    })
+}
+
+#[post("/upload/session?<metadata..>")]
+pub fn create_upload_session(config: State<HandlerConfig>, headers: Headers, metadata: Form<CreateUploadSessionMetadata>) -> HandlerResult<UploadSessionResult> {
+    with_authentication(&config.logger, "create_upload_session", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        let uploaded_file_metadata = rbackup::to_uploaded_file(&device.account_id, &device.id, &metadata.file_path, metadata.size, metadata.mtime);
+
+        rbackup::create_upload_session(&config.dao, uploaded_file_metadata, &metadata.sha256)
+    })
+}
+
+#[get("/upload/session?<metadata..>")]
+pub fn upload_session_status(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadSessionMetadata>) -> HandlerResult<UploadSessionResult> {
+    with_authentication(&config.logger, "upload_session_status", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::upload_session_status(&config.dao, &device.account_id, &device.id, &metadata.upload_session_id)
+    })
+}
+
+#[put("/upload/session/chunk?<metadata..>", data = "<data>")]
+pub fn upload_chunk(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadChunkMetadata>, data: Data) -> HandlerResult<UploadSessionResult> {
+    with_authentication(&config.logger, "upload_chunk", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
+            .and_then(|repo| {
+                rbackup::save_upload_chunk(&config.logger, config.statsd_client.clone(), &repo, &config.dao, &device.account_id, &device.id, &metadata.upload_session_id, metadata.index, &metadata.sha256, data)
+            })
+    })
+}
+
+#[post("/upload/session/finalize?<metadata..>")]
+pub fn finalize_upload_session(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadSessionMetadata>) -> HandlerResult<UploadSessionResult> {
+    with_authentication(&config.logger, "finalize_upload_session", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
+            .and_then(|repo| {
+                rbackup::finalize_upload_session(&config.logger, config.statsd_client.clone(), &repo, &config.dao, &device.account_id, &device.id, &metadata.upload_session_id)
+            })
+    })
+}
+
+#[delete("/upload/session?<metadata..>")]
+pub fn abort_upload_session(config: State<HandlerConfig>, headers: Headers, metadata: Form<UploadSessionMetadata>) -> HandlerResult<UploadSessionResult> {
+    with_authentication(&config.logger, "abort_upload_session", Permission::Upload, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
+            .and_then(|repo| {
+                rbackup::abort_upload_session(&config.logger, &repo, &config.dao, &device.account_id, &device.id, &metadata.upload_session_id)
+            })
+    })
+}
+
 #[delete("/remove/fileVersion?<metadata..>")]
 pub fn remove_file_version(config: State<HandlerConfig>, headers: Headers, metadata: Form<RemoveFileVersionMetadata>) -> HandlerResult<RemoveFileVersionResult> {
-    with_authentication(&config.logger, "remove_file_version", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
-        Repo::new(&config.repo_root, &device.account_id, device.repo_pass, &config.logger)
+    with_authentication(&config.logger, "remove_file_version", Permission::Modify, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        Repo::new(&config.repo_root, &device.account_id, device.repo_pass.clone(), &config.logger)
             .and_then(|repo| {
-                rbackup::remove_file_version(&repo, &config.dao, metadata.file_version_id)
+                rbackup::remove_file_version(&repo, &config.dao, &device.account_id, metadata.file_version_id)
             })
     })
 }
 
 #[delete("/remove/file?<metadata..>")]
 pub fn remove_file(config: State<HandlerConfig>, headers: Headers, metadata: Form<RemoveFileMetadata>) -> HandlerResult<RemoveFileResult> {
-    with_authentication(&config.logger, "remove_file", &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+    with_authentication(&config.logger, "remove_file", Permission::Modify, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
         Repo::new(&config.repo_root, &device.account_id, device.repo_pass.clone(), &config.logger)
             .and_then(|repo| {
-                rbackup::remove_file(&config.logger, &repo, &config.dao, &device.id, metadata.file_id)
+                rbackup::remove_file(&config.logger, &repo, &config.dao, &device.account_id, &device.id, metadata.file_id)
             })
     })
 }
 
-fn with_authentication<'a, R: rocket::response::Responder<'a>, F2: FnOnce(DeviceIdentity) -> Result<R, Error>>(logger: &Logger, name: &str, statsd_client: &StatsdClient, dao: &Dao, enc: &Encryptor, session_id: &str, f2: F2) -> HandlerResult<R> {
+#[get("/retention/policy?<metadata..>")]
+pub fn get_retention_policy(config: State<HandlerConfig>, headers: Headers, metadata: Form<RetentionPolicyMetadata>) -> HandlerResult<RetentionPolicyResult> {
+    with_authentication(&config.logger, "get_retention_policy", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::get_retention_policy(&config.dao, &config.retention_policy, &device.account_id, metadata.device_id.as_ref().map(String::as_str))
+    })
+}
+
+#[put("/retention/policy?<metadata..>")]
+pub fn set_retention_policy(config: State<HandlerConfig>, headers: Headers, metadata: Form<SetRetentionPolicyMetadata>) -> HandlerResult<SetRetentionPolicyResult> {
+    with_authentication(&config.logger, "set_retention_policy", Permission::Modify, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        let policy = RetentionPolicy {
+            keep_all_hours: metadata.keep_all_hours,
+            keep_daily_days: metadata.keep_daily_days,
+            keep_weekly_weeks: metadata.keep_weekly_weeks
+        };
+
+        rbackup::set_retention_policy(&config.dao, &device.account_id, metadata.device_id.as_ref().map(String::as_str), &policy)
+    })
+}
+
+#[delete("/retention/policy?<metadata..>")]
+pub fn remove_retention_policy(config: State<HandlerConfig>, headers: Headers, metadata: Form<RetentionPolicyMetadata>) -> HandlerResult<RemoveRetentionPolicyResult> {
+    with_authentication(&config.logger, "remove_retention_policy", Permission::Modify, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::remove_retention_policy(&config.dao, &device.account_id, metadata.device_id.as_ref().map(String::as_str))
+    })
+}
+
+#[get("/retention/dryRun?<metadata..>")]
+pub fn retention_dry_run(config: State<HandlerConfig>, headers: Headers, metadata: Form<RetentionPolicyMetadata>) -> HandlerResult<RetentionDryRunResult> {
+    with_authentication(&config.logger, "retention_dry_run", Permission::Read, &config.statsd_client, &config.dao, &config.encryptor, &headers.session_pass, |device| {
+        rbackup::retention_dry_run(&config.dao, &config.retention_policy, &device.account_id, metadata.device_id.as_ref().map(String::as_str))
+    })
+}
+
+fn with_authentication<'a, R: rocket::response::Responder<'a>, F2: FnOnce(DeviceIdentity) -> Result<R, Error>>(logger: &Logger, name: &str, permission: Permission, statsd_client: &StatsdClient, dao: &Dao, enc: &Encryptor, session_id: &str, f2: F2) -> HandlerResult<R> {
     debug!(logger, "Authenticating '{}' request", name);
 
     with_metrics(logger, statsd_client, name, || {
         match rbackup::authenticate(dao, enc, session_id) {
-            Ok(Some(identity)) => {
+            Ok(Authentication::Valid(ref identity)) if !identity.is_allowed(permission) => {
+                #[allow(unused_must_use)] { statsd_client.count("authentication.forbidden", 1); }
+                debug!(logger, "Request '{}' is not allowed to the device", name; "role" => ?identity.role, "scope" => ?identity.token_scope);
+                Err(status::Custom(Status::Forbidden, "Not allowed by the role of the device or the scope of the API token".to_string()))
+            },
+            Ok(Authentication::Valid(identity)) => {
                 #[allow(unused_must_use)] { statsd_client.count("authentication.ok", 1); }
                 debug!(logger, "Authenticated '{}' request", name);
                 f2(identity.clone()).map_err(status_internal_server_error)
             },
-            Ok(None) => {
+            Ok(Authentication::Expired) => {
+                #[allow(unused_must_use)] { statsd_client.count("authentication.expired", 1); }
+                debug!(logger, "Request with expired session! SessionId: {}", session_id);
+                Err(status::Custom(Status::Unauthorized, "Session expired".to_string()))
+            },
+            Ok(Authentication::NotFound) => {
                 #[allow(unused_must_use)] { statsd_client.count("authentication.not_found", 1); }
                 debug!(logger, "Unauthenticated request! SessionId: {}", session_id);
                 Err(status::Custom(Status::Unauthorized, "Cannot find session".to_string()))
@@ -253,14 +766,52 @@ fn with_metrics<O, E, F: FnOnce() -> Result<O, E>>(logger: &Logger, statsd_clien
     })
 }
 
+/// Usernames of new accounts; existing accounts may have been registered before the format was checked.
+fn validate_username(username: &str) -> Result<(), status::Custom<String>> {
+    let valid_chars = username.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '@');
+
+    if username.is_empty() || username.chars().count() > 250 || !valid_chars {
+        Err(status::Custom(Status::BadRequest, "Invalid username; it has to have 1-250 characters, only letters, digits and '.', '_', '-', '@' are allowed".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_name(field: &str, value: &str) -> Result<(), status::Custom<String>> {
+    if value.is_empty() || value.chars().count() > 100 {
+        Err(status::Custom(Status::BadRequest, format!("Invalid {}; it has to have 1-100 characters", field)))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_device_id(device_id: &str) -> Result<(), status::Custom<String>> {
+    if device_id.is_empty() || device_id.chars().count() > 100 {
+        Err(status::Custom(Status::BadRequest, "Invalid device_id; it has to have 1-100 characters".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_not_empty(field: &str, value: &str) -> Result<(), status::Custom<String>> {
+    if value.is_empty() {
+        Err(status::Custom(Status::BadRequest, format!("Field '{}' must not be empty", field)))
+    } else {
+        Ok(())
+    }
+}
+
 fn status_internal_server_error(e: Error) -> status::Custom<String> {
     status::Custom(Status::InternalServerError, format!("{}", e))
 }
 
 pub struct HandlerConfig {
     pub repo_root: String,
-    pub dao: Dao,
+    pub dao: Arc<Dao>,
     pub encryptor: Encryptor,
+    pub retention_policy: Option<RetentionPolicy>,
+    pub account_deletion_grace_period: chrono::Duration,
+    pub trusted_proxies: Vec<IpAddr>,
     pub logger: slog::Logger,
     pub statsd_client: StatsdClient
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use chrono::prelude::*;
+use chrono::Duration;
+
+/// Lifetime of login sessions.
+///
+/// A session expires `max_lifetime` after it was created, no matter how it's used, or when it wasn't used for `idle_timeout`.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub max_lifetime: Duration,
+    pub idle_timeout: Duration,
+    /// How often is the `last_used` time of sessions written to the DB.
+    pub last_used_flush_interval: std::time::Duration,
+    /// How often are the expired sessions removed from the DB.
+    pub sweep_interval: std::time::Duration,
+    /// Whether a device may have more sessions at once; otherwise a login replaces the current session of the device.
+    pub multiple_per_device: bool
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            max_lifetime: Duration::days(30),
+            idle_timeout: Duration::days(7),
+            last_used_flush_interval: std::time::Duration::from_secs(60),
+            sweep_interval: std::time::Duration::from_secs(10 * 60),
+            multiple_per_device: false
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn is_expired(&self, created: &NaiveDateTime, last_used: &NaiveDateTime, now: &NaiveDateTime) -> bool {
+        *now - *created > self.max_lifetime || *now - *last_used > self.idle_timeout
+    }
+
+    /// Sessions created before the returned time are expired.
+    pub fn created_threshold(&self, now: &NaiveDateTime) -> NaiveDateTime {
+        *now - self.max_lifetime
+    }
+
+    /// Sessions last used before the returned time are expired.
+    pub fn last_used_threshold(&self, now: &NaiveDateTime) -> NaiveDateTime {
+        *now - self.idle_timeout
+    }
+}
@@ -1,5 +1,5 @@
 use failure::Error;
-use mysql::chrono::prelude::NaiveDateTime;
+use chrono::prelude::NaiveDateTime;
 use crate::rdedup::Repo as RdedupRepo;
 use std;
 use slog;
@@ -58,9 +58,189 @@ pub struct UploadedFile {
     pub identity_hash: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    pub account_id: String,
+    pub device_id: String,
+    pub original_name: String,
+    pub size: u64,
+    pub mtime: NaiveDateTime,
+    pub hash: String
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadChunk {
+    pub index: u32,
+    pub size: u64,
+    pub hash: String,
+    pub storage_name: String
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SavedUploadChunk {
+    /// Contains storage name of the chunk with the same index which was replaced (if any).
+    Saved(Option<String>),
+    /// The chunks would be bigger than the uploaded file, nothing was saved.
+    SizeExceeded
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Device {
+    pub id: String,
+    /// Friendly name set by the user.
+    pub name: Option<String>,
+    pub os: Option<String>,
+    pub created: NaiveDateTime,
+    pub last_backup: Option<NaiveDateTime>
+}
+
 #[derive(Debug, Clone)]
 pub struct DeviceIdentity {
     pub id: String,
     pub account_id: String,
-    pub repo_pass: String
+    pub repo_pass: String,
+    pub role: DeviceRole,
+    /// Scope of the API token the request was authenticated by; `None` for login sessions, which are limited just by the role.
+    pub token_scope: Option<TokenScope>
+}
+
+impl DeviceIdentity {
+    pub fn is_allowed(&self, permission: Permission) -> bool {
+        self.role.allows(permission) && self.token_scope.map(|scope| scope.allows(permission)).unwrap_or(true)
+    }
+}
+
+/// What an authenticated request needs to be allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Upload,
+    Read,
+    /// Removing files and changing the retention policies.
+    Modify,
+    /// Managing the account itself - password, sessions, tokens, device roles etc. Never allowed to API tokens.
+    Account,
+    /// Managing the current session (i.e. logging out).
+    Session
+}
+
+/// Role of the device within the account. Devices without explicitly set role are admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceRole {
+    Admin,
+    ReadOnly,
+    WriteOnly
+}
+
+impl DeviceRole {
+    pub fn from_name(name: &str) -> Option<DeviceRole> {
+        match name {
+            "admin" => Some(DeviceRole::Admin),
+            "read-only" => Some(DeviceRole::ReadOnly),
+            "write-only" => Some(DeviceRole::WriteOnly),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceRole::Admin => "admin",
+            DeviceRole::ReadOnly => "read-only",
+            DeviceRole::WriteOnly => "write-only"
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        match (self, permission) {
+            (DeviceRole::Admin, _) => true,
+            (_, Permission::Session) => true,
+            (DeviceRole::ReadOnly, Permission::Read) => true,
+            (DeviceRole::WriteOnly, Permission::Upload) => true,
+            _ => false
+        }
+    }
+}
+
+impl Default for DeviceRole {
+    fn default() -> DeviceRole {
+        DeviceRole::Admin
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRoleInfo {
+    pub device_id: String,
+    pub role: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    UploadOnly,
+    ReadOnly,
+    Full
+}
+
+impl TokenScope {
+    pub fn from_name(name: &str) -> Option<TokenScope> {
+        match name {
+            "upload" => Some(TokenScope::UploadOnly),
+            "read" => Some(TokenScope::ReadOnly),
+            "full" => Some(TokenScope::Full),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenScope::UploadOnly => "upload",
+            TokenScope::ReadOnly => "read",
+            TokenScope::Full => "full"
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        match (self, permission) {
+            (_, Permission::Account) => false,
+            (TokenScope::Full, _) => true,
+            (TokenScope::UploadOnly, Permission::Upload) => true,
+            (TokenScope::ReadOnly, Permission::Read) => true,
+            _ => false
+        }
+    }
+}
+
+/// API token as shown to the user; the ID is the hash of the token, never the token itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub device_id: String,
+    pub name: String,
+    pub scope: String,
+    pub created: NaiveDateTime
+}
+
+/// Session of the account as shown to the user; the ID is the hash of the session pass, never the pass itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub device_id: String,
+    pub created: NaiveDateTime,
+    pub last_used: NaiveDateTime,
+    pub current: bool
+}
+
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Valid(DeviceIdentity),
+    Expired,
+    NotFound
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecondFactorVerification {
+    /// The account doesn't have the TOTP enabled, no code is needed.
+    NotEnabled,
+    Valid,
+    Missing,
+    Invalid
 }
@@ -0,0 +1,62 @@
+use chrono::prelude::*;
+use chrono::Duration;
+
+/// Protection of the login and registration against brute-force attacks.
+///
+/// After `*_free_failures` failed logins of a username (or from an IP address) every further attempt is blocked for `base_delay`,
+/// doubled with each next failure up to `max_delay`. The counters are reset by a successful login (only the username one) or after
+/// `reset_after` without failures.
+#[derive(Debug, Clone)]
+pub struct ThrottlingConfig {
+    pub username_free_failures: u32,
+    pub ip_free_failures: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub reset_after: Duration,
+    /// Registrations allowed from a single IP address; the counter is reset after `registration_window` without registrations.
+    pub registrations_per_ip: u32,
+    pub registration_window: Duration
+}
+
+impl Default for ThrottlingConfig {
+    fn default() -> ThrottlingConfig {
+        ThrottlingConfig {
+            username_free_failures: 5,
+            ip_free_failures: 20,
+            base_delay: Duration::seconds(30),
+            max_delay: Duration::hours(1),
+            reset_after: Duration::days(1),
+            registrations_per_ip: 5,
+            registration_window: Duration::hours(1)
+        }
+    }
+}
+
+impl ThrottlingConfig {
+    /// Returns the time until which next logins are blocked, if they are.
+    pub fn login_blocked_until(&self, failures: u32, free_failures: u32, last_failure: &NaiveDateTime, now: &NaiveDateTime) -> Option<NaiveDateTime> {
+        if failures < free_failures || *now - *last_failure > self.reset_after {
+            return None;
+        }
+
+        // capped exponent, the delay is capped by `max_delay` long before anyway
+        let exponent = std::cmp::min(failures - free_failures, 20);
+        let delay = std::cmp::min(self.base_delay * 2i32.pow(exponent), self.max_delay);
+
+        Some(*last_failure + delay).filter(|until| until > now)
+    }
+
+    /// Returns the time until which next registrations are blocked, if they are.
+    pub fn registration_blocked_until(&self, registrations: u32, last_registration: &NaiveDateTime, now: &NaiveDateTime) -> Option<NaiveDateTime> {
+        if registrations < self.registrations_per_ip {
+            return None;
+        }
+
+        Some(*last_registration + self.registration_window).filter(|until| until > now)
+    }
+
+    /// Counters of attempts made before the returned time are not relevant anymore.
+    pub fn stale_threshold(&self, now: &NaiveDateTime) -> NaiveDateTime {
+        *now - std::cmp::max(self.reset_after, self.registration_window)
+    }
+}
@@ -0,0 +1,166 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::util::fixed_time_eq;
+use failure::Error;
+use hex;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Length of the time step in seconds, as expected by the authenticator apps.
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Number of steps before and after the current one which are accepted, to tolerate clock drift.
+const ALLOWED_DRIFT: u64 = 1;
+const BACKUP_CODES_COUNT: usize = 10;
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a random TOTP secret; returns it hex encoded (to be stored).
+pub fn generate_secret() -> String {
+    let random_bytes: [u8; 20] = rand::thread_rng().gen();
+    hex::encode(&random_bytes)
+}
+
+/// Returns the secret in the form the authenticator apps accept - base32, and `otpauth://` URI (usually shown as QR code).
+pub fn export_secret(secret: &str) -> Result<(String, String), Error> {
+    let encoded = base32_encode(&hex::decode(secret)?);
+    let uri = format!("otpauth://totp/RBackup?secret={}&issuer=RBackup&algorithm=SHA1&digits={}&period={}", encoded, DIGITS, STEP_SECS);
+
+    Ok((encoded, uri))
+}
+
+/// Verifies the code against the secret, returns the time step it belongs to. The caller must reject steps which were already used.
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> Result<Option<u64>, Error> {
+    let secret = hex::decode(secret)?;
+    let current_step = unix_time / STEP_SECS;
+
+    for step in current_step.saturating_sub(ALLOWED_DRIFT)..=current_step + ALLOWED_DRIFT {
+        if fixed_time_eq(generate_code(&secret, step).as_bytes(), code.trim().as_bytes()) {
+            return Ok(Some(step));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Generates one-time backup codes; returns them together with their hashes (to be stored).
+pub fn generate_backup_codes() -> Vec<(String, String)> {
+    (0..BACKUP_CODES_COUNT)
+        .map(|_| {
+            let random_bytes: [u8; 5] = rand::thread_rng().gen();
+            let code = hex::encode(&random_bytes);
+            let code_hash = hash_backup_code(&code);
+            (code, code_hash)
+        })
+        .collect()
+}
+
+pub fn hash_backup_code(code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(code.trim().to_lowercase().as_bytes());
+    hex::encode(&hasher.result())
+}
+
+/// Looks like a backup code rather than a TOTP code.
+pub fn is_backup_code(code: &str) -> bool {
+    code.trim().len() != DIGITS as usize
+}
+
+/// RFC 6238 (HMAC-SHA1 variant).
+fn generate_code(secret: &[u8], step: u64) -> String {
+    let mut hmac = Hmac::new(Sha1::new(), secret);
+    hmac.input(&step.to_be_bytes());
+    let result = hmac.result();
+    let hash = result.code();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        // at most 4 bits are left from the previous byte
+        buffer = ((buffer << 8) | byte as u32) & 0xfff;
+        bits += 8;
+
+        while bits >= 5 {
+            result.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 0x1f) as usize] as char);
+            bits -= 5;
+        }
+    }
+
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use hex;
+
+    use super::{base32_encode, export_secret, generate_code, verify};
+
+    // the SHA-1 seed of RFC 6238, appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn encodes_base32() {
+        // RFC 4648, section 10 (without padding)
+        assert_eq!("", base32_encode(b""));
+        assert_eq!("MY", base32_encode(b"f"));
+        assert_eq!("MZXQ", base32_encode(b"fo"));
+        assert_eq!("MZXW6", base32_encode(b"foo"));
+        assert_eq!("MZXW6YQ", base32_encode(b"foob"));
+        assert_eq!("MZXW6YTB", base32_encode(b"fooba"));
+        assert_eq!("MZXW6YTBOI", base32_encode(b"foobar"));
+    }
+
+    #[test]
+    fn exports_secret() {
+        let (encoded, uri) = export_secret(&hex::encode(RFC_SECRET)).unwrap();
+
+        assert_eq!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", encoded);
+        assert_eq!("otpauth://totp/RBackup?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=RBackup&algorithm=SHA1&digits=6&period=30", uri);
+    }
+
+    #[test]
+    fn generates_rfc_6238_codes() {
+        // the RFC lists 8 digits codes, these are their last 6 digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130")
+        ];
+
+        for &(unix_time, code) in vectors.iter() {
+            assert_eq!(code, generate_code(RFC_SECRET, unix_time / 30), "time {}", unix_time);
+        }
+    }
+
+    #[test]
+    fn verifies_codes_within_drift() {
+        let secret = hex::encode(RFC_SECRET);
+
+        assert_eq!(Some(1), verify(&secret, "287082", 59).unwrap());
+        assert_eq!(Some(1), verify(&secret, " 287082 ", 59).unwrap());
+        assert_eq!(Some(1), verify(&secret, "287082", 59 - 30).unwrap());
+        assert_eq!(Some(1), verify(&secret, "287082", 59 + 30).unwrap());
+        assert_eq!(None, verify(&secret, "287082", 59 + 60).unwrap());
+        assert_eq!(None, verify(&secret, "287083", 59).unwrap());
+        assert_eq!(None, verify(&secret, "", 59).unwrap());
+    }
+}
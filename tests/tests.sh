@@ -1,5 +1,7 @@
 #!/usr/bin/env bash
 
+compose_file=$1
+
 function upload() {
     session_id=$1
     file_name=$2
@@ -45,6 +47,605 @@ function list_files() {
     echo ${result}
 }
 
+function large_file() {
+    session_id=$1
+    file_name="largeFile.dat"
+
+    # sparse file, bigger than 4 GiB
+    truncate -s 5G "${file_name}"
+
+    sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+    size=$(stat -c %s "${file_name}")
+
+    version_id=$(curl -sS --header "Content-Type: multipart/form-data" -H "RBackup-Session-Pass: ${session_id}" \
+        -F file=@"${file_name}" -F file-hash="${sha}" \
+        -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=${size}&mtime=123" | jq '.versions[-1].version')
+
+    rm "${file_name}"
+
+    headers=$(curl -sS -D - -o /dev/null -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}")
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}" | sha256sum | awk '{ print $1 }')
+
+    content_length=$(echo "${headers}" | grep -i '^Content-Length:' | awk '{ print $2 }' | tr -d '\r')
+    hash_header=$(echo "${headers}" | grep -i '^RBackup-File-Hash:' | awk '{ print $2 }' | tr -d '\r')
+
+    assert "${size}" "${content_length}" "Content-Length of large file was different" \
+     && assert "${sha}" "${hash_header}" "Hash header of large file was different" \
+     && assert "${sha}" "${downloaded_sha}" "Hash of downloaded large file was different"
+}
+
+function unchanged_upload() {
+    file_name="unchanged.dat"
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-unchanged&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    head -c 1024 /dev/urandom > "${file_name}"
+    sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+
+    first_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -F file=@"${file_name}" -F file-hash="${sha}" \
+        -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=1024&mtime=1000")
+    second_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -F file=@"${file_name}" -F file-hash="${sha}" \
+        -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=1024&mtime=2000")
+    by_hash_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" \
+        -X PUT "http://localhost:3369/upload/byHash?file_path=${file_name}&size=1024&mtime=3000&sha256=${sha}")
+
+    rm "${file_name}"
+
+    versions=$(list_files ${session_id} | jq -c '.[0].versions | map({hash: .hash, mtime: .mtime})')
+
+    assert "200" "${first_status}" "Could not upload the file" \
+     && assert "208" "${second_status}" "Upload of unchanged content didn't return 208" \
+     && assert "208" "${by_hash_status}" "Upload of unchanged content by hash didn't return 208" \
+     && assert "[{\"hash\":\"${sha}\",\"mtime\":\"1970-01-01T00:00:03\"}]" "${versions}" "Unchanged content created new version or mtime was not updated"
+}
+
+function upload_by_hash() {
+    file_name="byHash.dat"
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-by-hash&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    head -c 1024 /dev/urandom > "${file_name}"
+    sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+
+    curl -sS -o /dev/null -H "RBackup-Session-Pass: ${session_id}" -F file=@"${file_name}" -F file-hash="${sha}" \
+        -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=1024&mtime=1000"
+
+    unknown_sha=$(head -c 1024 /dev/urandom | sha256sum | awk '{ print $1 }')
+
+    rm "${file_name}"
+
+    by_hash_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" \
+        -X PUT "http://localhost:3369/upload/byHash?file_path=copyOfByHash.dat&size=1024&mtime=2000&sha256=${sha}")
+    unknown_hash_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" \
+        -X PUT "http://localhost:3369/upload/byHash?file_path=unknown.dat&size=1024&mtime=2000&sha256=${unknown_sha}")
+
+    copy=$(list_files ${session_id} | jq -c '.[] | select(.original_name == "copyOfByHash.dat") | .versions')
+    copy_version_id=$(echo "${copy}" | jq '.[0].version')
+    unknown_files_count=$(list_files ${session_id} | jq '[.[] | select(.original_name == "unknown.dat")] | length')
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/download?file_version_id=${copy_version_id}" | sha256sum | awk '{ print $1 }')
+
+    assert "200" "${by_hash_status}" "Could not create file version by hash" \
+     && assert "1" "$(echo "${copy}" | jq 'length')" "Upload by hash didn't create exactly one version" \
+     && assert "${sha}" "$(echo "${copy}" | jq -r '.[0].hash')" "Hash of version created by hash was different" \
+     && assert "${sha}" "${downloaded_sha}" "Hash of file downloaded after upload by hash was different" \
+     && assert "404" "${unknown_hash_status}" "Upload by unknown hash didn't return 404" \
+     && assert "0" "${unknown_files_count}" "Upload by unknown hash created a file"
+}
+
+function resumable_upload() {
+    file_name="resumable.dat"
+    base_url="http://localhost:3369/upload/session"
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-resumable&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    head -c 3000 /dev/urandom > "${file_name}"
+    sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+
+    head -c 1024 "${file_name}" > "${file_name}.0"
+    tail -c +1025 "${file_name}" | head -c 1024 > "${file_name}.1"
+    tail -c +2049 "${file_name}" > "${file_name}.2"
+
+    function put_chunk() {
+        index=$1
+        chunk_file=$2
+        chunk_sha=${3:-$(sha256sum "${chunk_file}" | awk '{ print $1 }')}
+
+        curl -sS -w " %{http_code}" -H "RBackup-Session-Pass: ${session_id}" -H "Content-Type: application/octet-stream" --data-binary @"${chunk_file}" \
+            -X PUT "${base_url}/chunk?upload_session_id=${upload_session_id}&index=${index}&sha256=${chunk_sha}"
+    }
+
+    upload_session_id=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X POST "${base_url}?file_path=${file_name}&size=3000&mtime=1000&sha256=${sha}" | jq -r '.upload_session_id')
+
+    first_chunk=$(put_chunk 0 "${file_name}.0")
+    # the connection "drops" before the second chunk is sent
+    third_chunk=$(put_chunk 2 "${file_name}.2")
+    incomplete_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X POST "${base_url}/finalize?upload_session_id=${upload_session_id}")
+    progress=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "${base_url}?upload_session_id=${upload_session_id}" | jq -c '.')
+    mismatch_chunk=$(put_chunk 1 "${file_name}.1" "${sha}")
+    oversized_chunk=$(put_chunk 3 "${file_name}")
+    second_chunk=$(put_chunk 1 "${file_name}.1")
+    replaced_chunk=$(put_chunk 0 "${file_name}.0")
+
+    finalize_status=$(curl -sS -o finalized.json -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X POST "${base_url}/finalize?upload_session_id=${upload_session_id}")
+    version_id=$(jq '.versions[-1].version' finalized.json)
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}" | sha256sum | awk '{ print $1 }')
+    finished_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "${base_url}?upload_session_id=${upload_session_id}")
+
+    aborted_session_id=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X POST "${base_url}?file_path=${file_name}&size=3000&mtime=2000&sha256=${sha}" | jq -r '.upload_session_id')
+    upload_session_id=${aborted_session_id}
+    put_chunk 0 "${file_name}.0" > /dev/null
+    abort_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "${base_url}?upload_session_id=${aborted_session_id}")
+    aborted_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "${base_url}?upload_session_id=${aborted_session_id}")
+
+    rm "${file_name}" "${file_name}.0" "${file_name}.1" "${file_name}.2" finalized.json
+
+    assert '{"received": 1024} 200' "${first_chunk}" "First chunk was not received" \
+     && assert '{"received": 1024} 200' "${third_chunk}" "Chunk after a gap was not received or the gap was skipped" \
+     && assert "400" "${incomplete_status}" "Incomplete upload was finalized" \
+     && assert '{"received":1024}' "${progress}" "Progress of upload didn't stop at the gap" \
+     && assert "412" "$(echo "${mismatch_chunk}" | awk '{ print $NF }')" "Chunk with wrong hash was accepted" \
+     && assert "400" "$(echo "${oversized_chunk}" | awk '{ print $NF }')" "Chunk exceeding size of the file was accepted" \
+     && assert '{"received": 3000} 200' "${second_chunk}" "Missing chunk was not received" \
+     && assert '{"received": 3000} 200' "${replaced_chunk}" "Chunk was not replaced" \
+     && assert "200" "${finalize_status}" "Could not finalize the upload" \
+     && assert "${sha}" "${downloaded_sha}" "Hash of file downloaded after resumable upload was different" \
+     && assert "404" "${finished_status}" "Upload session was not removed after finalization" \
+     && assert "200" "${abort_status}" "Could not abort the upload" \
+     && assert "404" "${aborted_status}" "Upload session was not removed after abort"
+}
+
+function change_password() {
+    file_name=$1
+
+    # log in again, the global session_id was overwritten by the calls on the second account
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests&username=rbackup&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    sha=$(sha256sum "$file_name" | awk '{ print $1 }')
+    version_id=$(list_files ${session_id} | jq '.[0].versions[0].version')
+    token=$(curl -sS -X POST -H "RBackup-Session-Pass: ${session_id}" -d "name=password-change&scope=read" "http://localhost:3369/account/tokens" | jq '.token' | sed -e 's/^"//' -e 's/"$//')
+    token_before_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/list/files")
+
+    new_session_id=$(curl -sS -X POST -H "RBackup-Session-Pass: ${session_id}" -d "old_password=rbackup&new_password=rbackup-changed" "http://localhost:3369/account/password" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    old_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/files")
+    old_password_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "device_id=docker-tests&username=rbackup&password=rbackup" "http://localhost:3369/account/login")
+    token_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/list/files")
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${new_session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}" | sha256sum | awk '{ print $1 }')
+    new_password_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "device_id=docker-tests&username=rbackup&password=rbackup-changed" "http://localhost:3369/account/login")
+
+    assert "401" "${old_session_status}" "Old session was still valid after password change" \
+     && assert "401" "${old_password_status}" "Old password was still valid after password change" \
+     && assert "200" "${token_before_status}" "API token was not valid before password change" \
+     && assert "401" "${token_status}" "API token was still valid after password change" \
+     && assert "${sha}" "${downloaded_sha}" "Hash of file downloaded after password change was different" \
+     && assert "200" "${new_password_status}" "Could not login with the new password"
+}
+
+function recover_account() {
+    file_name=$1
+
+    sha=$(sha256sum "$file_name" | awk '{ print $1 }')
+
+    recovery_key=$(curl -sS -X POST -d "username=rbackup3&password=rbackup&recovery_key=true" "http://localhost:3369/account/register" | jq '.recovery_key' | sed -e 's/^"//' -e 's/"$//')
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests&username=rbackup3&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    version_id=$(upload ${session_id} "${file_name}" | jq '.versions[-1].version')
+
+    invalid_key_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "username=rbackup3&recovery_key=invalid&new_password=rbackup-recovered" "http://localhost:3369/account/recover")
+    recovery=$(curl -sS -w "\n%{http_code}" -X POST -d "username=rbackup3&recovery_key=${recovery_key}&new_password=rbackup-recovered" "http://localhost:3369/account/recover")
+    recovery_status=$(echo "${recovery}" | tail -n 1)
+    new_recovery_key=$(echo "${recovery}" | head -n 1 | jq '.recovery_key' | sed -e 's/^"//' -e 's/"$//')
+    reused_key_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "username=rbackup3&recovery_key=${recovery_key}&new_password=rbackup-reused" "http://localhost:3369/account/recover")
+    reused_password_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "device_id=docker-tests&username=rbackup3&password=rbackup-reused" "http://localhost:3369/account/login")
+    new_session_id=$(curl -sS -X POST -d "device_id=docker-tests&username=rbackup3&password=rbackup-recovered" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${new_session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}" | sha256sum | awk '{ print $1 }')
+    new_key_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "username=rbackup3&recovery_key=${new_recovery_key}&new_password=rbackup-recovered" "http://localhost:3369/account/recover")
+
+    # the successful recovery has reset the counter of failed attempts
+    for i in 1 2 3 4 5; do
+        curl -sS -o /dev/null -X POST -d "username=rbackup3&recovery_key=invalid&new_password=rbackup-recovered" "http://localhost:3369/account/recover"
+    done
+
+    blocked_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "username=rbackup3&recovery_key=invalid&new_password=rbackup-recovered" "http://localhost:3369/account/recover")
+
+    assert "403" "${invalid_key_status}" "Invalid recovery key was accepted" \
+     && assert "200" "${recovery_status}" "Could not recover account using the recovery key" \
+     && assert "64" "${#new_recovery_key}" "New recovery key was not returned" \
+     && assert "403" "${reused_key_status}" "Recovery key was accepted twice" \
+     && assert "401" "${reused_password_status}" "Password set by reused recovery key is valid" \
+     && assert "${sha}" "${downloaded_sha}" "Hash of file downloaded after recovery was different" \
+     && assert "200" "${new_key_status}" "Could not recover account using the new recovery key" \
+     && assert "429" "${blocked_status}" "Recovery was not blocked after too many failed attempts"
+}
+
+function sessions() {
+    session_id=$1
+
+    other_session_id=$(curl -sS -X POST -d "device_id=docker-tests-other&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    sessions_count=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/account/sessions" | jq 'length')
+    revoked=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X DELETE "http://localhost:3369/account/sessions?device_id=docker-tests-other" | jq '.revoked')
+    revoked_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${other_session_id}" -X GET "http://localhost:3369/list/files")
+    logout_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X POST "http://localhost:3369/account/logout")
+    logged_out_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/files")
+
+    assert "2" "${sessions_count}" "Number of sessions was different" \
+     && assert "1" "${revoked}" "Number of revoked sessions was different" \
+     && assert "401" "${revoked_session_status}" "Revoked session was still valid" \
+     && assert "200" "${logout_status}" "Could not log out" \
+     && assert "401" "${logged_out_session_status}" "Session was still valid after logout"
+}
+
+function session_renewal() {
+    credentials="device_id=docker-tests-renewal&username=rbackup2&password=rbackup"
+
+    first_login=$(curl -sS -w "\n%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+    first_status=$(echo "${first_login}" | tail -n 1)
+    first_session_id=$(echo "${first_login}" | head -n 1 | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    second_login=$(curl -sS -w "\n%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+    second_status=$(echo "${second_login}" | tail -n 1)
+    second_session_id=$(echo "${second_login}" | head -n 1 | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    first_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${first_session_id}" -X GET "http://localhost:3369/list/files")
+    second_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${second_session_id}" -X GET "http://localhost:3369/list/files")
+    sessions_count=$(curl -sS -H "RBackup-Session-Pass: ${second_session_id}" -X GET "http://localhost:3369/account/sessions" | jq '[.[] | select(.device_id == "docker-tests-renewal")] | length')
+
+    assert "201" "${first_status}" "First login of the device didn't create new session" \
+     && assert "200" "${second_status}" "Second login of the device didn't renew the session" \
+     && assert "401" "${first_session_status}" "Replaced session was still valid" \
+     && assert "200" "${second_session_status}" "Renewed session was not valid" \
+     && assert "1" "${sessions_count}" "Device had more sessions after renewal"
+}
+
+function validation() {
+    empty_password_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "username=rbackup4&password=" "http://localhost:3369/account/register")
+    invalid_username_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "Content-Type: application/json" -d '{"username": "rback up", "password": "rbackup"}' "http://localhost:3369/account/register")
+    legacy_login_status=$(curl -sS -o /dev/null -w "%{http_code}" "http://localhost:3369/account/login?device_id=docker-tests&username=rbackup2&password=rbackup")
+
+    assert "400" "${empty_password_status}" "Empty password was accepted" \
+     && assert "400" "${invalid_username_status}" "Invalid username was accepted" \
+     && assert "404" "${legacy_login_status}" "Deprecated GET login was available"
+}
+
+function throttling() {
+    curl -sS -X POST -d "username=rbackup5&password=rbackup" "http://localhost:3369/account/register" > /dev/null
+
+    for i in 1 2 3 4 5; do
+        curl -sS -o /dev/null -X POST -d "device_id=docker-tests&username=rbackup5&password=wrong" "http://localhost:3369/account/login"
+    done
+
+    blocked_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "device_id=docker-tests&username=rbackup5&password=rbackup" "http://localhost:3369/account/login")
+    other_user_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "device_id=docker-tests-throttling&username=rbackup2&password=rbackup" "http://localhost:3369/account/login")
+
+    assert "429" "${blocked_status}" "Login was not blocked after too many failed attempts" \
+     && assert "201" "${other_user_status}" "Login of other account was blocked"
+}
+
+# Prints the TOTP code of the base32 secret for the time step.
+function totp_code() {
+    python3 - "$1" "$2" <<'EOF'
+import base64, hashlib, hmac, struct, sys
+
+key = base64.b32decode(sys.argv[1] + "=" * (-len(sys.argv[1]) % 8))
+digest = hmac.new(key, struct.pack(">Q", int(sys.argv[2])), hashlib.sha1).digest()
+offset = digest[-1] & 0x0f
+print("%06d" % ((struct.unpack(">I", digest[offset:offset + 4])[0] & 0x7fffffff) % 1000000))
+EOF
+}
+
+function totp() {
+    credentials="device_id=docker-tests-totp&username=rbackup2&password=rbackup"
+
+    session_id=$(curl -sS -X POST -d "${credentials}" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    secret=$(curl -sS -X POST -H "RBackup-Session-Pass: ${session_id}" "http://localhost:3369/account/totp" | jq '.secret' | sed -e 's/^"//' -e 's/"$//')
+    invalid_code_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "code=abcdef" "http://localhost:3369/account/totp/confirm")
+    # the enrollment was not confirmed, so the login doesn't need the code yet
+    login=$(curl -sS -w "\n%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+    login_status=$(echo "${login}" | tail -n 1)
+    session_id=$(echo "${login}" | head -n 1 | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    disable_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "password=rbackup&code=000000" "http://localhost:3369/account/totp/disable")
+
+    # the server accepts codes of the neighbouring time steps, so the next step can be used right away
+    step=$(( $(date +%s) / 30 ))
+    code=$(totp_code "${secret}" ${step})
+    next_code=$(totp_code "${secret}" $(( step + 1 )))
+
+    confirm=$(curl -sS -w "\n%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "code=${code}" "http://localhost:3369/account/totp/confirm")
+    confirm_status=$(echo "${confirm}" | tail -n 1)
+    backup_codes=$(echo "${confirm}" | head -n 1)
+
+    missing_code=$(curl -sS -w "\n%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+    missing_code_status=$(echo "${missing_code}" | tail -n 1)
+    second_factor=$(echo "${missing_code}" | head -n 1 | jq -r '.second_factor_required')
+    replayed_code_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "${credentials}&totp_code=${code}" "http://localhost:3369/account/login")
+    code_login_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "${credentials}&totp_code=${next_code}" "http://localhost:3369/account/login")
+
+    backup_code=$(echo "${backup_codes}" | jq -r '.backup_codes[0]')
+    backup_code_login=$(curl -sS -w "\n%{http_code}" -X POST -d "${credentials}&totp_code=${backup_code}" "http://localhost:3369/account/login")
+    backup_code_login_status=$(echo "${backup_code_login}" | tail -n 1)
+    session_id=$(echo "${backup_code_login}" | head -n 1 | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    reused_backup_code_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "${credentials}&totp_code=${backup_code}" "http://localhost:3369/account/login")
+
+    # the following tests log in without the code
+    enabled_disable_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" \
+        -d "password=rbackup&code=$(echo "${backup_codes}" | jq -r '.backup_codes[1]')" "http://localhost:3369/account/totp/disable")
+    disabled_login_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+
+    assert "32" "${#secret}" "TOTP secret was not returned" \
+     && assert "403" "${invalid_code_status}" "Invalid TOTP code was accepted" \
+     && assert "200" "${login_status}" "Login required the code of not confirmed TOTP" \
+     && assert "404" "${disable_status}" "Not enabled TOTP was disabled" \
+     && assert "200" "${confirm_status}" "Could not confirm the TOTP" \
+     && assert "10" "$(echo "${backup_codes}" | jq '.backup_codes | length')" "Number of backup codes was different" \
+     && assert "401" "${missing_code_status}" "Login without the code was not rejected" \
+     && assert "totp" "${second_factor}" "Required second factor was different" \
+     && assert "403" "${replayed_code_status}" "Already used TOTP code was accepted" \
+     && assert "200" "${code_login_status}" "Could not log in with the TOTP code" \
+     && assert "200" "${backup_code_login_status}" "Could not log in with the backup code" \
+     && assert "403" "${reused_backup_code_status}" "Backup code was accepted twice" \
+     && assert "200" "${enabled_disable_status}" "Could not disable the TOTP" \
+     && assert "200" "${disabled_login_status}" "Login required the code of disabled TOTP"
+}
+
+function api_tokens() {
+    file_name=$1
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-tokens&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    version_id=$(list_files ${session_id} | jq '.[0].versions[-1].version')
+    file_id=$(list_files ${session_id} | jq '.[0].id')
+
+    created=$(curl -sS -X POST -H "RBackup-Session-Pass: ${session_id}" -d "name=nightly&scope=upload" "http://localhost:3369/account/tokens")
+    token=$(echo "${created}" | jq '.token' | sed -e 's/^"//' -e 's/"$//')
+    token_id=$(echo "${created}" | jq '.id' | sed -e 's/^"//' -e 's/"$//')
+
+    invalid_scope_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "name=nightly&scope=admin" "http://localhost:3369/account/tokens")
+    unknown_device_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "name=nightly&scope=upload&device_id=docker-tests-unknown" "http://localhost:3369/account/tokens")
+    upload_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -F file=@"${file_name}" -F file-hash="$(sha256sum "${file_name}" | awk '{ print $1 }')" \
+        -X PUT "http://localhost:3369/upload?file_path=tokens.dat&size=1520&mtime=123")
+    list_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/list/files")
+    download_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/download?file_version_id=${version_id}")
+    remove_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X DELETE "http://localhost:3369/remove/file?file_id=${file_id}")
+    token_tokens_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/account/tokens")
+    tokens_count=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/account/tokens" | jq 'length')
+    revoke_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "http://localhost:3369/account/tokens?id=${token_id}")
+    revoked_token_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "Authorization: Bearer ${token}" -X GET "http://localhost:3369/list/files")
+
+    assert "400" "${invalid_scope_status}" "Token with invalid scope was created" \
+     && assert "404" "${unknown_device_status}" "Token of unknown device was created" \
+     && assert "200" "${upload_status}" "Upload-only token could not upload" \
+     && assert "403" "${list_status}" "Upload-only token could list files" \
+     && assert "403" "${download_status}" "Upload-only token could download" \
+     && assert "403" "${remove_status}" "Upload-only token could delete" \
+     && assert "403" "${token_tokens_status}" "Token could manage the account" \
+     && assert "1" "${tokens_count}" "Number of tokens was different" \
+     && assert "200" "${revoke_status}" "Could not revoke the token" \
+     && assert "401" "${revoked_token_status}" "Revoked token was still valid"
+}
+
+function device_roles() {
+    file_name=$1
+
+    admin_session_id=$(curl -sS -X POST -d "device_id=docker-tests-roles-admin&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    reader_session_id=$(curl -sS -X POST -d "device_id=docker-tests-roles-reader&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    file_id=$(list_files ${admin_session_id} | jq '.[0].id')
+
+    invalid_role_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${admin_session_id}" -X PUT "http://localhost:3369/account/devices/role?device_id=docker-tests-roles-reader&role=owner")
+    own_role_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${admin_session_id}" -X PUT "http://localhost:3369/account/devices/role?device_id=docker-tests-roles-admin&role=read-only")
+    set_role_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${admin_session_id}" -X PUT "http://localhost:3369/account/devices/role?device_id=docker-tests-roles-reader&role=read-only")
+
+    list_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${reader_session_id}" -X GET "http://localhost:3369/list/files")
+    upload_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${reader_session_id}" -F file=@"${file_name}" -F file-hash="$(sha256sum "${file_name}" | awk '{ print $1 }')" \
+        -X PUT "http://localhost:3369/upload?file_path=roles.dat&size=1520&mtime=123")
+    remove_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${reader_session_id}" -X DELETE "http://localhost:3369/remove/file?file_id=${file_id}")
+    escalation_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${reader_session_id}" -X PUT "http://localhost:3369/account/devices/role?device_id=docker-tests-roles-reader&role=admin")
+    reader_role=$(curl -sS -H "RBackup-Session-Pass: ${admin_session_id}" -X GET "http://localhost:3369/account/devices/roles" | jq '.[] | select(.device_id == "docker-tests-roles-reader") | .role' | sed -e 's/^"//' -e 's/"$//')
+
+    assert "400" "${invalid_role_status}" "Invalid role was accepted" \
+     && assert "409" "${own_role_status}" "Device could change its own role" \
+     && assert "200" "${set_role_status}" "Could not change role of the device" \
+     && assert "200" "${list_status}" "Read-only device could not list files" \
+     && assert "403" "${upload_status}" "Read-only device could upload" \
+     && assert "403" "${remove_status}" "Read-only device could delete" \
+     && assert "403" "${escalation_status}" "Read-only device could change roles" \
+     && assert "read-only" "${reader_role}" "Role of the device was different"
+}
+
+function retention() {
+    file_name="retention.dat"
+    policy_url="http://localhost:3369/retention/policy?device_id=docker-tests-retention"
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-retention&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    for i in 1 2 3; do
+        head -c 1024 /dev/urandom > "${file_name}"
+        sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+        curl -sS -o /dev/null -H "RBackup-Session-Pass: ${session_id}" -F file=@"${file_name}" -F file-hash="${sha}" \
+            -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=1024&mtime=${i}000"
+    done
+
+    rm "${file_name}"
+
+    older_versions=$(list_files ${session_id} | jq -c '.[0].versions | map(.version) | sort | .[0:2]')
+
+    set_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X PUT "${policy_url}&keep_all_hours=0&keep_daily_days=0&keep_weekly_weeks=0")
+    policy=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "${policy_url}" | jq -c '.')
+    dry_run=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/retention/dryRun?device_id=docker-tests-retention" | jq -c 'sort')
+    unknown_set_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X PUT "http://localhost:3369/retention/policy?device_id=docker-tests-unknown&keep_all_hours=0&keep_daily_days=0&keep_weekly_weeks=0")
+    unknown_dry_run_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/retention/dryRun?device_id=docker-tests-unknown")
+    remove_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "${policy_url}")
+    repeated_remove_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "${policy_url}")
+    # there is no account or global policy
+    dry_run_without_policy=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/retention/dryRun?device_id=docker-tests-retention" | jq -c '.')
+
+    assert "200" "${set_status}" "Could not set the retention policy" \
+     && assert '{"keep_all_hours":0,"keep_daily_days":0,"keep_weekly_weeks":0}' "${policy}" "Retention policy was different" \
+     && assert "${older_versions}" "${dry_run}" "Dry run didn't return all but the newest version" \
+     && assert "404" "${unknown_set_status}" "Retention policy of unknown device was set" \
+     && assert "404" "${unknown_dry_run_status}" "Dry run for unknown device succeeded" \
+     && assert "200" "${remove_status}" "Could not remove the retention policy" \
+     && assert "404" "${repeated_remove_status}" "Retention policy was removed twice" \
+     && assert "[]" "${dry_run_without_policy}" "Dry run without policy returned some versions"
+}
+
+function cross_account() {
+    file_name="crossAccount.dat"
+
+    # random content, so it can't be found in the other account by its hash
+    head -c 2048 /dev/urandom > "${file_name}"
+    sha=$(sha256sum "${file_name}" | awk '{ print $1 }')
+    size=$(stat -c %s "${file_name}")
+
+    # the victim and the attacker use the same device ID
+    victim_session_id=$(curl -sS -X POST -d "device_id=docker-tests-cross&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    attacker_session_id=$(curl -sS -X POST -d "device_id=docker-tests-cross&username=rbackup3&password=rbackup-recovered" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    uploaded=$(curl -sS -H "RBackup-Session-Pass: ${victim_session_id}" -F file=@"${file_name}" -F file-hash="${sha}" \
+        -X PUT "http://localhost:3369/upload?file_path=${file_name}&size=${size}&mtime=123")
+    file_id=$(echo "${uploaded}" | jq '.id')
+    version_id=$(echo "${uploaded}" | jq '.versions[-1].version')
+    upload_session_id=$(curl -sS -H "RBackup-Session-Pass: ${victim_session_id}" -X POST "http://localhost:3369/upload/session?file_path=${file_name}&size=${size}&mtime=123&sha256=${sha}" | jq '.upload_session_id' | sed -e 's/^"//' -e 's/"$//')
+    victim_hashed_session_id=$(curl -sS -H "RBackup-Session-Pass: ${victim_session_id}" -X GET "http://localhost:3369/account/sessions" | jq '.[] | select(.current) | .id' | sed -e 's/^"//' -e 's/"$//')
+    token_id=$(curl -sS -X POST -H "RBackup-Session-Pass: ${victim_session_id}" -d "name=cross&scope=full" "http://localhost:3369/account/tokens" | jq '.id' | sed -e 's/^"//' -e 's/"$//')
+    curl -sS -o /dev/null -H "RBackup-Session-Pass: ${victim_session_id}" -X PUT "http://localhost:3369/retention/policy?device_id=docker-tests-cross&keep_all_hours=1&keep_daily_days=1&keep_weekly_weeks=1"
+
+    attacker() {
+        curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${attacker_session_id}" "$@"
+    }
+
+    download_status=$(attacker -X GET "http://localhost:3369/download?file_version_id=${version_id}")
+    remove_version_status=$(attacker -X DELETE "http://localhost:3369/remove/fileVersion?file_version_id=${version_id}")
+    remove_file_status=$(attacker -X DELETE "http://localhost:3369/remove/file?file_id=${file_id}")
+    upload_by_hash_status=$(attacker -X PUT "http://localhost:3369/upload/byHash?file_path=${file_name}&size=${size}&mtime=123&sha256=${sha}")
+    upload_session_status=$(attacker -X GET "http://localhost:3369/upload/session?upload_session_id=${upload_session_id}")
+    upload_chunk_status=$(attacker -X PUT --data-binary @"${file_name}" "http://localhost:3369/upload/session/chunk?upload_session_id=${upload_session_id}&index=0&sha256=${sha}")
+    finalize_status=$(attacker -X POST "http://localhost:3369/upload/session/finalize?upload_session_id=${upload_session_id}")
+    abort_status=$(attacker -X DELETE "http://localhost:3369/upload/session?upload_session_id=${upload_session_id}")
+    revoke_session_status=$(attacker -X DELETE "http://localhost:3369/account/sessions?session_id=${victim_hashed_session_id}")
+    revoke_token_status=$(attacker -X DELETE "http://localhost:3369/account/tokens?id=${token_id}")
+    remove_policy_status=$(attacker -X DELETE "http://localhost:3369/retention/policy?device_id=docker-tests-cross")
+    attacker_files_count=$(curl -sS -H "RBackup-Session-Pass: ${attacker_session_id}" -X GET "http://localhost:3369/list/files" | jq "[.[] | select(.id == ${file_id})] | length")
+
+    downloaded_sha=$(curl -sS -H "RBackup-Session-Pass: ${victim_session_id}" -X GET "http://localhost:3369/download?file_version_id=${version_id}" | sha256sum | awk '{ print $1 }')
+    victim_upload_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${victim_session_id}" -X DELETE "http://localhost:3369/upload/session?upload_session_id=${upload_session_id}")
+    victim_policy_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${victim_session_id}" -X DELETE "http://localhost:3369/retention/policy?device_id=docker-tests-cross")
+    victim_token_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${victim_session_id}" -X DELETE "http://localhost:3369/account/tokens?id=${token_id}")
+
+    rm "${file_name}"
+
+    assert "404" "${download_status}" "File version of other account could be downloaded" \
+     && assert "404" "${remove_version_status}" "File version of other account could be deleted" \
+     && assert "404" "${remove_file_status}" "File of other account could be deleted" \
+     && assert "404" "${upload_by_hash_status}" "Content of other account could be used for upload by hash" \
+     && assert "404" "${upload_session_status}" "Upload session of other account could be read" \
+     && assert "404" "${upload_chunk_status}" "Chunk could be uploaded into upload session of other account" \
+     && assert "404" "${finalize_status}" "Upload session of other account could be finalized" \
+     && assert "404" "${abort_status}" "Upload session of other account could be aborted" \
+     && assert "404" "${revoke_session_status}" "Session of other account could be revoked" \
+     && assert "404" "${revoke_token_status}" "API token of other account could be revoked" \
+     && assert "404" "${remove_policy_status}" "Retention policy of other account could be removed" \
+     && assert "0" "${attacker_files_count}" "File of other account was listed" \
+     && assert "${sha}" "${downloaded_sha}" "File was changed by other account" \
+     && assert "200" "${victim_upload_session_status}" "Upload session was changed by other account" \
+     && assert "200" "${victim_policy_status}" "Retention policy was removed by other account" \
+     && assert "200" "${victim_token_status}" "API token was revoked by other account"
+}
+
+function devices() {
+    file_name=$1
+
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-devices&device_os=Linux&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    old_session_id=$(curl -sS -X POST -d "device_id=docker-tests-devices-old&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    upload ${old_session_id} "${file_name}" > /dev/null
+
+    rename_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X PUT "http://localhost:3369/account/devices/name?device_id=docker-tests-devices-old&name=Old%20laptop")
+    rename_unknown_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X PUT "http://localhost:3369/account/devices/name?device_id=docker-tests-unknown&name=Unknown")
+    devices=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/devices")
+    os=$(echo "${devices}" | jq '.[] | select(.id == "docker-tests-devices") | .os' | sed -e 's/^"//' -e 's/"$//')
+    old_name=$(echo "${devices}" | jq '.[] | select(.id == "docker-tests-devices-old") | .name' | sed -e 's/^"//' -e 's/"$//')
+    old_has_backup=$(echo "${devices}" | jq '.[] | select(.id == "docker-tests-devices-old") | .last_backup != null')
+
+    current_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "http://localhost:3369/account/devices?device_id=docker-tests-devices")
+    removed_files=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X DELETE "http://localhost:3369/account/devices?device_id=docker-tests-devices-old&delete_files=true" | jq '.removed_files')
+    old_session_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${old_session_id}" -X GET "http://localhost:3369/list/files")
+    old_files_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/files?device_id=docker-tests-devices-old")
+    old_listed=$(curl -sS -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/devices" | jq '[.[] | select(.id == "docker-tests-devices-old")] | length')
+    repeated_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X DELETE "http://localhost:3369/account/devices?device_id=docker-tests-devices-old")
+
+    assert "200" "${rename_status}" "Could not rename the device" \
+     && assert "404" "${rename_unknown_status}" "Unknown device was renamed" \
+     && assert "Linux" "${os}" "OS of the device was different" \
+     && assert "Old laptop" "${old_name}" "Name of the device was different" \
+     && assert "true" "${old_has_backup}" "Last backup of the device was not recorded" \
+     && assert "409" "${current_status}" "Current device could be decommissioned" \
+     && assert "1" "${removed_files}" "Number of removed files was different" \
+     && assert "401" "${old_session_status}" "Session of decommissioned device was still valid" \
+     && assert "404" "${old_files_status}" "Files of decommissioned device were still listed" \
+     && assert "0" "${old_listed}" "Decommissioned device was still listed" \
+     && assert "404" "${repeated_status}" "Decommissioned device could be decommissioned again"
+}
+
+function account_deletion() {
+    session_id=$(curl -sS -X POST -d "device_id=docker-tests-deletion&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+
+    wrong_password_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "password=wrong" "http://localhost:3369/account/delete")
+    request=$(curl -sS -w "\n%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -H "Content-Type: application/json" -d '{"password": "rbackup"}' "http://localhost:3369/account/delete")
+    request_status=$(echo "${request}" | tail -n 1)
+    purge_after=$(echo "${request}" | head -n 1 | jq '.purge_after' | sed -e 's/^"//' -e 's/"$//')
+    repeated_request_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" -d "password=rbackup" "http://localhost:3369/account/delete")
+    # the account stays usable during the grace period
+    files_status=$(curl -sS -o /dev/null -w "%{http_code}" -H "RBackup-Session-Pass: ${session_id}" -X GET "http://localhost:3369/list/files")
+    cancel_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" "http://localhost:3369/account/delete/cancel")
+    repeated_cancel_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" "http://localhost:3369/account/delete/cancel")
+
+    assert "403" "${wrong_password_status}" "Deletion was requested with wrong password" \
+     && assert "202" "${request_status}" "Could not request deletion of the account" \
+     && assert "19" "${#purge_after}" "Time of the purge was not returned" \
+     && assert "409" "${repeated_request_status}" "Deletion was requested twice" \
+     && assert "200" "${files_status}" "Account was not usable during the grace period" \
+     && assert "200" "${cancel_status}" "Could not cancel deletion of the account" \
+     && assert "404" "${repeated_cancel_status}" "Deletion was cancelled twice"
+}
+
+# Runs the deleteaccount admin command in the container, prints its exit code.
+function delete_account_cli() {
+    docker-compose -f "${compose_file}" exec -T tests /rbackup -c /config.toml deleteaccount "$@" > /dev/null 2>&1
+    echo $?
+}
+
+function repos_count() {
+    docker-compose -f "${compose_file}" exec -T tests ls /data/deduprepo | wc -l | tr -d ' '
+}
+
+function account_purge() {
+    credentials="device_id=docker-tests-purge&username=rbackup6&password=rbackup"
+
+    curl -sS -X POST -d "username=rbackup6&password=rbackup" "http://localhost:3369/account/register" > /dev/null
+    session_id=$(curl -sS -X POST -d "${credentials}" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//')
+    upload ${session_id} "theFileToBeUploaded.dat" > /dev/null
+    count=$(repos_count)
+
+    schedule_status=$(delete_account_cli rbackup6)
+    # the deletion scheduled by the admin may be cancelled by the account, and vice versa
+    cancel_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" "http://localhost:3369/account/delete/cancel")
+    curl -sS -o /dev/null -X POST -H "RBackup-Session-Pass: ${session_id}" -d "password=rbackup" "http://localhost:3369/account/delete"
+    cli_cancel_status=$(delete_account_cli rbackup6 --cancel)
+    repeated_cancel_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -H "RBackup-Session-Pass: ${session_id}" "http://localhost:3369/account/delete/cancel")
+
+    purge_status=$(delete_account_cli rbackup6 --now)
+    login_status=$(curl -sS -o /dev/null -w "%{http_code}" -X POST -d "${credentials}" "http://localhost:3369/account/login")
+    purged_count=$(repos_count)
+    unknown_account_status=$(delete_account_cli rbackup6 --now)
+
+    assert "0" "${schedule_status}" "Could not schedule deletion of the account by the CLI" \
+     && assert "200" "${cancel_status}" "Deletion scheduled by the CLI was not cancellable" \
+     && assert "0" "${cli_cancel_status}" "Could not cancel deletion of the account by the CLI" \
+     && assert "404" "${repeated_cancel_status}" "Deletion was not cancelled by the CLI" \
+     && assert "0" "${purge_status}" "Could not purge the account by the CLI" \
+     && assert "401" "${login_status}" "Purged account could still log in" \
+     && assert "$(( count - 1 ))" "${purged_count}" "Repository of the purged account was not removed" \
+     && assert "1" "${unknown_account_status}" "Purged account was found by the CLI"
+}
+
 function assert() {
     expected=$1
     actual=$2
@@ -55,25 +656,61 @@ function assert() {
 
 echo -e "Running tests:\n"
 
-curl -sS "http://localhost:3369/account/register?username=rbackup&password=rbackup" > /dev/null \
- && session_id=$(curl -sS "http://localhost:3369/account/login?device_id=docker-tests&username=rbackup&password=rbackup" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//') \
+curl -sS -X POST -d "username=rbackup&password=rbackup" "http://localhost:3369/account/register" > /dev/null \
+ && session_id=$(curl -sS -X POST -H "Content-Type: application/json" -d '{"device_id": "docker-tests", "username": "rbackup", "password": "rbackup"}' "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//') \
  && echo -e "SessionID: ${session_id} \n" \
  && upload ${session_id} "theFileToBeUploaded.dat" > /dev/null \
  && upload ${session_id} "theFileToBeUploaded.dat" > /dev/null \
  && list_response=$(list_files ${session_id} | jq '.[] | {original_name: .original_name, versions: [.versions[] | { version: .version, hash: .hash, size: .size }] }') \
  && echo ${list_response} \
  && list_response_sha=$(echo ${list_response} | sha256sum | awk '{ print $1 }') \
- && assert "8dccd82e8e22115199801c700802456a6323c0a1e16927eeadddbbc79890584e" ${list_response_sha} "List response content was different" \
+ && assert "c7d9e963a25f699cd3c82472e01bffee18e84edaa7679daa9575fd1d9ead87af" ${list_response_sha} "List response content was different" \
  && echo -e "\nSecond account:\n" \
- && curl -sS "http://localhost:3369/account/register?username=rbackup2&password=rbackup" > /dev/null \
- && session_id2=$(curl -sS "http://localhost:3369/account/login?device_id=docker-tests&username=rbackup2&password=rbackup" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//') \
+ && curl -sS -X POST -d "username=rbackup2&password=rbackup" "http://localhost:3369/account/register" > /dev/null \
+ && session_id2=$(curl -sS -X POST -d "device_id=docker-tests&username=rbackup2&password=rbackup" "http://localhost:3369/account/login" | jq '.session_id' | sed -e 's/^"//' -e 's/"$//') \
  && echo -e "SessionID2: ${session_id2} \n" \
  && upload ${session_id2} "theFileToBeUploaded.dat" > /dev/null \
- && list_response2=$(list_files ${session_id} | jq '.[] | {original_name: .original_name, versions: [.versions[] | { version: .version, hash: .hash, size: .size }] }') \
+ && list_response2=$(list_files ${session_id2} | jq '.[] | {original_name: .original_name, versions: [.versions[] | { version: .version, hash: .hash, size: .size }] }') \
  && echo ${list_response2} \
  && list_response_sha2=$(echo ${list_response2} | sha256sum | awk '{ print $1 }') \
- && assert "1ea40011b807d87d9de0e45560d35a68babc03c3a889da4c9bb77defec08f633" ${list_response_sha2} "List response content was different" \
+ && assert "e7e6a072c3a90a05afeef2a449f1be36224c79fd478419d54ca26559129c0098" ${list_response_sha2} "List response content was different" \
+ && echo -e "\nUnchanged content:\n" \
+ && unchanged_upload \
+ && echo -e "\nUpload by hash:\n" \
+ && upload_by_hash \
+ && echo -e "\nResumable upload:\n" \
+ && resumable_upload \
+ && echo -e "\nLarge file:\n" \
+ && large_file ${session_id2} \
+ && echo -e "\nPassword change:\n" \
+ && change_password "theFileToBeUploaded.dat" \
+ && echo -e "\nAccount recovery:\n" \
+ && recover_account "theFileToBeUploaded.dat" \
+ && echo -e "\nSessions:\n" \
+ && sessions ${session_id2} \
+ && echo -e "\nSession renewal:\n" \
+ && session_renewal \
+ && echo -e "\nRetention policies:\n" \
+ && retention \
+ && echo -e "\nValidation:\n" \
+ && validation \
+ && echo -e "\nTOTP:\n" \
+ && totp \
+ && echo -e "\nAPI tokens:\n" \
+ && api_tokens "theFileToBeUploaded.dat" \
+ && echo -e "\nDevice roles:\n" \
+ && device_roles "theFileToBeUploaded.dat" \
+ && echo -e "\nCross-account access:\n" \
+ && cross_account \
+ && echo -e "\nDevices:\n" \
+ && devices "theFileToBeUploaded.dat" \
+ && echo -e "\nAccount deletion:\n" \
+ && account_deletion \
+ && echo -e "\nThrottling:\n" \
+ && throttling \
+ && echo -e "\nAccount purge:\n" \
+ && account_purge \
  && echo -e "\n\nTests were successful\n\n"
 
-# SHA256 of (with trailing \n): { "original_name": "theFileToBeUploaded.dat", "versions": [ { "version": 1, "hash": "bc5ef071dd97166222168541bb53568b87e858b2db5614e120bc65fd6565f0af", "size": 1520 }, { "version": 2, "hash": "bc5ef071dd97166222168541bb53568b87e858b2db5614e120bc65fd6565f0af", "size": 1520 } ] }
-# SHA256 of (with trailing \n): { "original_name": "theFileToBeUploaded.dat", "versions": [ { "version": 3, "hash": "bc5ef071dd97166222168541bb53568b87e858b2db5614e120bc65fd6565f0af", "size": 1520 } ] }
+# SHA256 of (with trailing \n): { "original_name": "theFileToBeUploaded.dat", "versions": [ { "version": 1, "hash": "bc5ef071dd97166222168541bb53568b87e858b2db5614e120bc65fd6565f0af", "size": 1520 } ] }
+# SHA256 of (with trailing \n): { "original_name": "theFileToBeUploaded.dat", "versions": [ { "version": 2, "hash": "bc5ef071dd97166222168541bb53568b87e858b2db5614e120bc65fd6565f0af", "size": 1520 } ] }